@@ -0,0 +1,414 @@
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use poem_openapi::{Enum, Object};
+use scylla::IntoTypedRows;
+use scylla::frame::value::SerializedValues;
+use serde_json::Value;
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::utils::{new_time_uuid, JsSafeBigInt, Timestamp};
+
+
+/// The length of each audit log partition in milliseconds.
+const DAY_MILLIS: i64 = 86_400_000;
+
+/// How many entries are read from a partition at a time when querying.
+const QUERY_PAGE_SIZE: i32 = 100;
+
+/// The most pages read for a single query, see `query`.
+const MAX_QUERY_PAGES: usize = 10;
+
+
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum AuditAction {
+    RoomClosed,
+    PlaylistRemoved,
+    EntryRemoved,
+    CreditsGranted,
+    NotificationCreated,
+    BroadcastStarted,
+    RolesChanged,
+    MemberKicked,
+    RoomRoleChanged,
+    OwnershipTransferred,
+    InviteRevoked,
+    SessionsRevoked,
+    ApiKeyRevoked,
+}
+
+/// The kind of thing an audited action was done to.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum TargetKind {
+    Room,
+    Playlist,
+    Entry,
+    User,
+    Broadcast,
+    Invite,
+    ApiKey,
+    Session,
+}
+
+#[derive(Object)]
+pub struct AuditEntry {
+    pub id: Uuid,
+
+    /// The user who did the action, not set if the static superuser key
+    /// was used.
+    pub actor_id: Option<JsSafeBigInt>,
+    pub action: AuditAction,
+    pub target_kind: TargetKind,
+    pub target_id: String,
+
+    /// The target before the action, if it existed.
+    pub before: Option<Value>,
+
+    /// The target after the action, if it still exists.
+    pub after: Option<Value>,
+    pub created_at: Timestamp,
+}
+
+/// An action to add to the audit log.
+pub struct AuditRecord {
+    pub actor_id: Option<i64>,
+    pub action: AuditAction,
+    pub target_kind: TargetKind,
+    pub target_id: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Object)]
+pub struct AuditPage {
+    /// The entries on this page, newest first.
+    pub entries: Vec<AuditEntry>,
+
+    /// The id to pass as `before` to fetch the next page, if there is one.
+    pub next_cursor: Option<Uuid>,
+}
+
+/// What to filter the audit log by, entries must match every filter
+/// which is set.
+pub struct AuditFilter {
+    pub actor_id: Option<i64>,
+    pub target_id: Option<String>,
+    pub from: Timestamp,
+    pub to: Timestamp,
+}
+
+
+/// Adds the action to the audit log.
+///
+/// The log is append only, entries are never updated or removed. Like
+/// `events::emit` this never fails, write errors are logged instead so
+/// they don't fail an action which has already been done.
+pub async fn record(sess: &Session, record: AuditRecord) {
+    let action = record.action;
+    let target_id = record.target_id.clone();
+
+    if let Err(e) = insert_record(sess, record).await {
+        error!("failed to record {} of {} in audit log: {}", action, target_id, e);
+    }
+}
+
+/// Gets a page of up to `limit` entries matching the filter, newest
+/// first, starting after the entry with the `before` id if it's given.
+///
+/// Filtering by actor or target reads that actor's or target's lookup
+/// table, otherwise the log is read a day partition at a time. Either
+/// way at most `MAX_QUERY_PAGES` pages of `QUERY_PAGE_SIZE` rows are
+/// read, so a page can have fewer entries than asked for while still
+/// having a cursor to carry on from.
+pub async fn query(
+    sess: &Session,
+    filter: &AuditFilter,
+    before: Option<Uuid>,
+    limit: usize,
+) -> Result<AuditPage> {
+    let partitions = match (filter.actor_id, filter.target_id.as_deref()) {
+        (Some(actor_id), _) => vec![Partition::Actor(actor_id)],
+        (None, Some(target_id)) => vec![Partition::Target(target_id)],
+        (None, None) => {
+            let last = before.and_then(uuid_time).unwrap_or(filter.to);
+            (*filter.from / DAY_MILLIS..=*last / DAY_MILLIS)
+                .rev()
+                .map(Partition::Day)
+                .collect()
+        },
+    };
+
+    let mut entries = vec![];
+    let mut cursor = before;
+    let mut pages_read = 0;
+    for partition in partitions {
+        loop {
+            if (entries.len() >= limit) | (pages_read >= MAX_QUERY_PAGES) {
+                return Ok(finish_page(entries, cursor, limit))
+            }
+
+            let (found, last_id, has_more) = query_page(sess, &partition, filter, cursor).await?;
+            pages_read += 1;
+            entries.extend(found);
+            cursor = last_id.or(cursor);
+
+            if !has_more {
+                break
+            }
+        }
+    }
+
+    if entries.len() > limit {
+        return Ok(finish_page(entries, cursor, limit))
+    }
+
+    Ok(AuditPage { entries, next_cursor: None })
+}
+
+
+/// A partition of the audit log or one of its lookup tables.
+enum Partition<'a> {
+    Day(i64),
+    Actor(i64),
+    Target(&'a str),
+}
+
+/// Cuts the entries down to `limit`, a full page carries on after its
+/// last entry and anything else after the last row read.
+fn finish_page(mut entries: Vec<AuditEntry>, cursor: Option<Uuid>, limit: usize) -> AuditPage {
+    if entries.len() < limit {
+        return AuditPage { entries, next_cursor: cursor }
+    }
+
+    entries.truncate(limit);
+    let next_cursor = entries.last().map(|v| v.id);
+
+    AuditPage { entries, next_cursor }
+}
+
+/// Gets the time a time based id was created at.
+fn uuid_time(id: Uuid) -> Option<Timestamp> {
+    let (secs, nanos) = id.to_timestamp()?.to_unix();
+    Some(Timestamp(secs as i64 * 1000 + nanos as i64 / 1_000_000))
+}
+
+/// Reads a page of the partition's entries older than `before` if it's
+/// given, returning those which match the filter, the id of the last row
+/// read and if the partition may have more.
+async fn query_page(
+    sess: &Session,
+    partition: &Partition<'_>,
+    filter: &AuditFilter,
+    before: Option<Uuid>,
+) -> Result<(Vec<AuditEntry>, Option<Uuid>, bool)> {
+    let mut values = SerializedValues::new();
+    let table = match partition {
+        Partition::Day(day) => {
+            values.add_value(day)?;
+            "audit_log WHERE day = ?"
+        },
+        Partition::Actor(actor_id) => {
+            values.add_value(actor_id)?;
+            "audit_log_by_actor WHERE actor_id = ?"
+        },
+        Partition::Target(target_id) => {
+            values.add_value(target_id)?;
+            "audit_log_by_target WHERE target_id = ?"
+        },
+    };
+
+    values.add_value(&filter.from)?;
+    let end = match before {
+        None => {
+            values.add_value(&filter.to)?;
+            "id <= maxTimeuuid(?)"
+        },
+        Some(before) => {
+            values.add_value(&before)?;
+            "id < ?"
+        },
+    };
+    values.add_value(&QUERY_PAGE_SIZE)?;
+
+    let query = format!(
+        r#"
+        SELECT
+            id,
+            actor_id,
+            action,
+            target_kind,
+            target_id,
+            before,
+            after,
+            created_at
+        FROM {} AND id >= minTimeuuid(?) AND {}
+        LIMIT ?;
+        "#,
+        table,
+        end,
+    );
+
+    let result = sess.query_prepared(&query, values).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    type AuditInfo = (
+        Uuid,
+        Option<i64>,
+        String,
+        String,
+        String,
+        Option<String>,
+        Option<String>,
+        Timestamp,
+    );
+
+    let rows: Vec<AuditInfo> = rows.into_typed::<AuditInfo>()
+        .filter_map(|v| v.ok())
+        .collect();
+
+    let last_id = rows.last().map(|v| v.0);
+    let has_more = rows.len() as i32 >= QUERY_PAGE_SIZE;
+
+    let found = rows.into_iter()
+        .filter(|v| filter.actor_id.map(|actor_id| v.1 == Some(actor_id)).unwrap_or(true))
+        .filter(|v| filter.target_id.as_ref().map(|target_id| &v.4 == target_id).unwrap_or(true))
+        .filter_map(|v| {
+            Some(AuditEntry {
+                id: v.0,
+                actor_id: v.1.map(JsSafeBigInt),
+                action: AuditAction::from_str(&v.2).ok()?,
+                target_kind: TargetKind::from_str(&v.3).ok()?,
+                target_id: v.4,
+                before: v.5.and_then(|v| serde_json::from_str(&v).ok()),
+                after: v.6.and_then(|v| serde_json::from_str(&v).ok()),
+                created_at: v.7,
+            })
+        })
+        .collect();
+
+    Ok((found, last_id, has_more))
+}
+
+/// Writes the entry to the log and to the lookup tables by its actor
+/// and its target.
+async fn insert_record(sess: &Session, record: AuditRecord) -> Result<()> {
+    let created_at = Timestamp::now();
+    let id = new_time_uuid();
+    let before = record.before.map(|v| v.to_string());
+    let after = record.after.map(|v| v.to_string());
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO audit_log (
+            day,
+            id,
+            actor_id,
+            action,
+            target_kind,
+            target_id,
+            before,
+            after,
+            created_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);
+        "#,
+        (
+            *created_at / DAY_MILLIS,
+            id,
+            record.actor_id,
+            record.action.to_string(),
+            record.target_kind.to_string(),
+            &record.target_id,
+            &before,
+            &after,
+            created_at,
+        )
+    ).await?;
+
+    insert_lookups(
+        sess,
+        id,
+        record.actor_id,
+        record.action,
+        record.target_kind,
+        &record.target_id,
+        before,
+        after,
+        created_at,
+    ).await
+}
+
+/// Writes an entry to the lookup tables by its actor, if it has one, and
+/// by its target.
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_lookups(
+    sess: &Session,
+    id: Uuid,
+    actor_id: Option<i64>,
+    action: AuditAction,
+    target_kind: TargetKind,
+    target_id: &str,
+    before: Option<String>,
+    after: Option<String>,
+    created_at: Timestamp,
+) -> Result<()> {
+    if let Some(actor_id) = actor_id {
+        sess.query_prepared(
+            r#"
+            INSERT INTO audit_log_by_actor (
+                actor_id,
+                id,
+                action,
+                target_kind,
+                target_id,
+                before,
+                after,
+                created_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+            "#,
+            (
+                actor_id,
+                id,
+                action.to_string(),
+                target_kind.to_string(),
+                target_id,
+                &before,
+                &after,
+                created_at,
+            )
+        ).await?;
+    }
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO audit_log_by_target (
+            target_id,
+            id,
+            actor_id,
+            action,
+            target_kind,
+            before,
+            after,
+            created_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?);
+        "#,
+        (
+            target_id,
+            id,
+            actor_id,
+            action.to_string(),
+            target_kind.to_string(),
+            before,
+            after,
+            created_at,
+        )
+    ).await?;
+
+    Ok(())
+}
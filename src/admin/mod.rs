@@ -0,0 +1,172 @@
+pub mod audit;
+pub mod roles;
+
+use std::collections::HashSet;
+use poem::web::Data;
+use poem::Result;
+use poem_openapi::{Object, OpenApi};
+use poem_openapi::param::Query;
+use poem_openapi::payload::Json;
+use poem_openapi::types::ToJSON;
+use uuid::Uuid;
+
+use crate::ApiTags;
+use crate::admin::audit::{AuditAction, AuditFilter, AuditPage, AuditRecord, TargetKind};
+use crate::admin::roles::{Permission, Role};
+use crate::db::Session;
+use crate::users::user_info;
+use crate::utils::{JsSafeBigInt, JsonResponse, SuperUserBearer, Timestamp};
+
+
+#[derive(Object)]
+pub struct UserRoles {
+    user_id: JsSafeBigInt,
+    roles: Vec<Role>,
+}
+
+#[derive(Object)]
+pub struct UserRolesPayload {
+    user_id: i64,
+
+    /// The user's new roles, replacing any they had.
+    roles: Vec<Role>,
+}
+
+
+/// The longest time range the audit log can be queried over, in days.
+const MAX_AUDIT_RANGE_DAYS: i64 = 31;
+
+
+fn default_audit_page_size() -> u32 {
+    50
+}
+
+pub struct AdminApi;
+
+#[OpenApi]
+impl AdminApi {
+    /// Get User Roles
+    ///
+    /// Gets the superuser roles a user has.
+    #[oai(path = "/admin/roles", method = "get", tag = "ApiTags::Admin")]
+    pub async fn get_user_roles(
+        &self,
+        user_id: Query<i64>,
+        superuser: SuperUserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<UserRoles>> {
+        if !superuser.0.has(Permission::ManageRoles) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let roles = roles::get_roles(&session, user_id.0).await?;
+
+        Ok(JsonResponse::ok(UserRoles {
+            user_id: JsSafeBigInt(user_id.0),
+            roles: sorted_roles(roles),
+        }))
+    }
+
+    /// Set User Roles
+    ///
+    /// Replaces the superuser roles a user has, an empty list removes
+    /// all of them.
+    #[oai(path = "/admin/roles", method = "put", tag = "ApiTags::Admin")]
+    pub async fn set_user_roles(
+        &self,
+        payload: Json<UserRolesPayload>,
+        superuser: SuperUserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<UserRoles>> {
+        if !superuser.0.has(Permission::ManageRoles) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let user = user_info::get_user_from_id(&session, payload.user_id).await?;
+        if user.is_none() {
+            return Ok(JsonResponse::bad_request("This user does not exist."))
+        }
+
+        let roles: HashSet<Role> = payload.roles.iter().copied().collect();
+        if (superuser.0.user_id == Some(payload.user_id)) & !roles.contains(&Role::Admin) {
+            return Ok(JsonResponse::bad_request("You can't remove your own admin role."))
+        }
+
+        let before = roles::get_roles(&session, payload.user_id).await?;
+        roles::set_roles(&session, payload.user_id, &roles).await?;
+
+        audit::record(&session, AuditRecord {
+            actor_id: superuser.0.user_id,
+            action: AuditAction::RolesChanged,
+            target_kind: TargetKind::User,
+            target_id: payload.user_id.to_string(),
+            before: Some(sorted_roles(before).to_json()),
+            after: Some(sorted_roles(roles.clone()).to_json()),
+        }).await;
+
+        Ok(JsonResponse::ok(UserRoles {
+            user_id: JsSafeBigInt(payload.user_id),
+            roles: sorted_roles(roles),
+        }))
+    }
+
+    /// Get Audit Log
+    ///
+    /// Gets the privileged and destructive actions done in a time range,
+    /// newest first, optionally filtered by who did them and what to.
+    ///
+    /// The range defaults to the last 7 days and can be at most 31 days.
+    /// Pages can have fewer entries than the limit while there are more
+    /// to come, pass the `next_cursor` of a page as `before` to get the
+    /// next one.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/audit", method = "get", tag = "ApiTags::Admin")]
+    pub async fn get_audit_log(
+        &self,
+        actor_id: Query<Option<i64>>,
+        target_id: Query<Option<String>>,
+        from: Query<Option<i64>>,
+        to: Query<Option<i64>>,
+        before: Query<Option<Uuid>>,
+        #[oai(default = "default_audit_page_size", validator(minimum(value = "1"), maximum(value = "200")))]
+        limit: Query<u32>,
+        superuser: SuperUserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<AuditPage>> {
+        if !superuser.0.has(Permission::ViewAuditLog) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let to = to.0.unwrap_or(*Timestamp::now());
+        let from = from.0.unwrap_or(to - 7 * 86_400_000);
+        if from > to {
+            return Ok(JsonResponse::bad_request("The start of the range must be before its end."))
+        }
+
+        if (to - from) > MAX_AUDIT_RANGE_DAYS * 86_400_000 {
+            return Ok(JsonResponse::bad_request("The range can be at most 31 days."))
+        }
+
+        let filter = AuditFilter {
+            actor_id: actor_id.0,
+            target_id: target_id.0,
+            from: Timestamp(from),
+            to: Timestamp(to),
+        };
+
+        if before.0.map(|v| v.to_timestamp().is_none()).unwrap_or(false) {
+            return Ok(JsonResponse::bad_request("Invalid cursor."))
+        }
+
+        let page = audit::query(&session, &filter, before.0, limit.0 as usize).await?;
+
+        Ok(JsonResponse::ok(page))
+    }
+}
+
+
+fn sorted_roles(roles: HashSet<Role>) -> Vec<Role> {
+    let mut roles: Vec<Role> = roles.into_iter().collect();
+    roles.sort_by_key(|v| v.to_string());
+    roles
+}
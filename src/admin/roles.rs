@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use poem_openapi::Enum;
+use scylla::IntoTypedRows;
+use strum::{Display, EnumString};
+
+use crate::auth::identity::AuthenticatedUser;
+use crate::db::Session;
+
+
+/// A role giving a user access to superuser endpoints.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum Role {
+    /// Can do everything, including managing roles and viewing the
+    /// audit log.
+    Admin,
+
+    /// Can remove content and close rooms.
+    Moderator,
+
+    /// Can send notifications and grant credits.
+    Support,
+}
+
+/// Something a superuser can be allowed to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Permission {
+    ManageNotifications,
+    ModerateContent,
+    GrantCredits,
+    CloseRooms,
+    ManageRoles,
+    ViewAuditLog,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Self::Admin => &[
+                Permission::ManageNotifications,
+                Permission::ModerateContent,
+                Permission::GrantCredits,
+                Permission::CloseRooms,
+                Permission::ManageRoles,
+                Permission::ViewAuditLog,
+            ],
+            Self::Moderator => &[
+                Permission::ModerateContent,
+                Permission::CloseRooms,
+            ],
+            Self::Support => &[
+                Permission::ManageNotifications,
+                Permission::GrantCredits,
+            ],
+        }
+    }
+}
+
+
+/// Someone authorized as a superuser, see `SuperUserBearer`.
+pub struct SuperUser {
+    /// The user acting as a superuser, not set when the static
+    /// `SUPERUSER_KEY` is used.
+    pub user_id: Option<i64>,
+    roles: HashSet<Role>,
+}
+
+impl SuperUser {
+    /// The superuser authorized by the static `SUPERUSER_KEY`, which has
+    /// every permission so it can be used to bootstrap roles.
+    pub fn break_glass() -> Self {
+        Self {
+            user_id: None,
+            roles: HashSet::from([Role::Admin]),
+        }
+    }
+
+    /// The superuser an authenticated user is acting as, users without
+    /// any roles aren't superusers.
+    ///
+    /// Roles are always read fresh rather than cached with the user so
+    /// removing them takes effect immediately.
+    pub async fn from_user(sess: &Session, user: &AuthenticatedUser) -> Result<Option<Self>> {
+        let roles = get_roles(sess, user.id).await?;
+        if roles.is_empty() {
+            return Ok(None)
+        }
+
+        Ok(Some(Self {
+            user_id: Some(user.id),
+            roles,
+        }))
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.roles.iter().any(|v| v.permissions().contains(&permission))
+    }
+}
+
+
+/// Gets the user's roles, unknown roles are ignored.
+pub async fn get_roles(sess: &Session, user_id: i64) -> Result<HashSet<Role>> {
+    let result = sess.query_prepared(
+        "SELECT roles FROM user_roles WHERE user_id = ?;",
+        (user_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let roles = match rows.into_typed::<(Option<Vec<String>>,)>().next() {
+        None => HashSet::new(),
+        Some(v) => v?.0
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| Role::from_str(v).ok())
+            .collect(),
+    };
+
+    Ok(roles)
+}
+
+/// Replaces the user's roles, removing every role if none are given.
+pub async fn set_roles(sess: &Session, user_id: i64, roles: &HashSet<Role>) -> Result<()> {
+    if roles.is_empty() {
+        sess.query_prepared(
+            "DELETE FROM user_roles WHERE user_id = ?;",
+            (user_id,)
+        ).await?;
+
+        return Ok(())
+    }
+
+    let roles: Vec<String> = roles.iter()
+        .map(|v| v.to_string())
+        .collect();
+
+    sess.query_prepared(
+        "UPDATE user_roles SET roles = ? WHERE user_id = ?;",
+        (roles, user_id)
+    ).await?;
+
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use poem_openapi::{Enum, Object};
+use scylla::IntoTypedRows;
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+use crate::auth::identity::UserCache;
+use crate::auth::tokens;
+use crate::db::Session;
+use crate::utils::Timestamp;
+
+
+/// The most API keys a user can have at once.
+///
+/// This is checked before a key is created rather than in the same
+/// transaction, so concurrent creations can go over it.
+pub const MAX_KEYS_PER_USER: usize = 25;
+
+/// The furthest in the future an API key can expire, in milliseconds.
+pub const MAX_KEY_LIFETIME_MILLIS: i64 = 365 * 86_400_000;
+
+
+/// What an API key is allowed to do, access tokens can do everything.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ApiScope {
+    /// List the user's playlists and entries.
+    #[strum(serialize = "read:playlists")]
+    #[oai(rename = "read:playlists")]
+    ReadPlaylists,
+
+    /// Create, update and delete the user's playlists and entries.
+    #[strum(serialize = "write:playlists")]
+    #[oai(rename = "write:playlists")]
+    WritePlaylists,
+
+    /// Create, close and control playback of rooms.
+    #[strum(serialize = "rooms:control")]
+    #[oai(rename = "rooms:control")]
+    RoomsControl,
+
+    /// Read the user's notifications.
+    #[strum(serialize = "notifications:read")]
+    #[oai(rename = "notifications:read")]
+    NotificationsRead,
+}
+
+#[derive(Object)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: Timestamp,
+
+    /// When the key stops working, keys without an expiry last until
+    /// they're revoked.
+    pub expires_at: Option<Timestamp>,
+}
+
+/// The user and scopes an API key grants access to.
+#[derive(Debug, Clone)]
+pub struct KeyGrant {
+    pub user_id: i64,
+    pub scopes: HashSet<ApiScope>,
+    pub expires_at: Option<Timestamp>,
+}
+
+impl KeyGrant {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map(|v| *v <= *Timestamp::now()).unwrap_or(false)
+    }
+}
+
+#[derive(Object)]
+pub struct CreatedApiKey {
+    /// The key itself, this is only ever shown once.
+    pub key: String,
+    pub info: ApiKey,
+}
+
+
+/// Creates a new API key for the user.
+///
+/// Like access tokens only a hash of the key is stored, keys with an
+/// expiry are removed once they expire. The expiry must be no more than
+/// `MAX_KEY_LIFETIME_MILLIS` away.
+pub async fn create_key(
+    sess: &Session,
+    user_id: i64,
+    name: String,
+    scopes: HashSet<ApiScope>,
+    expires_at: Option<Timestamp>,
+) -> Result<CreatedApiKey> {
+    let key = tokens::issue_api_key();
+    let key_hash = tokens::storage_key(&key)
+        .ok_or_else(|| anyhow!("issued key has no storage key"))?;
+
+    let info = ApiKey {
+        id: Uuid::new_v4(),
+        name,
+        scopes: sorted_scopes(scopes),
+        created_at: Timestamp::now(),
+        expires_at,
+    };
+
+    let ttl = match expires_at {
+        None => 0,
+        Some(expires_at) => {
+            let lifetime = *expires_at - *info.created_at;
+            if lifetime > MAX_KEY_LIFETIME_MILLIS {
+                return Err(anyhow!("API keys can't last longer than {}ms", MAX_KEY_LIFETIME_MILLIS))
+            }
+
+            (lifetime / 1000).max(1) as i32
+        },
+    };
+
+    let scopes: Vec<String> = info.scopes.iter()
+        .map(|v| v.to_string())
+        .collect();
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO api_keys (
+            key_hash,
+            id,
+            user_id,
+            scopes,
+            expires_at
+        ) VALUES (?, ?, ?, ?, ?) USING TTL ?;
+        "#,
+        (&key_hash, info.id, user_id, &scopes, expires_at, ttl)
+    ).await?;
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO user_api_keys (
+            user_id,
+            id,
+            key_hash,
+            name,
+            scopes,
+            created_at,
+            expires_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?) USING TTL ?;
+        "#,
+        (user_id, info.id, &key_hash, &info.name, &scopes, info.created_at, expires_at, ttl)
+    ).await?;
+
+    Ok(CreatedApiKey { key, info })
+}
+
+/// Lists the user's API keys, newest first.
+pub async fn list_keys(sess: &Session, user_id: i64) -> Result<Vec<ApiKey>> {
+    let result = sess.query_prepared(
+        "SELECT id, name, scopes, created_at, expires_at FROM user_api_keys WHERE user_id = ?;",
+        (user_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let mut keys: Vec<ApiKey> = rows.into_typed::<(Uuid, String, Option<Vec<String>>, Timestamp, Option<Timestamp>)>()
+        .filter_map(|v| v.ok())
+        .map(|v| ApiKey {
+            id: v.0,
+            name: v.1,
+            scopes: sorted_scopes(parse_scopes(v.2)),
+            created_at: v.3,
+            expires_at: v.4,
+        })
+        .collect();
+
+    keys.sort_by_key(|v| std::cmp::Reverse(*v.created_at));
+
+    Ok(keys)
+}
+
+/// Revokes one of the user's API keys, returning `false` if the user has
+/// no key with the given id.
+pub async fn revoke_key(sess: &Session, cache: &UserCache, user_id: i64, id: Uuid) -> Result<bool> {
+    let result = sess.query_prepared(
+        "SELECT key_hash FROM user_api_keys WHERE user_id = ? AND id = ?;",
+        (user_id, id)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let key_hash = match rows.into_typed::<(String,)>().next() {
+        None => return Ok(false),
+        Some(v) => v?.0,
+    };
+
+    sess.query_prepared(
+        "DELETE FROM api_keys WHERE key_hash = ?;",
+        (&key_hash,)
+    ).await?;
+
+    sess.query_prepared(
+        "DELETE FROM user_api_keys WHERE user_id = ? AND id = ?;",
+        (user_id, id)
+    ).await?;
+
+    cache.invalidate([key_hash]);
+
+    Ok(true)
+}
+
+/// Gets what an API key grants, if it's valid.
+pub async fn get_key_grant(sess: &Session, key: &str) -> Result<Option<KeyGrant>> {
+    let key_hash = match tokens::storage_key(key) {
+        None => return Ok(None),
+        Some(key_hash) => key_hash,
+    };
+
+    let result = sess.query_prepared(
+        "SELECT user_id, scopes, expires_at FROM api_keys WHERE key_hash = ?;",
+        (key_hash,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let (user_id, scopes, expires_at) = match rows.into_typed::<(i64, Option<Vec<String>>, Option<Timestamp>)>().next() {
+        None => return Ok(None),
+        Some(v) => v?,
+    };
+
+    let grant = KeyGrant {
+        user_id,
+        scopes: parse_scopes(scopes),
+        expires_at,
+    };
+
+    // The TTL removes expired keys but only once compaction catches up.
+    if grant.is_expired() {
+        return Ok(None)
+    }
+
+    Ok(Some(grant))
+}
+
+
+fn parse_scopes(scopes: Option<Vec<String>>) -> HashSet<ApiScope> {
+    scopes.unwrap_or_default()
+        .iter()
+        .filter_map(|v| ApiScope::from_str(v).ok())
+        .collect()
+}
+
+fn sorted_scopes(scopes: HashSet<ApiScope>) -> Vec<ApiScope> {
+    let mut scopes: Vec<ApiScope> = scopes.into_iter().collect();
+    scopes.sort_by_key(|v| v.to_string());
+    scopes
+}
@@ -1,12 +1,35 @@
 use std::collections::HashMap;
-use anyhow::{Error, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
-use reqwest::StatusCode;
+use reqwest::{header, Method, RequestBuilder, Response, StatusCode};
+use thiserror::Error;
 
-static DISCORD_URL: &str = "https://discord.com/api/v9";
+/// The scopes requested when a user logs in.
+static SCOPES: &str = "identify guilds";
+
+/// How many times a request is retried after being rate limited or
+/// failing with a server error.
+const MAX_RETRIES: u32 = 3;
+
+/// The delay before the first retry of a server error, doubled for each
+/// retry after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest a request will wait for a rate limit to reset, anything
+/// longer fails with `DiscordError::RateLimited`.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(10);
 
 lazy_static! {
+    static ref API_URL: String = {
+        std::env::var("DISCORD_API_URL")
+            .unwrap_or_else(|_| "https://discord.com/api/v9".to_string())
+    };
+
     static ref CLIENT_ID: u64 = {
         std::env::var("DISCORD_CLIENT_ID")
             .map(|v| v.parse::<u64>().unwrap_or(0))
@@ -22,8 +45,38 @@ lazy_static! {
         std::env::var("DISCORD_REDIRECT_URI")
             .unwrap_or_else(|_| "http://127.0.0.1:8000/api/v0/auth/authorize".to_string())
     };
+
+    /// The client shared by every request to Discord.
+    pub static ref CLIENT: DiscordClient = DiscordClient::new(API_URL.as_str());
 }
 
+
+#[derive(Debug, Error)]
+pub enum DiscordError {
+    /// The authorization code is invalid, expired or was already used.
+    #[error("the authorization code is invalid or has expired")]
+    InvalidCode,
+
+    /// The user's access or refresh token is no longer valid, usually
+    /// because they removed the app's authorization.
+    #[error("the discord token has been revoked")]
+    RevokedToken,
+
+    /// Discord is rate limiting us for longer than we're willing to wait.
+    #[error("rate limited by discord for {0:?}")]
+    RateLimited(Duration),
+
+    /// Discord responded with a status we don't handle.
+    #[error("unexpected response from discord with status {0}")]
+    Unexpected(StatusCode),
+
+    #[error("failed to talk to discord: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+pub type Result<T> = std::result::Result<T, DiscordError>;
+
+
 #[derive(Serialize, Debug)]
 pub struct ExchangeForm<'a> {
     client_id: u64,
@@ -31,50 +84,29 @@ pub struct ExchangeForm<'a> {
     grant_type: &'static str,
     code: &'a str,
     redirect_uri: &'static str,
+    code_verifier: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct RefreshForm<'a> {
+    client_id: u64,
+    client_secret: &'a str,
+    grant_type: &'static str,
+    refresh_token: &'a str,
 }
 
 #[derive(Deserialize)]
 pub struct ExchangeResp {
-    access_token: String,
+    pub access_token: String,
+
+    /// Used to get a new access token once this one expires, Discord
+    /// issues a new refresh token each time one is used.
+    pub refresh_token: Option<String>,
 
     #[serde(flatten)]
     _other: HashMap<String, Value>,
 }
 
-pub async fn exchange_code(code: &str) -> Result<String> {
-    let client = reqwest::Client::new();
-
-    let body = ExchangeForm {
-        client_id: *CLIENT_ID,
-        client_secret: &CLIENT_SECRET,
-        grant_type: "authorization_code",
-        code,
-        redirect_uri: &REDIRECT_URI,
-    };
-
-    trace!("exchanging payload {:?}", &body);
-    let resp = client.post(&format!("{}/oauth2/token", DISCORD_URL))
-        .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .form(&body)
-        .send()
-        .await?;
-
-    if resp.status() == StatusCode::OK {
-        let body: ExchangeResp = resp.json().await?;
-        return Ok(body.access_token)
-    }
-
-    let status = resp.status().to_string();
-    let body: serde_json::Value = resp.json().await?;
-    error!(
-        "error from discord with status {} body={}",
-        status,
-        serde_json::to_string_pretty(&body).unwrap(),
-    );
-
-    Err(Error::msg("an internal server error has arisen while talking to Discord."))
-}
-
 #[derive(Deserialize)]
 pub struct UserInfo {
     #[serde(with = "discord_id")]
@@ -87,29 +119,6 @@ pub struct UserInfo {
     _other: Value,
 }
 
-pub async fn fetch_user_info(token: &str) -> Result<UserInfo> {
-    let client = reqwest::Client::new();
-
-    let resp = client.get(&format!("{}/users/@me", DISCORD_URL))
-        .header(reqwest::header::AUTHORIZATION, bearer(token))
-        .send()
-        .await?;
-
-    if resp.status() == StatusCode::OK {
-        return Ok(resp.json().await?)
-    }
-
-    let status = resp.status().to_string();
-    let body: serde_json::Value = resp.json().await?;
-    error!(
-        "error from discord with status {} body={}",
-        status,
-        serde_json::to_string_pretty(&body).unwrap(),
-    );
-
-    Err(Error::msg("an internal server error has arisen while talking to Discord."))
-}
-
 #[derive(Deserialize)]
 pub struct Guild {
     #[serde(with = "discord_id")]
@@ -134,33 +143,314 @@ impl Guild {
     }
 }
 
-pub async fn fetch_user_guilds(token: &str) -> Result<Vec<Guild>> {
-    let client = reqwest::Client::new();
+#[derive(Deserialize)]
+struct RateLimitBody {
+    retry_after: f64,
+
+    #[serde(default)]
+    global: bool,
+}
+
+
+/// What's known about a rate limit bucket from Discord's last response.
+#[derive(Default)]
+struct Bucket {
+    remaining: Option<u32>,
+    reset_at: Option<Instant>,
+}
+
+/// Identifies a rate limit bucket.
+///
+/// Limits on routes used with a user's token apply to that token only,
+/// so those buckets are also keyed by a hash of the token.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BucketKey {
+    /// Discord's `X-RateLimit-Bucket` for the route once it's known, the
+    /// route itself before then.
+    bucket: String,
+    token: Option<u64>,
+}
+
+/// A Discord API client which reuses connections and respects Discord's
+/// rate limits.
+///
+/// Rate limits are tracked per bucket from the `X-RateLimit-*` headers,
+/// requests to a bucket with no remaining requests wait for it to reset
+/// first. Rate limited and server errors are retried up to `MAX_RETRIES`
+/// times.
+pub struct DiscordClient {
+    http: reqwest::Client,
+    base_url: String,
+    route_buckets: Mutex<HashMap<&'static str, String>>,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+    global_reset_at: Mutex<Option<Instant>>,
+}
+
+impl DiscordClient {
+    /// Creates a client sending requests to the given base URL, normally
+    /// `https://discord.com/api/v9`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            route_buckets: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+            global_reset_at: Mutex::new(None),
+        }
+    }
+
+    /// Builds the URL users are sent to for authorizing a login.
+    ///
+    /// The code challenge is the S256 PKCE challenge of the verifier later
+    /// given to `exchange_code`.
+    pub fn authorize_url(&self, state: &str, code_challenge: &str) -> anyhow::Result<String> {
+        let client_id = CLIENT_ID.to_string();
+        let url = reqwest::Url::parse_with_params(
+            &format!("{}/oauth2/authorize", self.base_url),
+            &[
+                ("client_id", client_id.as_str()),
+                ("redirect_uri", REDIRECT_URI.as_str()),
+                ("response_type", "code"),
+                ("scope", SCOPES),
+                ("state", state),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(url.to_string())
+    }
+
+    pub async fn exchange_code(&self, code: &str, code_verifier: &str) -> Result<ExchangeResp> {
+        let body = ExchangeForm {
+            client_id: *CLIENT_ID,
+            client_secret: &CLIENT_SECRET,
+            grant_type: "authorization_code",
+            code,
+            redirect_uri: &REDIRECT_URI,
+            code_verifier,
+        };
+
+        let resp = self.send(Method::POST, "/oauth2/token", None, |req| req.form(&body)).await?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(resp.json().await?),
+            StatusCode::BAD_REQUEST => Err(DiscordError::InvalidCode),
+            status => Err(unexpected(status, resp).await),
+        }
+    }
+
+    /// Exchanges a refresh token for a new access token.
+    pub async fn refresh_token(&self, refresh_token: &str) -> Result<ExchangeResp> {
+        let body = RefreshForm {
+            client_id: *CLIENT_ID,
+            client_secret: &CLIENT_SECRET,
+            grant_type: "refresh_token",
+            refresh_token,
+        };
+
+        let resp = self.send(Method::POST, "/oauth2/token", None, |req| req.form(&body)).await?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(resp.json().await?),
+            StatusCode::BAD_REQUEST => Err(DiscordError::RevokedToken),
+            status => Err(unexpected(status, resp).await),
+        }
+    }
+
+    pub async fn fetch_user_info(&self, token: &str) -> Result<UserInfo> {
+        self.get_authorized("/users/@me", token).await
+    }
+
+    pub async fn fetch_user_guilds(&self, token: &str) -> Result<Vec<Guild>> {
+        self.get_authorized("/users/@me/guilds", token).await
+    }
+
+    async fn get_authorized<T: DeserializeOwned>(&self, route: &'static str, token: &str) -> Result<T> {
+        let resp = self.send(Method::GET, route, Some(token), |req| {
+            req.header(header::AUTHORIZATION, bearer(token))
+        }).await?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(resp.json().await?),
+            StatusCode::UNAUTHORIZED => Err(DiscordError::RevokedToken),
+            status => Err(unexpected(status, resp).await),
+        }
+    }
+
+    /// Sends a request to the route, waiting out rate limits and retrying
+    /// rate limited and server errors.
+    ///
+    /// Requests made with a user's token should pass it so they're rate
+    /// limited separately from other users. Any other response is returned
+    /// for the caller to handle.
+    async fn send(
+        &self,
+        method: Method,
+        route: &'static str,
+        token: Option<&str>,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response> {
+        let token = token.map(hash_token);
+        let mut attempt = 0;
+
+        loop {
+            self.wait_for_bucket(route, token).await?;
+
+            let req = self.http.request(method.clone(), format!("{}{}", self.base_url, route));
+            let resp = build(req).send().await?;
+
+            self.update_bucket(route, token, &resp);
+
+            let status = resp.status();
+            if attempt >= MAX_RETRIES {
+                return match status {
+                    StatusCode::TOO_MANY_REQUESTS => Err(DiscordError::RateLimited(retry_after(resp).await)),
+                    _ => Ok(resp),
+                }
+            }
+
+            attempt += 1;
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let global = resp.headers().contains_key("X-RateLimit-Global");
+                let wait = retry_after(resp).await;
+                if wait > MAX_RATE_LIMIT_WAIT {
+                    return Err(DiscordError::RateLimited(wait))
+                }
+
+                if global {
+                    *self.global_reset_at.lock().unwrap() = Some(Instant::now() + wait);
+                }
+
+                warn!("rate limited by discord on {} for {:?}", route, wait);
+                tokio::time::sleep(wait).await;
+            } else if status.is_server_error() {
+                let wait = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+
+                warn!("discord responded to {} with {}, retrying in {:?}", route, status, wait);
+                tokio::time::sleep(wait).await;
+            } else {
+                return Ok(resp)
+            }
+        }
+    }
+
+    /// Waits until the route's bucket and the global limit allow another
+    /// request.
+    async fn wait_for_bucket(&self, route: &'static str, token: Option<u64>) -> Result<()> {
+        let now = Instant::now();
+        let key = self.bucket_key(route, token);
+
+        let global_reset_at = *self.global_reset_at.lock().unwrap();
+        let bucket_reset_at = self.buckets.lock().unwrap()
+            .get(&key)
+            .filter(|v| v.remaining == Some(0))
+            .and_then(|v| v.reset_at);
+
+        let reset_at = match global_reset_at.max(bucket_reset_at) {
+            Some(reset_at) if reset_at > now => reset_at,
+            _ => return Ok(()),
+        };
+
+        let wait = reset_at - now;
+        if wait > MAX_RATE_LIMIT_WAIT {
+            return Err(DiscordError::RateLimited(wait))
+        }
 
-    let resp = client.get(&format!("{}/users/@me/guilds", DISCORD_URL))
-        .header(reqwest::header::AUTHORIZATION, bearer(token))
-        .send()
-        .await?;
+        debug!("waiting {:?} for the {} rate limit to reset", wait, route);
+        tokio::time::sleep(wait).await;
 
-    if resp.status() == StatusCode::OK {
-        return Ok(resp.json().await?)
+        Ok(())
     }
 
-    let status = resp.status().to_string();
-    let body: serde_json::Value = resp.json().await?;
+    fn update_bucket(&self, route: &'static str, token: Option<u64>, resp: &Response) {
+        if let Some(bucket) = header_value::<String>(resp, "X-RateLimit-Bucket") {
+            self.route_buckets.lock().unwrap().insert(route, bucket);
+        }
+
+        let remaining = header_value::<u32>(resp, "X-RateLimit-Remaining");
+        let reset_after = header_value::<f64>(resp, "X-RateLimit-Reset-After");
+
+        if remaining.is_none() && reset_after.is_none() {
+            return
+        }
+
+        let now = Instant::now();
+        let key = self.bucket_key(route, token);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Buckets are kept per token, so forget those which have reset
+        // rather than keeping one for every user ever seen.
+        buckets.retain(|_, v| v.reset_at.map(|reset_at| reset_at > now).unwrap_or(false));
+
+        let bucket = buckets.entry(key).or_default();
+        bucket.remaining = remaining;
+        bucket.reset_at = reset_after.map(|v| now + Duration::from_secs_f64(v.max(0.0)));
+    }
+
+    fn bucket_key(&self, route: &'static str, token: Option<u64>) -> BucketKey {
+        let bucket = self.route_buckets.lock().unwrap()
+            .get(route)
+            .cloned()
+            .unwrap_or_else(|| route.to_string());
+
+        BucketKey { bucket, token }
+    }
+}
+
+
+/// Gets how long to wait before retrying a rate limited request, from
+/// the body if Discord sent one or the `Retry-After` header otherwise.
+async fn retry_after(resp: Response) -> Duration {
+    let header_secs = header_value::<f64>(&resp, "Retry-After");
+
+    let secs = match resp.json::<RateLimitBody>().await {
+        Ok(body) => {
+            if body.global {
+                debug!("hit the global discord rate limit");
+            }
+
+            body.retry_after
+        },
+        Err(_) => header_secs.unwrap_or(1.0),
+    };
+
+    Duration::from_secs_f64(secs.max(0.0))
+}
+
+async fn unexpected(status: StatusCode, resp: Response) -> DiscordError {
+    let body: Value = resp.json().await.unwrap_or(Value::Null);
     error!(
         "error from discord with status {} body={}",
         status,
         serde_json::to_string_pretty(&body).unwrap(),
     );
 
-    Err(Error::msg("an internal server error has arisen while talking to Discord."))
+    DiscordError::Unexpected(status)
+}
+
+fn header_value<T: std::str::FromStr>(resp: &Response, name: &str) -> Option<T> {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<T>().ok())
+}
+
+/// If Discord redirects users back over HTTPS.
+pub fn is_secure_redirect() -> bool {
+    REDIRECT_URI.starts_with("https://")
 }
 
 fn bearer(token: &str) -> String {
     format!("Bearer {}", token)
 }
 
+fn hash_token(token: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    token.hash(&mut hasher);
+    hasher.finish()
+}
+
 
 mod discord_id {
     use serde::{self, Deserialize, Deserializer};
@@ -174,4 +464,201 @@ mod discord_id {
         let s = String::deserialize(deserializer)?;
         s.parse::<i64>().map_err(serde::de::Error::custom)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A canned response from the mock server.
+    struct Reply {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: String,
+    }
+
+    impl Reply {
+        fn new(status: u16, body: &str) -> Self {
+            Self { status, headers: vec![], body: body.to_string() }
+        }
+
+        fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+            self.headers.push((name, value.to_string()));
+            self
+        }
+    }
+
+    const USER: &str = r#"{"id": "1", "username": "user", "avatar": null}"#;
+
+    /// Starts a server which answers each request with the next reply,
+    /// repeating the last one once they run out.
+    ///
+    /// Returns a client pointed at the server and the number of requests
+    /// it has received.
+    async fn mock_server(replies: Vec<Reply>) -> (DiscordClient, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let received = count.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                read_request(&mut stream).await;
+
+                let n = received.fetch_add(1, Ordering::SeqCst);
+                let reply = &replies[n.min(replies.len() - 1)];
+
+                let mut resp = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    reply.status,
+                    reply.body.len(),
+                );
+                for (name, value) in reply.headers.iter() {
+                    resp.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                resp.push_str("\r\n");
+                resp.push_str(&reply.body);
+
+                stream.write_all(resp.as_bytes()).await.unwrap();
+                stream.shutdown().await.ok();
+            }
+        });
+
+        (DiscordClient::new(format!("http://{}", addr)), count)
+    }
+
+    async fn read_request(stream: &mut tokio::net::TcpStream) {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+
+        loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                return
+            }
+            buf.extend_from_slice(&chunk[..n]);
+
+            let text = String::from_utf8_lossy(&buf);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let content_length = text[..end].lines()
+                    .filter_map(|v| v.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+
+                if buf.len() >= end + 4 + content_length {
+                    return
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_out_retry_after() {
+        let (client, count) = mock_server(vec![
+            Reply::new(429, r#"{"retry_after": 0.2, "global": false}"#),
+            Reply::new(200, USER),
+        ]).await;
+
+        let started = Instant::now();
+        let user = client.fetch_user_info("token").await.unwrap();
+
+        assert_eq!(user.id, 1);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_for_too_long() {
+        let (client, count) = mock_server(vec![
+            Reply::new(429, r#"{"retry_after": 60, "global": false}"#),
+        ]).await;
+
+        let err = client.fetch_user_info("token").await.err().unwrap();
+
+        assert!(matches!(err, DiscordError::RateLimited(_)));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_backoff() {
+        let (client, count) = mock_server(vec![
+            Reply::new(502, ""),
+            Reply::new(200, USER),
+        ]).await;
+
+        let started = Instant::now();
+        client.fetch_user_info("token").await.unwrap();
+
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() >= RETRY_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_repeated_server_errors() {
+        let (client, count) = mock_server(vec![Reply::new(500, "")]).await;
+
+        let err = client.fetch_user_info("token").await.err().unwrap();
+
+        assert!(matches!(err, DiscordError::Unexpected(StatusCode::INTERNAL_SERVER_ERROR)));
+        assert_eq!(count.load(Ordering::SeqCst), MAX_RETRIES as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn bad_code_is_invalid_code() {
+        let (client, _) = mock_server(vec![
+            Reply::new(400, r#"{"error": "invalid_grant"}"#),
+        ]).await;
+
+        let err = client.exchange_code("code", "verifier").await.err().unwrap();
+
+        assert!(matches!(err, DiscordError::InvalidCode));
+    }
+
+    #[tokio::test]
+    async fn bad_refresh_token_is_revoked() {
+        let (client, _) = mock_server(vec![
+            Reply::new(400, r#"{"error": "invalid_grant"}"#),
+        ]).await;
+
+        let err = client.refresh_token("refresh").await.err().unwrap();
+
+        assert!(matches!(err, DiscordError::RevokedToken));
+    }
+
+    #[tokio::test]
+    async fn unauthorized_token_is_revoked() {
+        let (client, _) = mock_server(vec![
+            Reply::new(401, r#"{"message": "401: Unauthorized", "code": 0}"#),
+        ]).await;
+
+        let err = client.fetch_user_info("token").await.err().unwrap();
+
+        assert!(matches!(err, DiscordError::RevokedToken));
+    }
+
+    #[tokio::test]
+    async fn buckets_are_per_token() {
+        let (client, count) = mock_server(vec![
+            Reply::new(200, USER)
+                .header("X-RateLimit-Bucket", "abc")
+                .header("X-RateLimit-Remaining", 0)
+                .header("X-RateLimit-Reset-After", 60),
+        ]).await;
+
+        client.fetch_user_info("first").await.unwrap();
+        client.fetch_user_info("second").await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        let err = client.fetch_user_info("first").await.err().unwrap();
+        assert!(matches!(err, DiscordError::RateLimited(_)));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
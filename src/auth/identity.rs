@@ -0,0 +1,151 @@
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use concread::arcache::{ARCache, ARCacheBuilder};
+
+use crate::auth::api_keys::{self, ApiScope, KeyGrant};
+use crate::auth::tokens;
+use crate::db::Session;
+use crate::users::user_info::{self, User};
+
+
+/// How long a resolved token is trusted before it's looked up again, so
+/// how long changes to a user can take to apply.
+const CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// The most tokens kept in the cache at once.
+const CACHE_SIZE: usize = 1024;
+
+
+/// The user a bearer token resolved to.
+///
+/// Superuser roles aren't included, see `SuperUser::from_user`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: i64,
+    pub user: User,
+}
+
+#[derive(Debug, Clone)]
+struct CachedUser {
+    user: AuthenticatedUser,
+
+    /// What the token grants if it's an API key, access tokens can do
+    /// everything.
+    grant: Option<KeyGrant>,
+    cached_at: Instant,
+}
+
+/// A short lived cache of resolved tokens keyed by their storage key, see
+/// `tokens::storage_key`.
+///
+/// Revoking a token or API key invalidates its entry, anything else which
+/// changes the user is picked up once the entry expires.
+pub struct UserCache(ARCache<String, CachedUser>);
+
+impl UserCache {
+    pub fn new() -> Self {
+        let cache = ARCacheBuilder::new()
+            .set_size(CACHE_SIZE, num_cpus::get())
+            .build()
+            .unwrap();
+
+        Self(cache)
+    }
+
+    /// Removes the tokens with the given storage keys from the cache.
+    pub fn invalidate(&self, token_keys: impl IntoIterator<Item = String>) {
+        let mut writer = self.0.write();
+        for key in token_keys {
+            writer.remove(key);
+        }
+        writer.commit();
+    }
+
+    fn get(&self, token_key: &str) -> Option<CachedUser> {
+        let mut reader = self.0.read();
+        reader.get(token_key)
+            .filter(|v| v.cached_at.elapsed() < CACHE_TTL)
+            .cloned()
+    }
+
+    fn insert(&self, token_key: String, user: CachedUser) {
+        let mut writer = self.0.write();
+        writer.insert(token_key, user);
+        writer.commit();
+    }
+}
+
+impl Default for UserCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Resolves a bearer token to its user, using the cache where possible.
+///
+/// Without a scope only access tokens are accepted, with one API keys
+/// which were granted that scope are accepted too.
+pub async fn authenticate(
+    sess: &Session,
+    cache: &UserCache,
+    token: &str,
+    scope: Option<ApiScope>,
+) -> Result<Option<AuthenticatedUser>> {
+    let is_api_key = tokens::is_api_key(token);
+    if is_api_key & scope.is_none() {
+        return Ok(None)
+    }
+
+    let token_key = match tokens::storage_key(token) {
+        None => return Ok(None),
+        Some(token_key) => token_key,
+    };
+
+    let cached = match cache.get(&token_key) {
+        Some(cached) => cached,
+        None => {
+            let cached = match resolve(sess, token, is_api_key).await? {
+                None => return Ok(None),
+                Some(cached) => cached,
+            };
+
+            cache.insert(token_key, cached.clone());
+            cached
+        },
+    };
+
+    if let (Some(grant), Some(scope)) = (cached.grant.as_ref(), scope) {
+        if grant.is_expired() | !grant.scopes.contains(&scope) {
+            return Ok(None)
+        }
+    }
+
+    Ok(Some(cached.user))
+}
+
+
+async fn resolve(sess: &Session, token: &str, is_api_key: bool) -> Result<Option<CachedUser>> {
+    let (user_id, grant) = if is_api_key {
+        match api_keys::get_key_grant(sess, token).await? {
+            None => return Ok(None),
+            Some(grant) => (grant.user_id, Some(grant)),
+        }
+    } else {
+        match user_info::get_user_id_from_token(sess, token).await? {
+            None => return Ok(None),
+            Some(user_id) => (user_id, None),
+        }
+    };
+
+    let user = match user_info::get_user_from_id(sess, user_id).await? {
+        None => return Ok(None),
+        Some(user) => user,
+    };
+
+    Ok(Some(CachedUser {
+        user: AuthenticatedUser { id: user_id, user },
+        grant,
+        cached_at: Instant::now(),
+    }))
+}
@@ -1,105 +1,203 @@
+pub mod api_keys;
 mod discord;
+pub mod identity;
+mod oauth;
+pub mod refresh;
+pub mod sessions;
+mod tokens;
 
-use std::collections::HashMap;
-use poem::Result;
+pub use tokens::check_secret;
+
+use std::sync::Arc;
+use poem::{Request, Result};
 use poem::web::Data;
-use poem_openapi::OpenApi;
-use poem_openapi::payload::Json;
+use poem_openapi::{ApiResponse, OpenApi};
 use poem_openapi::param::Query;
 use poem_openapi::Object;
-use rand::distributions::Alphanumeric;
-use rand::Rng;
+use serde_json::Value;
+use uuid::Uuid;
 
 use crate::ApiTags;
-use crate::auth::discord::Guild;
+use crate::admin::audit::{self, AuditAction, AuditRecord, TargetKind};
+use crate::auth::discord::DiscordError;
+use crate::auth::identity::UserCache;
+use crate::auth::sessions::SessionInfo;
 use crate::db::Session;
+use crate::utils::{JsonResponse, TokenBearer};
 
 #[derive(Object)]
 pub struct ExchangePayload {
     access_token: String
 }
 
+#[derive(ApiResponse)]
+pub enum LoginResponse {
+    /// Redirects the user to Discord to authorize the login.
+    #[oai(status = 302)]
+    Redirect(#[oai(header = "Location")] String),
+}
+
 pub struct AuthApi;
 
 #[OpenApi]
 impl AuthApi {
+    /// Login
+    ///
+    /// Starts logging in with Discord, redirecting the user to authorize
+    /// the login. Discord then redirects back to `/auth/authorize`.
+    #[oai(path = "/auth/login", method = "get", tag = "ApiTags::Auth")]
+    pub async fn login(&self, req: &Request) -> Result<LoginResponse> {
+        let url = oauth::start_login(req)?;
+        Ok(LoginResponse::Redirect(url))
+    }
+
     /// Exchange code
     ///
     /// Exchanges a Discord code for a generated access token used for
     /// authorization.
+    ///
+    /// The state must be the one given to Discord by `/auth/login` from
+    /// the same browser within the last 10 minutes.
+    ///
+    /// Each token starts a new session which expires after 28 days of not
+    /// being used.
     #[oai(path = "/auth/authorize", method = "get", tag = "ApiTags::Auth")]
     pub async fn exchange_code(
         &self,
         code: Query<String>,
+        state: Query<String>,
+        req: &Request,
         session: Data<&Session>
-    ) -> Result<Json<ExchangePayload>> {
-        let access_token = discord::exchange_code(&code.0).await?;
-
-        let user = discord::fetch_user_info(&access_token).await?;
-        let guilds = discord::fetch_user_guilds(&access_token).await?;
-
-        update_guilds(&session, &guilds).await?;
-
-        let guilds: HashMap<i64, bool> = guilds.into_iter()
-            .map(|v| {
-                (v.id, v.is_manager())
-            })
-            .collect();
-
-        session.query(
-            "INSERT INTO users (id, username, avatar, updated_on, access_servers) VALUES (?, ?, ?, toTimeStamp(now()), ?);",
-            (user.id, user.username, user.avatar, guilds)
-        ).await?;
-
-        let access_token = generate_token(48);
-        session.query_prepared(
-            "INSERT INTO access_tokens (user_id, access_token) VALUES (?, ?);",
-            (user.id, access_token.clone()),
-        ).await?;
-
-
-        Ok(Json(
+    ) -> Result<JsonResponse<ExchangePayload>> {
+        let code_verifier = match oauth::finish_login(req, &state.0) {
+            None => return Ok(JsonResponse::bad_request("Invalid or expired login state, please try logging in again.")),
+            Some(v) => v,
+        };
+
+        let grant = match discord::CLIENT.exchange_code(&code.0, &code_verifier).await {
+            Ok(grant) => grant,
+            Err(DiscordError::InvalidCode) => {
+                return Ok(JsonResponse::bad_request("Invalid or expired code, please try logging in again."))
+            },
+            Err(e) => return Err(anyhow::Error::from(e).into()),
+        };
+        let user_id = refresh::save_user_data(&session, &grant.access_token).await?;
+
+        if let Some(refresh_token) = grant.refresh_token.as_ref() {
+            refresh::store_refresh_token(&session, user_id, refresh_token).await?;
+        }
+
+        let access_token = sessions::create_session(&session, user_id, req).await?;
+
+        Ok(JsonResponse::ok(
             ExchangePayload {
                 access_token,
             }
         ))
     }
 
+    /// List Sessions
+    ///
+    /// Lists all of the user's active sessions, most recently used first.
+    #[oai(path = "/auth/sessions", method = "get", tag = "ApiTags::Auth")]
+    pub async fn list_sessions(
+        &self,
+        token: TokenBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Vec<SessionInfo>>> {
+        let current = match sessions::get_token_session(&session, &token.0.token).await? {
+            None => return Ok(JsonResponse::unauthorized()),
+            Some(v) => v,
+        };
+
+        let sessions = sessions::list_sessions(&session, current.user_id, current.session_id).await?;
+
+        Ok(JsonResponse::ok(sessions))
+    }
+
     /// Revoke Token
     ///
-    /// Revokes the given access token.
+    /// Revokes one of the user's sessions, defaulting to the session of the
+    /// token making the request.
     #[oai(path = "/auth/revoke", method = "post", tag = "ApiTags::Auth")]
     pub async fn revoke_token(
         &self,
-        token: Query<String>,
+        id: Query<Option<Uuid>>,
+        token: TokenBearer,
         session: Data<&Session>,
-    ) -> Result<()> {
-        session.query_prepared(
-            "DELETE FROM access_tokens WHERE access_token = ?;",
-            (token.0,)
-        ).await?;
-
-        Ok(())
+        cache: Data<&Arc<UserCache>>,
+    ) -> Result<JsonResponse<Value>> {
+        let current = match sessions::get_token_session(&session, &token.0.token).await? {
+            None => return Ok(JsonResponse::unauthorized()),
+            Some(v) => v,
+        };
+
+        let session_id = match id.0.or(current.session_id) {
+            Some(session_id) => session_id,
+            None => {
+                // Tokens from before sessions existed can only revoke themselves.
+                sessions::revoke_token(&session, &cache, &token.0.token).await?;
+                audit::record(&session, AuditRecord {
+                    actor_id: Some(current.user_id),
+                    action: AuditAction::SessionsRevoked,
+                    target_kind: TargetKind::User,
+                    target_id: current.user_id.to_string(),
+                    before: None,
+                    after: None,
+                }).await;
+
+                return Ok(JsonResponse::ok(Value::Null))
+            },
+        };
+
+        if !sessions::revoke_session(&session, &cache, current.user_id, session_id).await? {
+            return Ok(JsonResponse::bad_request("No session exists with this id."))
+        }
+
+        audit::record(&session, AuditRecord {
+            actor_id: Some(current.user_id),
+            action: AuditAction::SessionsRevoked,
+            target_kind: TargetKind::Session,
+            target_id: session_id.to_string(),
+            before: None,
+            after: None,
+        }).await;
+
+        Ok(JsonResponse::ok(Value::Null))
     }
-}
 
-async fn update_guilds(sess: &Session, guilds: &[Guild]) -> anyhow::Result<()> {
-    for guild in guilds {
-        sess.query_prepared(
-            "INSERT INTO guilds (id, name, icon, updated_on) VALUES (?, ?, ?, toTimeStamp(now()));",
-            (guild.id, guild.name.clone(), guild.icon.to_owned()),
-        ).await?;
+    /// Revoke All Tokens
+    ///
+    /// Revokes every one of the user's sessions, including the one making
+    /// the request. Tokens issued before sessions were introduced aren't
+    /// tied to a session, only the one making the request is revoked, any
+    /// others stay valid until they expire.
+    #[oai(path = "/auth/revoke/all", method = "post", tag = "ApiTags::Auth")]
+    pub async fn revoke_all_tokens(
+        &self,
+        token: TokenBearer,
+        session: Data<&Session>,
+        cache: Data<&Arc<UserCache>>,
+    ) -> Result<JsonResponse<Value>> {
+        let current = match sessions::get_token_session(&session, &token.0.token).await? {
+            None => return Ok(JsonResponse::unauthorized()),
+            Some(v) => v,
+        };
+
+        sessions::revoke_all_sessions(&session, &cache, current.user_id).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: Some(current.user_id),
+            action: AuditAction::SessionsRevoked,
+            target_kind: TargetKind::User,
+            target_id: current.user_id.to_string(),
+            before: None,
+            after: None,
+        }).await;
+
+        if current.session_id.is_none() {
+            sessions::revoke_token(&session, &cache, &token.0.token).await?;
+        }
+
+        Ok(JsonResponse::ok(Value::Null))
     }
-
-    Ok(())
 }
-
-
-#[inline]
-fn generate_token(length: usize) -> String {
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(length)
-        .map(char::from)
-        .collect()
-}
\ No newline at end of file
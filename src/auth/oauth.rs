@@ -0,0 +1,166 @@
+use std::time::Duration;
+use poem::Request;
+use poem::web::cookie::{Cookie, SameSite};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{discord, tokens};
+use crate::utils::generate_token;
+
+
+/// The cookie the login state is kept in while the user is on Discord.
+const STATE_COOKIE: &str = "login_state";
+
+/// How long users have to authorize a login, in seconds.
+const STATE_TTL_SECS: i64 = 600;
+
+/// The length of the random part of the login state.
+const STATE_NONCE_LENGTH: usize = 24;
+
+
+/// Starts a login, returning the Discord URL the user should be sent to.
+///
+/// The state is signed and carries its own expiry so nothing needs to be
+/// stored, it's also set as a cookie which ties the login to the browser
+/// that started it. The PKCE verifier is derived from the state.
+pub fn start_login(req: &Request) -> anyhow::Result<String> {
+    let state = new_state(chrono::Utc::now().timestamp());
+    let url = discord::CLIENT.authorize_url(&state, &code_challenge(&code_verifier(&state)))?;
+
+    let mut cookie = Cookie::new_with_str(STATE_COOKIE, state);
+    cookie.set_http_only(true);
+    cookie.set_secure(discord::is_secure_redirect());
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_path("/");
+    cookie.set_max_age(Duration::from_secs(STATE_TTL_SECS as u64));
+    req.cookie().add(cookie);
+
+    Ok(url)
+}
+
+/// Finishes a login, returning the PKCE verifier for the code if the
+/// state is valid.
+///
+/// The state must match the browser's cookie, have a valid signature and
+/// not have expired. The cookie is removed either way so a state can't
+/// be reused.
+pub fn finish_login(req: &Request, state: &str) -> Option<String> {
+    let expected = req.cookie().get(STATE_COOKIE)?;
+    req.cookie().remove(STATE_COOKIE);
+
+    check_state(expected.value_str(), state, chrono::Utc::now().timestamp())
+}
+
+
+/// Creates a signed login state which expires `STATE_TTL_SECS` after
+/// `now`, in seconds.
+fn new_state(now: i64) -> String {
+    let expires_on = now + STATE_TTL_SECS;
+    let payload = format!("{}.{}", expires_on, generate_token(STATE_NONCE_LENGTH));
+    format!("{}.{}", payload, tokens::sign(&state_message(&payload)))
+}
+
+/// Checks the state matches the one the browser started with, was
+/// signed by `new_state` and hasn't expired by `now`, returning its PKCE
+/// verifier if so.
+fn check_state(expected: &str, state: &str, now: i64) -> Option<String> {
+    if expected != state {
+        return None
+    }
+
+    let (payload, signature) = state.rsplit_once('.')?;
+    if !tokens::verify(&state_message(payload), signature) {
+        return None
+    }
+
+    let (expires_on, _) = payload.split_once('.')?;
+    if expires_on.parse::<i64>().ok()? < now {
+        return None
+    }
+
+    Some(code_verifier(state))
+}
+
+fn state_message(payload: &str) -> String {
+    format!("state:{}", payload)
+}
+
+/// Derives the PKCE verifier for the state, 43 URL safe characters.
+fn code_verifier(state: &str) -> String {
+    tokens::sign(&format!("pkce:{}", state))
+}
+
+fn code_challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    /// The same secret the token tests use, see `tokens::tests`.
+    fn set_secret() {
+        std::env::set_var("TOKEN_SECRET", "test-secret");
+    }
+
+    #[test]
+    fn valid_state_is_accepted() {
+        set_secret();
+
+        let state = new_state(NOW);
+        assert_eq!(check_state(&state, &state, NOW + STATE_TTL_SECS), Some(code_verifier(&state)));
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        set_secret();
+
+        let state = new_state(NOW);
+        let (payload, signature) = state.rsplit_once('.').unwrap();
+
+        let mut signature = signature.to_string();
+        let first = if signature.starts_with('A') { "B" } else { "A" };
+        signature.replace_range(..1, first);
+
+        let tampered = format!("{}.{}", payload, signature);
+        assert_eq!(check_state(&tampered, &tampered, NOW), None);
+
+        // Nor can the expiry be pushed back without re-signing.
+        let (_, rest) = state.split_once('.').unwrap();
+        let extended = format!("{}.{}", NOW + 2 * STATE_TTL_SECS, rest);
+        assert_eq!(check_state(&extended, &extended, NOW), None);
+    }
+
+    #[test]
+    fn expired_state_is_rejected() {
+        set_secret();
+
+        let state = new_state(NOW);
+        assert_eq!(check_state(&state, &state, NOW + STATE_TTL_SECS + 1), None);
+    }
+
+    #[test]
+    fn mismatched_cookie_is_rejected() {
+        set_secret();
+
+        let state = new_state(NOW);
+        assert_eq!(check_state(&new_state(NOW), &state, NOW), None);
+        assert_eq!(check_state("", &state, NOW), None);
+    }
+
+    #[test]
+    fn pkce_verifier_matches_challenge() {
+        set_secret();
+
+        let state = new_state(NOW);
+        let challenge = code_challenge(&code_verifier(&state));
+
+        let verifier = check_state(&state, &state, NOW).unwrap();
+        assert_eq!(verifier.len(), 43);
+        assert!(verifier.chars().all(|c| c.is_ascii_alphanumeric() | (c == '-') | (c == '_')));
+        assert_eq!(code_challenge(&verifier), challenge);
+        assert_ne!(verifier, code_verifier(&new_state(NOW)));
+    }
+}
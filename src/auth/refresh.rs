@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+
+use crate::auth::discord::{DiscordError, Guild, CLIENT};
+use crate::auth::tokens;
+use crate::db::{self, Session};
+use crate::utils::Timestamp;
+
+
+/// How often users are checked for stale Discord data.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How old a user's Discord data can get before it's refreshed, in seconds.
+const STALE_AFTER_SECS: i64 = 86400;
+
+/// The most users refreshed each interval, any others are picked up by
+/// the next one.
+const REFRESH_BATCH_SIZE: i32 = 100;
+
+/// How long to wait before trying to refresh a user again after it
+/// failed for a reason other than the token being revoked, in seconds.
+const RETRY_AFTER_SECS: i64 = 3600;
+
+
+/// Starts refreshing stale Discord data in the background.
+///
+/// Users are refreshed with their stored refresh token once it's older
+/// than `STALE_AFTER_SECS`, users without one are left until they next
+/// log in.
+pub fn start_refresher(sess: Session) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = refresh_stale(&sess).await {
+                error!("failed to refresh stale users: {}", e);
+            }
+        }
+    });
+}
+
+/// Fetches the user's info and guilds from Discord with the given access
+/// token and saves them, returning the user's id.
+pub async fn save_user_data(sess: &Session, access_token: &str) -> Result<i64> {
+    let user = CLIENT.fetch_user_info(access_token).await?;
+    let guilds = CLIENT.fetch_user_guilds(access_token).await?;
+
+    update_guilds(sess, &guilds).await?;
+
+    let guilds: HashMap<i64, bool> = guilds.into_iter()
+        .map(|v| {
+            (v.id, v.is_manager())
+        })
+        .collect();
+
+    sess.query_prepared(
+        "INSERT INTO users (id, username, avatar, updated_on, access_servers) VALUES (?, ?, ?, toTimeStamp(now()), ?);",
+        (user.id, user.username, user.avatar, guilds)
+    ).await?;
+
+    Ok(user.id)
+}
+
+/// Stores the user's Discord refresh token encrypted, see `tokens::encrypt`.
+pub async fn store_refresh_token(sess: &Session, user_id: i64, refresh_token: &str) -> Result<()> {
+    let previous = get_stored_refresh_token(sess, user_id).await?;
+    let now = Timestamp::now();
+
+    sess.query_prepared(
+        "INSERT INTO discord_tokens (user_id, refresh_token, updated_on) VALUES (?, ?, ?);",
+        (user_id, tokens::encrypt(refresh_token)?, now)
+    ).await?;
+
+    sess.query_prepared(
+        "INSERT INTO discord_tokens_by_update (bucket, updated_on, user_id) VALUES (0, ?, ?);",
+        (now, user_id)
+    ).await?;
+
+    if let Some((_, updated_on)) = previous {
+        remove_from_schedule(sess, user_id, updated_on).await?;
+    }
+
+    Ok(())
+}
+
+/// Refreshes the user's Discord data using their stored refresh token.
+///
+/// Returns `false` if the user has no usable refresh token, tokens which
+/// Discord rejects or which can no longer be decrypted are removed.
+pub async fn resync_user(sess: &Session, user_id: i64) -> Result<bool> {
+    let (stored, updated_on) = match get_stored_refresh_token(sess, user_id).await? {
+        None => return Ok(false),
+        Some(stored) => stored,
+    };
+
+    let refresh_token = match tokens::decrypt(&stored) {
+        Ok(refresh_token) => refresh_token,
+        Err(e) => {
+            warn!("failed to decrypt refresh token for user {}: {}", user_id, e);
+            remove_refresh_token(sess, user_id, &stored, updated_on).await?;
+            return Ok(false)
+        },
+    };
+
+    let grant = match CLIENT.refresh_token(&refresh_token).await {
+        Ok(grant) => grant,
+        Err(DiscordError::RevokedToken) => {
+            remove_refresh_token(sess, user_id, &stored, updated_on).await?;
+            return Ok(false)
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    // Discord doesn't always rotate the refresh token, storing it again
+    // either way moves the user to the back of the refresh schedule.
+    let refresh_token = grant.refresh_token.as_deref().unwrap_or(&refresh_token);
+    store_refresh_token(sess, user_id, refresh_token).await?;
+
+    save_user_data(sess, &grant.access_token).await?;
+
+    Ok(true)
+}
+
+
+/// Gets the user's encrypted refresh token and when it was stored.
+async fn get_stored_refresh_token(sess: &Session, user_id: i64) -> Result<Option<(String, Timestamp)>> {
+    let result = sess.query_prepared(
+        "SELECT refresh_token, updated_on FROM discord_tokens WHERE user_id = ?;",
+        (user_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let stored = match rows.into_typed::<(String, Timestamp)>().next() {
+        None => None,
+        Some(v) => Some(v?),
+    };
+
+    Ok(stored)
+}
+
+/// Removes the user's refresh token so they have to log in again.
+async fn remove_refresh_token(
+    sess: &Session,
+    user_id: i64,
+    stored: &str,
+    updated_on: Timestamp,
+) -> Result<()> {
+    // Only remove the token if it wasn't just replaced by another refresh.
+    let result = sess.query_prepared(
+        "DELETE FROM discord_tokens WHERE user_id = ? IF refresh_token = ?;",
+        (user_id, stored)
+    ).await?;
+
+    if db::was_applied(&result) {
+        debug!("removed unusable refresh token for user {}", user_id);
+        remove_from_schedule(sess, user_id, updated_on).await?;
+    }
+
+    Ok(())
+}
+
+async fn remove_from_schedule(sess: &Session, user_id: i64, updated_on: Timestamp) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM discord_tokens_by_update WHERE bucket = 0 AND updated_on = ? AND user_id = ?;",
+        (updated_on, user_id)
+    ).await?;
+
+    Ok(())
+}
+
+/// Resyncs the users whose refresh tokens are older than
+/// `STALE_AFTER_SECS`, oldest first and at most `REFRESH_BATCH_SIZE` at
+/// a time.
+///
+/// Failures for a single user are logged rather than stopping the
+/// refresh, the user is tried again after `RETRY_AFTER_SECS` so they
+/// don't hold up the rest of the schedule.
+async fn refresh_stale(sess: &Session) -> Result<()> {
+    let result = sess.query_prepared(
+        r#"
+        SELECT updated_on, user_id FROM discord_tokens_by_update
+        WHERE bucket = 0 AND updated_on < ?
+        LIMIT ?;
+        "#,
+        (Timestamp(*Timestamp::now() - STALE_AFTER_SECS * 1000), REFRESH_BATCH_SIZE)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    for (updated_on, user_id) in rows.into_typed::<(Timestamp, i64)>().filter_map(|v| v.ok()) {
+        // Entries left behind by a token which has since been replaced or
+        // removed are cleaned up rather than refreshed again.
+        let stored = match get_stored_refresh_token(sess, user_id).await? {
+            Some((stored, current)) if current == updated_on => stored,
+            _ => {
+                remove_from_schedule(sess, user_id, updated_on).await?;
+                continue;
+            },
+        };
+
+        if let Err(e) = resync_user(sess, user_id).await {
+            warn!("failed to refresh discord data for user {}: {}", user_id, e);
+            reschedule(sess, user_id, &stored, updated_on).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the user's refresh token back in the schedule so it's next
+/// picked up `RETRY_AFTER_SECS` from now.
+async fn reschedule(sess: &Session, user_id: i64, stored: &str, updated_on: Timestamp) -> Result<()> {
+    let retry_on = Timestamp(*Timestamp::now() - (STALE_AFTER_SECS - RETRY_AFTER_SECS) * 1000);
+
+    // Only move the token if it wasn't just replaced by another refresh.
+    let result = sess.query_prepared(
+        "UPDATE discord_tokens SET updated_on = ? WHERE user_id = ? IF refresh_token = ?;",
+        (retry_on, user_id, stored)
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return Ok(())
+    }
+
+    sess.query_prepared(
+        "INSERT INTO discord_tokens_by_update (bucket, updated_on, user_id) VALUES (0, ?, ?);",
+        (retry_on, user_id)
+    ).await?;
+
+    remove_from_schedule(sess, user_id, updated_on).await
+}
+
+async fn update_guilds(sess: &Session, guilds: &[Guild]) -> Result<()> {
+    for guild in guilds {
+        sess.query_prepared(
+            "INSERT INTO guilds (id, name, icon, updated_on) VALUES (?, ?, ?, toTimeStamp(now()));",
+            (guild.id, guild.name.clone(), guild.icon.to_owned()),
+        ).await?;
+    }
+
+    Ok(())
+}
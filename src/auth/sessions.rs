@@ -0,0 +1,397 @@
+use std::net::IpAddr;
+use anyhow::{anyhow, Result};
+use poem::Request;
+use poem::http::header;
+use poem_openapi::Object;
+use scylla::IntoTypedRows;
+use uuid::Uuid;
+
+use crate::auth::identity::UserCache;
+use crate::auth::tokens;
+use crate::db::{self, Session};
+use crate::utils::Timestamp;
+
+
+/// How long a session lasts without being used, in seconds.
+const SESSION_TTL_SECS: i64 = 2419200;
+
+/// How often a session's expiry is pushed back while it's being used, in
+/// seconds. This stops every request from rewriting the session.
+const SESSION_TOUCH_INTERVAL_SECS: i64 = 3600;
+
+lazy_static! {
+    /// The reverse proxies whose `X-Forwarded-For` header is trusted, as a
+    /// comma separated list of IPs.
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = {
+        std::env::var("TRUSTED_PROXIES")
+            .map(|v| {
+                v.split(',')
+                    .filter_map(|v| v.trim().parse::<IpAddr>().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+}
+
+
+#[derive(Object)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub created_at: Timestamp,
+    pub last_used_at: Timestamp,
+
+    /// When the session expires if it's not used again before then.
+    pub expires_at: Timestamp,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+
+    /// If this is the session making the request.
+    pub current: bool,
+}
+
+/// The session an access token belongs to.
+pub struct TokenSession {
+    pub user_id: i64,
+
+    /// The id of the session, tokens issued before sessions existed
+    /// don't have one.
+    pub session_id: Option<Uuid>,
+}
+
+
+/// Creates a new session for the user, returning its access token.
+///
+/// The user agent and IP are taken from the request, see `client_ip`.
+pub async fn create_session(sess: &Session, user_id: i64, req: &Request) -> Result<String> {
+    let access_token = tokens::issue_token();
+    let now = Timestamp::now();
+
+    let user_agent = req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    let ip = client_ip(req).map(|v| v.to_string());
+
+    let session = StoredSession {
+        id: Uuid::new_v4(),
+        token_key: tokens::storage_key(&access_token)
+            .ok_or_else(|| anyhow!("issued token has no storage key"))?,
+        created_at: now,
+        user_agent,
+        ip,
+    };
+
+    save_session(sess, user_id, &session, now).await?;
+
+    Ok(access_token)
+}
+
+/// Gets the session the access token belongs to if it's valid.
+///
+/// Sessions slide, each time one is used its expiry is pushed back to
+/// `SESSION_TTL_SECS` from now, at most once every
+/// `SESSION_TOUCH_INTERVAL_SECS`.
+pub async fn get_token_session(sess: &Session, token: &str) -> Result<Option<TokenSession>> {
+    let token_key = match tokens::storage_key(token) {
+        None => return Ok(None),
+        Some(token_key) => token_key,
+    };
+
+    let result = sess.query_prepared(
+        "SELECT user_id, session_id, last_used_at FROM access_tokens WHERE access_token = ?;",
+        (token_key,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let (user_id, session_id, last_used_at) = match rows.into_typed::<(i64, Option<Uuid>, Option<Timestamp>)>().next() {
+        None => return Ok(None),
+        Some(v) => v?,
+    };
+
+    if let (Some(session_id), Some(last_used_at)) = (session_id, last_used_at) {
+        let now = Timestamp::now();
+        if (*now - *last_used_at) / 1000 >= SESSION_TOUCH_INTERVAL_SECS {
+            touch_session(sess, user_id, session_id, now).await?;
+        }
+    }
+
+    Ok(Some(TokenSession { user_id, session_id }))
+}
+
+/// Lists all of the user's active sessions, marking the current one.
+pub async fn list_sessions(sess: &Session, user_id: i64, current: Option<Uuid>) -> Result<Vec<SessionInfo>> {
+    let result = sess.query_prepared(
+        r#"
+        SELECT id, created_at, last_used_at, expires_at, user_agent, ip
+        FROM user_sessions
+        WHERE user_id = ?;
+        "#,
+        (user_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    type SessionRow = (Uuid, Timestamp, Timestamp, Timestamp, Option<String>, Option<String>);
+    let mut sessions: Vec<SessionInfo> = rows.into_typed::<SessionRow>()
+        .filter_map(|v| v.ok())
+        .map(|v| SessionInfo {
+            id: v.0,
+            created_at: v.1,
+            last_used_at: v.2,
+            expires_at: v.3,
+            user_agent: v.4,
+            ip: v.5,
+            current: Some(v.0) == current,
+        })
+        .collect();
+
+    sessions.sort_by_key(|v| std::cmp::Reverse(*v.last_used_at));
+
+    Ok(sessions)
+}
+
+/// Revokes one of the user's sessions, returning `false` if the user has
+/// no session with the given id.
+pub async fn revoke_session(sess: &Session, cache: &UserCache, user_id: i64, session_id: Uuid) -> Result<bool> {
+    let session = match get_stored_session(sess, user_id, session_id).await? {
+        None => return Ok(false),
+        Some(session) => session,
+    };
+
+    sess.query_prepared(
+        "DELETE FROM access_tokens WHERE access_token = ?;",
+        (&session.token_key,)
+    ).await?;
+
+    sess.query_prepared(
+        "DELETE FROM user_sessions WHERE user_id = ? AND id = ?;",
+        (user_id, session_id)
+    ).await?;
+
+    cache.invalidate([session.token_key]);
+
+    Ok(true)
+}
+
+/// Revokes every session the user has.
+///
+/// Tokens issued before sessions existed aren't tracked per user so they
+/// can't be found here, they stay valid until they expire.
+pub async fn revoke_all_sessions(sess: &Session, cache: &UserCache, user_id: i64) -> Result<()> {
+    let result = sess.query_prepared(
+        "SELECT access_token FROM user_sessions WHERE user_id = ?;",
+        (user_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let token_keys: Vec<String> = rows.into_typed::<(String,)>()
+        .filter_map(|v| v.ok())
+        .map(|v| v.0)
+        .collect();
+
+    if token_keys.is_empty() {
+        return Ok(())
+    }
+
+    for chunk in token_keys.chunks(db::MAX_IN_VALUES) {
+        sess.query_prepared(
+            "DELETE FROM access_tokens WHERE access_token IN ?;",
+            (chunk.to_vec(),)
+        ).await?;
+    }
+
+    sess.query_prepared(
+        "DELETE FROM user_sessions WHERE user_id = ?;",
+        (user_id,)
+    ).await?;
+
+    cache.invalidate(token_keys);
+
+    Ok(())
+}
+
+/// Revokes a single access token, used for tokens which don't belong to a
+/// session.
+pub async fn revoke_token(sess: &Session, cache: &UserCache, token: &str) -> Result<()> {
+    let token_key = match tokens::storage_key(token) {
+        None => return Ok(()),
+        Some(token_key) => token_key,
+    };
+
+    sess.query_prepared(
+        "DELETE FROM access_tokens WHERE access_token = ?;",
+        (&token_key,)
+    ).await?;
+
+    cache.invalidate([token_key]);
+
+    Ok(())
+}
+
+
+/// Gets the IP of the client making the request.
+///
+/// The `X-Forwarded-For` header is only used when the request comes from
+/// one of the `TRUSTED_PROXIES`, the client is the last address in it
+/// which isn't one of them. Anyone else could set the header to anything.
+fn client_ip(req: &Request) -> Option<IpAddr> {
+    let remote_ip = req.remote_addr()
+        .as_socket_addr()
+        .map(|v| v.ip())?;
+
+    if !TRUSTED_PROXIES.contains(&remote_ip) {
+        return Some(remote_ip)
+    }
+
+    let forwarded_ip = req.headers()
+        .get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.rsplit(',')
+                .filter_map(|v| v.trim().parse::<IpAddr>().ok())
+                .find(|v| !TRUSTED_PROXIES.contains(v))
+        });
+
+    Some(forwarded_ip.unwrap_or(remote_ip))
+}
+
+
+/// The parts of a session which don't change when it's used.
+struct StoredSession {
+    id: Uuid,
+
+    /// The key the session's token is stored under, see `tokens::storage_key`.
+    token_key: String,
+    created_at: Timestamp,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+async fn get_stored_session(sess: &Session, user_id: i64, session_id: Uuid) -> Result<Option<StoredSession>> {
+    let result = sess.query_prepared(
+        "SELECT access_token, created_at, user_agent, ip FROM user_sessions WHERE user_id = ? AND id = ?;",
+        (user_id, session_id)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let session = rows.into_typed::<(String, Timestamp, Option<String>, Option<String>)>()
+        .next()
+        .transpose()?
+        .map(|v| StoredSession {
+            id: session_id,
+            token_key: v.0,
+            created_at: v.1,
+            user_agent: v.2,
+            ip: v.3,
+        });
+
+    Ok(session)
+}
+
+/// Marks the session as used now, resetting its expiry.
+///
+/// Both writes are lightweight transactions which only apply while the
+/// session and its token still exist, so a session revoked part way
+/// through isn't brought back.
+async fn touch_session(sess: &Session, user_id: i64, session_id: Uuid, now: Timestamp) -> Result<()> {
+    let session = match get_stored_session(sess, user_id, session_id).await? {
+        None => return Ok(()),
+        Some(session) => session,
+    };
+
+    let expires_at = Timestamp(*now + SESSION_TTL_SECS * 1000);
+
+    // Every column is rewritten as a TTL only applies to the columns
+    // written with it.
+    let result = sess.query_prepared(
+        r#"
+        UPDATE user_sessions USING TTL ?
+        SET access_token = ?, created_at = ?, last_used_at = ?, expires_at = ?, user_agent = ?, ip = ?
+        WHERE user_id = ? AND id = ?
+        IF EXISTS;
+        "#,
+        (
+            SESSION_TTL_SECS as i32,
+            &session.token_key,
+            session.created_at,
+            now,
+            expires_at,
+            &session.user_agent,
+            &session.ip,
+            user_id,
+            session.id,
+        )
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return Ok(())
+    }
+
+    sess.query_prepared(
+        r#"
+        UPDATE access_tokens USING TTL ?
+        SET user_id = ?, session_id = ?, last_used_at = ?
+        WHERE access_token = ?
+        IF EXISTS;
+        "#,
+        (SESSION_TTL_SECS as i32, user_id, session.id, now, &session.token_key)
+    ).await?;
+
+    Ok(())
+}
+
+/// Writes the whole session with a fresh TTL.
+///
+/// Every column is rewritten as a TTL only applies to the columns written
+/// with it.
+async fn save_session(sess: &Session, user_id: i64, session: &StoredSession, now: Timestamp) -> Result<()> {
+    let expires_at = Timestamp(*now + SESSION_TTL_SECS * 1000);
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO user_sessions (
+            user_id,
+            id,
+            access_token,
+            created_at,
+            last_used_at,
+            expires_at,
+            user_agent,
+            ip
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?) USING TTL ?;
+        "#,
+        (
+            user_id,
+            session.id,
+            &session.token_key,
+            session.created_at,
+            now,
+            expires_at,
+            &session.user_agent,
+            &session.ip,
+            SESSION_TTL_SECS as i32,
+        )
+    ).await?;
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO access_tokens (
+            access_token,
+            user_id,
+            session_id,
+            last_used_at
+        ) VALUES (?, ?, ?, ?) USING TTL ?;
+        "#,
+        (&session.token_key, user_id, session.id, now, SESSION_TTL_SECS as i32)
+    ).await?;
+
+    Ok(())
+}
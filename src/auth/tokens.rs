@@ -0,0 +1,225 @@
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::Aead;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::utils::generate_token;
+
+
+lazy_static! {
+    static ref TOKEN_SECRET: String = {
+        std::env::var("TOKEN_SECRET")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .expect("TOKEN_SECRET must be set")
+    };
+
+    /// The key secrets are encrypted with, derived from `TOKEN_SECRET`.
+    static ref ENCRYPTION_KEY: Aes256Gcm = {
+        let key = mac("encryption-key").finalize().into_bytes();
+        Aes256Gcm::new(&key)
+    };
+}
+
+/// The scheme new access tokens are issued and stored with.
+const TOKEN_VERSION: &str = "v1";
+
+/// The scheme new API keys are issued and stored with.
+const API_KEY_VERSION: &str = "k1";
+
+/// The length of the random part of generated access tokens and API keys,
+/// also the length of tokens issued before tokens were versioned.
+const TOKEN_LENGTH: usize = 48;
+
+/// The scheme secrets are encrypted with.
+const ENCRYPTION_VERSION: &str = "v1";
+
+/// The length of the AES-GCM nonce in bytes.
+const NONCE_LENGTH: usize = 12;
+
+
+/// Checks `TOKEN_SECRET` is set.
+///
+/// The secret must be the same across restarts and instances, otherwise
+/// every session, login in progress and stored secret stops working, so
+/// this should be called at startup rather than waiting for the first
+/// token to be used.
+pub fn check_secret() -> Result<()> {
+    match std::env::var("TOKEN_SECRET") {
+        Ok(secret) if !secret.is_empty() => Ok(()),
+        _ => Err(anyhow!("TOKEN_SECRET must be set")),
+    }
+}
+
+/// Generates a new access token in the current scheme.
+///
+/// Tokens are prefixed with the scheme version followed by a `.` so the
+/// scheme can be rotated without invalidating existing tokens.
+pub fn issue_token() -> String {
+    format!("{}.{}", TOKEN_VERSION, generate_token(TOKEN_LENGTH))
+}
+
+/// Generates a new API key, these are stored the same way as access
+/// tokens but have their own prefix so they can't be used as one.
+pub fn issue_api_key() -> String {
+    format!("{}.{}", API_KEY_VERSION, generate_token(TOKEN_LENGTH))
+}
+
+/// If the token is an API key rather than an access token.
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(&format!("{}.", API_KEY_VERSION))
+}
+
+/// Gets the key the access token or API key is stored under, `None` if
+/// it's not in any known format.
+///
+/// `v1` tokens and `k1` keys are stored as the base64 encoded HMAC-SHA256
+/// of the token keyed with `TOKEN_SECRET`, prefixed with their version
+/// and a `:`. Tokens without a version prefix were issued before tokens
+/// were hashed and are stored as they are, they're accepted until they
+/// expire. Only the exact legacy format is passed through so a stored key
+/// can't be used as a token.
+pub fn storage_key(token: &str) -> Option<String> {
+    match token.split_once('.') {
+        Some((version @ (TOKEN_VERSION | API_KEY_VERSION), _)) => {
+            let hash = mac(token).finalize().into_bytes();
+            Some(format!("{}:{}", version, base64::encode(hash)))
+        },
+        None if is_legacy_token(token) => Some(token.to_string()),
+        _ => None,
+    }
+}
+
+/// Signs the value with `TOKEN_SECRET`, returning the URL safe base64
+/// encoded HMAC-SHA256.
+pub fn sign(value: &str) -> String {
+    base64::encode_config(mac(value).finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks the signature was made by `sign` for the value.
+pub fn verify(value: &str, signature: &str) -> bool {
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Err(_) => false,
+        Ok(signature) => mac(value).verify_slice(&signature).is_ok(),
+    }
+}
+
+/// Encrypts a secret for storing, using AES-256-GCM with a random nonce.
+///
+/// The result is the nonce followed by the ciphertext, base64 encoded and
+/// prefixed with the scheme version and a `:`.
+pub fn encrypt(secret: &str) -> Result<String> {
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    let ciphertext = ENCRYPTION_KEY.encrypt(Nonce::from_slice(&nonce), secret.as_bytes())
+        .map_err(|_| anyhow!("failed to encrypt secret"))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+
+    Ok(format!("{}:{}", ENCRYPTION_VERSION, base64::encode(sealed)))
+}
+
+/// Decrypts a secret encrypted by `encrypt`.
+pub fn decrypt(stored: &str) -> Result<String> {
+    let sealed = match stored.split_once(':') {
+        Some((ENCRYPTION_VERSION, sealed)) => base64::decode(sealed)?,
+        _ => return Err(anyhow!("unknown encryption scheme")),
+    };
+
+    if sealed.len() < NONCE_LENGTH {
+        return Err(anyhow!("encrypted secret is too short"))
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let secret = ENCRYPTION_KEY.decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("failed to decrypt secret"))?;
+
+    Ok(String::from_utf8(secret)?)
+}
+
+
+fn is_legacy_token(token: &str) -> bool {
+    (token.len() == TOKEN_LENGTH) & token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn mac(value: &str) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(TOKEN_SECRET.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(value.as_bytes());
+    mac
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every test uses the same secret so it doesn't matter which one
+    /// initialises the keys derived from it.
+    fn set_secret() {
+        std::env::set_var("TOKEN_SECRET", "test-secret");
+    }
+
+    #[test]
+    fn legacy_tokens_are_stored_as_they_are() {
+        set_secret();
+
+        let token = generate_token(TOKEN_LENGTH);
+        assert_eq!(storage_key(&token), Some(token));
+    }
+
+    #[test]
+    fn unversioned_tokens_are_rejected() {
+        set_secret();
+
+        assert_eq!(storage_key(&generate_token(TOKEN_LENGTH - 1)), None);
+        assert_eq!(storage_key(&format!("{}!", generate_token(TOKEN_LENGTH - 1))), None);
+        assert_eq!(storage_key(&format!("v2.{}", generate_token(TOKEN_LENGTH))), None);
+
+        // A stored key can't be used as a token.
+        let stored = storage_key(&issue_token()).unwrap();
+        assert_eq!(storage_key(&stored), None);
+    }
+
+    #[test]
+    fn access_tokens_and_api_keys_are_prefixed() {
+        set_secret();
+
+        let token = issue_token();
+        assert!(token.starts_with("v1."));
+        assert!(!is_api_key(&token));
+        assert!(storage_key(&token).unwrap().starts_with("v1:"));
+        assert_eq!(storage_key(&token), storage_key(&token));
+        assert_ne!(storage_key(&token), storage_key(&issue_token()));
+
+        let key = issue_api_key();
+        assert!(key.starts_with("k1."));
+        assert!(is_api_key(&key));
+        assert!(storage_key(&key).unwrap().starts_with("k1:"));
+    }
+
+    #[test]
+    fn encrypted_secrets_round_trip() {
+        set_secret();
+
+        let stored = encrypt("refresh-token").unwrap();
+        assert!(stored.starts_with("v1:"));
+        assert_ne!(stored, encrypt("refresh-token").unwrap());
+        assert_eq!(decrypt(&stored).unwrap(), "refresh-token");
+    }
+
+    #[test]
+    fn tampered_secrets_fail_to_decrypt() {
+        set_secret();
+
+        let stored = encrypt("refresh-token").unwrap();
+        let mut sealed = base64::decode(stored.split_once(':').unwrap().1).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+
+        assert!(decrypt(&format!("v1:{}", base64::encode(&sealed))).is_err());
+        assert!(decrypt(&format!("v1:{}", base64::encode(&sealed[..NONCE_LENGTH - 1]))).is_err());
+        assert!(decrypt(&stored.replacen("v1:", "v2:", 1)).is_err());
+    }
+}
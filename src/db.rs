@@ -5,8 +5,22 @@ use std::sync::Arc;
 use scylla::{QueryResult, SessionBuilder};
 use scylla::frame::value::ValueList;
 use scylla::prepared_statement::PreparedStatement;
+use scylla::query::Query;
+use scylla::transport::iterator::RowIterator;
 use concread::arcache::{ARCache, ARCacheBuilder};
 
+/// Columns added to existing tables as `(table, column, type)`, see
+/// `add_missing_columns`.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("user_vote_credits", "applied_entries", "map<timeuuid, int>"),
+    ("playlists", "item_order", "list<uuid>"),
+];
+
+/// The most values passed to a single `IN ?` restriction, larger lists
+/// are split across multiple queries.
+pub const MAX_IN_VALUES: usize = 100;
+
+
 #[derive(Clone)]
 pub struct Session(Arc<scylla::Session>, Arc<ARCache<String, PreppedStmt>>);
 
@@ -92,6 +106,29 @@ impl Session {
 
         result
     }
+
+    /// Executes a query whose rows are fetched `page_size` at a time as
+    /// they're read, for queries which can return more rows than should be
+    /// held in memory at once.
+    #[instrument(skip(self, query), level = "trace")]
+    pub async fn query_iter(
+        &self,
+        query: &str,
+        values: impl ValueList + Debug,
+        page_size: i32,
+    ) -> anyhow::Result<RowIterator> {
+        trace!("executing paged query {}", query);
+        let result = self.0
+            .query_iter(Query::new(query.to_string()).with_page_size(page_size), values)
+            .await
+            .map_err(anyhow::Error::from);
+
+        if let Err(ref e) = result {
+            error!("failed to execute paged query: {} due to error: {}", query, e);
+        }
+
+        result
+    }
 }
 
 #[derive(Clone)]
@@ -118,6 +155,21 @@ impl Deref for PreppedStmt{
 }
 
 
+/// Checks if a lightweight transaction was applied.
+///
+/// The first column of a conditional statement's result is always the
+/// `[applied]` flag.
+pub fn was_applied(result: &QueryResult) -> bool {
+    result.rows
+        .as_ref()
+        .and_then(|rows| rows.first())
+        .and_then(|row| row.columns.first())
+        .and_then(|v| v.as_ref())
+        .and_then(|v| v.as_boolean())
+        .unwrap_or(false)
+}
+
+
 pub async fn connect(node: &str) -> anyhow::Result<Session> {
     let session = SessionBuilder::new()
         .known_node(node)
@@ -128,6 +180,7 @@ pub async fn connect(node: &str) -> anyhow::Result<Session> {
     session.use_keyspace("spooderfy", false).await?;
 
     create_tables(&session).await?;
+    add_missing_columns(&session).await?;
 
     Ok(Session::from(session))
 }
@@ -144,4 +197,27 @@ async fn create_tables(session: &scylla::Session) -> anyhow::Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Adds columns which were added to tables after they were first created,
+/// `CREATE TABLE IF NOT EXISTS` leaves existing tables as they are.
+async fn add_missing_columns(session: &scylla::Session) -> anyhow::Result<()> {
+    for (table, column, type_) in ADDED_COLUMNS {
+        let result = session.query(
+            r#"
+            SELECT column_name FROM system_schema.columns
+            WHERE keyspace_name = 'spooderfy' AND table_name = ? AND column_name = ?;
+            "#,
+            (*table, *column)
+        ).await?;
+
+        if result.rows.map(|rows| !rows.is_empty()).unwrap_or(false) {
+            continue;
+        }
+
+        info!("adding column {} to {}", column, table);
+        session.query(format!("ALTER TABLE {} ADD {} {};", table, column, type_), &[]).await?;
+    }
+
+    Ok(())
+}
@@ -5,6 +5,7 @@ extern crate tracing;
 extern crate lazy_static;
 
 
+mod admin;
 mod users;
 mod db;
 mod auth;
@@ -13,17 +14,17 @@ mod utils;
 mod rooms;
 mod playlists;
 mod images;
+mod migrations;
 mod rtc;
 
 use std::sync::Arc;
 use std::time::Duration;
-use poem::{Endpoint, EndpointExt, IntoResponse, Request, Response, Result, Route, Server};
+use poem::{get, Endpoint, EndpointExt, IntoResponse, Request, Response, Result, Route, Server};
 use poem::listener::TcpListener;
 use poem::http::Method;
 use poem_openapi::{OpenApiService, Tags};
 
-use concread::arcache::{ARCache, ARCacheBuilder};
-use poem::middleware::Cors;
+use poem::middleware::{CookieJarManager, Cors};
 use tokio::time::Instant;
 
 #[derive(Tags)]
@@ -34,6 +35,7 @@ pub enum ApiTags {
     Rooms,
     Playlists,
     Rtc,
+    Admin,
 }
 
 #[tokio::main]
@@ -42,12 +44,21 @@ async fn main() -> anyhow::Result<()> {
         std::env::set_var("RUST_LOG", "info,poem=debug,scylla=info");
     }
     tracing_subscriber::fmt::init();
+    auth::check_secret()?;
 
     let session = db::connect("127.0.0.1:9042").await?;
-    let cache: ARCache<String, String> = ARCacheBuilder::new()
-        .set_size(1024, 10)
-        .build()
-        .unwrap();
+
+    // `backenda migrate` copies data out of old tables and exits, see
+    // `migrations::run`.
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        return migrations::run(&session).await
+    }
+
+    notifications::scheduled::start_delivery(session.clone());
+    notifications::broadcasts::start_resumer(session.clone());
+    auth::refresh::start_refresher(session.clone());
+
+    let cache = auth::identity::UserCache::new();
 
     let api_service = OpenApiService::new(
         (
@@ -56,6 +67,8 @@ async fn main() -> anyhow::Result<()> {
             notifications::NotificationsApi,
             rooms::RoomsApi,
             playlists::PlaylistsApi,
+            rtc::RtcApi,
+            admin::AdminApi,
         ),
         "Spooderfy API",
         "1.0.0"
@@ -66,16 +79,24 @@ async fn main() -> anyhow::Result<()> {
     let ui = api_service.redoc();
     let spec = api_service.spec();
 
-    let app = Route::new()
+    let mut app = Route::new()
         .nest("/api/v0", api_service)
         .nest("/ui", ui)
-        .at("/spec", poem::endpoint::make_sync(move |_| spec.clone()))
+        .at("/spec", poem::endpoint::make_sync(move |_| spec.clone()));
+
+    if rtc::sink::gateway_enabled() {
+        info!("serving the built in event gateway on /gateway");
+        app = app.at("/gateway", get(rtc::gateway::connect));
+    }
+
+    let app = app
         .with(
             Cors::new()
                 .allow_origins(["http://127.0.0.1:3000", "http://localhost:3000"])
                 .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PUT, Method::OPTIONS])
                 .allow_credentials(true)
         )
+        .with(CookieJarManager::new())
         .around(log)
         .data(session)
         .data(Arc::new(cache));
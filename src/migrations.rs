@@ -0,0 +1,300 @@
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use futures_util::StreamExt;
+use scylla::IntoTypedRows;
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::frame::value::ValueList;
+use uuid::Uuid;
+
+use crate::admin::audit::{self, AuditAction, TargetKind};
+use crate::db::Session;
+use crate::rooms::{browse, get_room_by_id};
+use crate::utils::{time_uuid_from, Timestamp};
+
+
+/// How many rows of an old table are read at once.
+const MIGRATION_PAGE_SIZE: i32 = 500;
+
+/// The columns the old `rooms` table had by the time it was replaced,
+/// databases created by older versions only have some of them.
+const LEGACY_ROOM_COLUMNS: &[&str] = &[
+    "id",
+    "owner_id",
+    "active_playlist",
+    "banner",
+    "created_on",
+    "guild_id",
+    "guild_visibility",
+    "invite_only",
+    "is_public",
+    "playing_now",
+    "title",
+    "topic",
+    "viewers",
+];
+
+
+/// Copies data out of tables used by older versions of the schema into
+/// the tables which replaced them, and fills in tables added alongside
+/// existing ones.
+///
+/// This is run once by hand with `backenda migrate` after upgrading
+/// rather than on every startup. No step changes rows which are already
+/// there so an interrupted run can simply be repeated. The old
+/// tables are left in place to be dropped once the copy is checked.
+pub async fn run(sess: &Session) -> Result<()> {
+    migrate_legacy_rooms(sess).await?;
+    migrate_legacy_notifications(sess).await?;
+    backfill_audit_lookups(sess).await?;
+
+    info!("migrations finished, the old tables can now be dropped");
+
+    Ok(())
+}
+
+
+/// Copies rooms out of the old `rooms` table, which was keyed by both the
+/// room and its owner so the owner couldn't be changed, into
+/// `active_rooms` and the browse listings.
+///
+/// Rooms without a stored creation time are given the time their title
+/// was written.
+async fn migrate_legacy_rooms(sess: &Session) -> Result<()> {
+    let columns = get_columns(sess, "rooms", LEGACY_ROOM_COLUMNS).await?;
+    if columns.is_empty() {
+        return Ok(())
+    }
+
+    let query = format!("SELECT {}, WRITETIME(title) FROM rooms;", columns.join(", "));
+    let mut rows = sess.query_iter(&query, &[], MIGRATION_PAGE_SIZE).await?;
+
+    let mut migrated = 0;
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let get = |name: &str| column(&columns, &row, name);
+
+        let id = get("id")
+            .and_then(|v| v.as_uuid())
+            .ok_or_else(|| anyhow!("legacy room has no id"))?;
+        let owner_id = get("owner_id")
+            .and_then(|v| v.as_bigint())
+            .ok_or_else(|| anyhow!("legacy room {} has no owner", id))?;
+
+        let written_at = row.columns.last()
+            .and_then(|v| v.as_ref())
+            .and_then(|v| v.as_bigint())
+            .map(|micros| Timestamp(micros / 1000));
+        let created_on = get("created_on")
+            .and_then(|v| v.as_duration())
+            .map(|v| Timestamp(v.num_milliseconds()))
+            .or(written_at)
+            .unwrap_or_else(Timestamp::now);
+
+        // There are more values than `query_prepared` can log as a tuple.
+        let values = (
+            id,
+            owner_id,
+            get("active_playlist").and_then(|v| v.as_uuid()),
+            get("banner").and_then(|v| v.as_text()),
+            created_on,
+            get("guild_id").and_then(|v| v.as_bigint()),
+            get("guild_visibility").and_then(|v| v.as_text()),
+            get("invite_only").and_then(|v| v.as_boolean()).unwrap_or(false),
+            get("is_public").and_then(|v| v.as_boolean()).unwrap_or(false),
+            get("playing_now").and_then(|v| v.as_uuid()),
+            get("title").and_then(|v| v.as_text()).cloned().unwrap_or_default(),
+            get("topic").and_then(|v| v.as_text()),
+            get("viewers").and_then(|v| v.as_int()).unwrap_or(0),
+        ).serialized()?.into_owned();
+
+        sess.query_prepared(
+            r#"
+            INSERT INTO active_rooms (
+                id,
+                owner_id,
+                active_playlist,
+                banner,
+                created_on,
+                guild_id,
+                guild_visibility,
+                invite_only,
+                is_public,
+                playback_paused,
+                playback_position,
+                playback_rate,
+                playing_now,
+                title,
+                topic,
+                viewers
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, true, 0, 1.0, ?, ?, ?, ?)
+            IF NOT EXISTS;
+            "#,
+            values
+        ).await?;
+
+        // The room may have been copied by an earlier run which stopped
+        // before listing it, so it's listed from what's now stored.
+        if let Some(room) = get_room_by_id(sess, id).await? {
+            if room.is_listed() {
+                browse::add_room(sess, &room).await?;
+            }
+        }
+
+        migrated += 1;
+    }
+
+    info!("migrated {} rooms to active_rooms", migrated);
+
+    Ok(())
+}
+
+/// Copies notifications out of the old `notifications` table, which was
+/// keyed by the notification rather than its recipient, into
+/// `user_notifications`.
+///
+/// Old notifications are given time based ids derived from their old id
+/// and when they were created, so a repeated run finds the copies it
+/// already made. They start out unread.
+async fn migrate_legacy_notifications(sess: &Session) -> Result<()> {
+    let columns = get_columns(sess, "notifications", &["id"]).await?;
+    if columns.is_empty() {
+        return Ok(())
+    }
+
+    let rows = sess.query_iter(
+        "SELECT id, recipient_id, title, description, created_on, icon FROM notifications;",
+        &[],
+        MIGRATION_PAGE_SIZE,
+    ).await?;
+
+    type LegacyNotification = (Uuid, i64, Option<String>, Option<String>, Timestamp, Option<String>);
+    let mut notifications = rows.into_typed::<LegacyNotification>();
+
+    let mut migrated = 0;
+    while let Some(notification) = notifications.next().await {
+        let (legacy_id, recipient_id, title, description, created_on, icon) = notification?;
+        let id = time_uuid_from(created_on, legacy_id);
+
+        sess.query_prepared(
+            r#"
+            INSERT INTO user_notifications (
+                recipient_id,
+                id,
+                title,
+                description,
+                created_on,
+                icon,
+                read
+            ) VALUES (?, ?, ?, ?, ?, ?, false)
+            IF NOT EXISTS;
+            "#,
+            (recipient_id, id, title.unwrap_or_default(), description, created_on, icon)
+        ).await?;
+
+        // The copy may have been read since an earlier run made it.
+        let result = sess.query_prepared(
+            "SELECT read FROM user_notifications WHERE recipient_id = ? AND id = ?;",
+            (recipient_id, id)
+        ).await?;
+
+        let rows = result.rows
+            .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+        let is_unread = rows.into_typed::<(Option<bool>,)>()
+            .next()
+            .transpose()?
+            .map(|v| !v.0.unwrap_or(false))
+            .unwrap_or(false);
+
+        if is_unread {
+            sess.query_prepared(
+                "INSERT INTO unread_notifications (recipient_id, id) VALUES (?, ?);",
+                (recipient_id, id)
+            ).await?;
+        }
+
+        migrated += 1;
+    }
+
+    info!("migrated {} notifications to user_notifications", migrated);
+
+    Ok(())
+}
+
+/// Adds audit log entries written before the lookup tables by actor and
+/// by target existed to those tables.
+///
+/// Entries already in the lookup tables are simply written again.
+async fn backfill_audit_lookups(sess: &Session) -> Result<()> {
+    let rows = sess.query_iter(
+        "SELECT id, actor_id, action, target_kind, target_id, before, after, created_at FROM audit_log;",
+        &[],
+        MIGRATION_PAGE_SIZE,
+    ).await?;
+
+    type AuditInfo = (
+        Uuid,
+        Option<i64>,
+        String,
+        String,
+        String,
+        Option<String>,
+        Option<String>,
+        Timestamp,
+    );
+    let mut entries = rows.into_typed::<AuditInfo>();
+
+    let mut backfilled = 0;
+    while let Some(entry) = entries.next().await {
+        let v = entry?;
+
+        audit::insert_lookups(
+            sess,
+            v.0,
+            v.1,
+            AuditAction::from_str(&v.2)?,
+            TargetKind::from_str(&v.3)?,
+            &v.4,
+            v.5,
+            v.6,
+            v.7,
+        ).await?;
+
+        backfilled += 1;
+    }
+
+    info!("added {} audit log entries to the lookup tables", backfilled);
+
+    Ok(())
+}
+
+
+/// Gets which of the given columns the table has, none if the table
+/// doesn't exist.
+async fn get_columns(sess: &Session, table: &str, columns: &[&'static str]) -> Result<Vec<&'static str>> {
+    let result = sess.query_prepared(
+        "SELECT column_name FROM system_schema.columns WHERE keyspace_name = 'spooderfy' AND table_name = ?;",
+        (table,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let existing: Vec<String> = rows.into_typed::<(String,)>()
+        .filter_map(|v| v.ok())
+        .map(|v| v.0)
+        .collect();
+
+    let columns = columns.iter()
+        .filter(|v| existing.iter().any(|existing| existing == *v))
+        .copied()
+        .collect();
+
+    Ok(columns)
+}
+
+/// Gets the value of the named column from a row selected with `columns`.
+fn column<'a>(columns: &[&str], row: &'a Row, name: &str) -> Option<&'a CqlValue> {
+    let index = columns.iter().position(|v| *v == name)?;
+    row.columns.get(index)?.as_ref()
+}
@@ -0,0 +1,645 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures_util::StreamExt;
+use poem_openapi::{Enum, Object};
+use scylla::IntoTypedRows;
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+use crate::db::{self, Session};
+use crate::users::notifications::{insert_notification, parse_action, Delivery, Icons, NotificationContent};
+use crate::utils::{JsSafeBigInt, Timestamp};
+
+
+/// How often broadcasts are checked for ones which stopped running, e.g.
+/// because the instance sending them was restarted.
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a running broadcast can go without saving progress before
+/// another instance takes it over, in milliseconds.
+const STALLED_AFTER_MILLIS: i64 = 300_000;
+
+/// How many users are read at once when resolving recipients.
+const RECIPIENT_PAGE_SIZE: i32 = 1000;
+
+
+/// Which users a broadcast is sent to.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum Segment {
+    /// Every registered user.
+    All,
+
+    /// Every user who is a member of the given guild.
+    Guild,
+
+    /// An explicit list of users.
+    Users,
+}
+
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum BroadcastStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Object)]
+pub struct Broadcast {
+    pub id: Uuid,
+    pub idempotency_key: String,
+    pub segment: Segment,
+    pub guild_id: Option<JsSafeBigInt>,
+    pub title: String,
+    pub status: BroadcastStatus,
+
+    /// How many users the broadcast is being sent to, not set until the
+    /// recipients have been resolved.
+    pub total: Option<i32>,
+
+    /// How many notifications have been sent so far.
+    pub sent: i32,
+
+    /// How many notifications could not be sent.
+    pub failed: i32,
+
+    /// How many users were skipped because they muted the category.
+    pub suppressed: i32,
+    pub created_on: Timestamp,
+    pub finished_on: Option<Timestamp>,
+}
+
+
+/// A broadcast being sent along with how far it has got.
+struct BroadcastJob {
+    id: Uuid,
+    segment: Segment,
+    guild_id: Option<i64>,
+    user_ids: Vec<i64>,
+    content: NotificationContent,
+    progress: Progress,
+
+    /// When progress was last saved, the broadcast is only sent by
+    /// whoever last saved progress, see `save_progress`.
+    heartbeat_on: Timestamp,
+}
+
+#[derive(Default)]
+struct Progress {
+    /// Recipients are sent to in order of their id, this is the last one
+    /// which was sent to.
+    last_recipient_id: Option<i64>,
+    sent: i32,
+    failed: i32,
+    suppressed: i32,
+}
+
+/// The outcome of trying to start a broadcast.
+pub enum Started {
+    /// A new broadcast was started.
+    New(Broadcast),
+
+    /// A broadcast was already started with the same idempotency key.
+    Existing(Broadcast),
+
+    /// The idempotency key was already used but its broadcast can no
+    /// longer be found.
+    Conflict,
+}
+
+
+/// Starts sending the broadcast in the background.
+///
+/// The broadcast is stored before its idempotency key is claimed with a
+/// lightweight transaction, so a claimed key always points at a stored
+/// broadcast. If the key has already been used the new broadcast is
+/// removed and the existing one is returned instead. Keys are kept for a
+/// week.
+///
+/// Everything needed to send the broadcast is stored with it so it can
+/// be resumed by `start_resumer` if it stops part way.
+pub async fn start_broadcast(
+    sess: &Session,
+    idempotency_key: String,
+    segment: Segment,
+    guild_id: Option<i64>,
+    user_ids: Vec<i64>,
+    content: NotificationContent,
+) -> Result<Started> {
+    let id = Uuid::new_v4();
+
+    let broadcast = Broadcast {
+        id,
+        idempotency_key,
+        segment,
+        guild_id: guild_id.map(JsSafeBigInt),
+        title: content.title.clone(),
+        status: BroadcastStatus::Running,
+        total: None,
+        sent: 0,
+        failed: 0,
+        suppressed: 0,
+        created_on: Timestamp::now(),
+        finished_on: None,
+    };
+
+    let heartbeat_on = Timestamp::now();
+    sess.query_prepared(
+        r#"
+        INSERT INTO notification_broadcasts (
+            id,
+            idempotency_key,
+            segment,
+            guild_id,
+            title,
+            status,
+            sent,
+            failed,
+            suppressed,
+            created_on,
+            heartbeat_on
+        ) VALUES (?, ?, ?, ?, ?, ?, 0, 0, 0, ?, ?);
+        "#,
+        (
+            broadcast.id,
+            &broadcast.idempotency_key,
+            broadcast.segment.to_string(),
+            guild_id,
+            &broadcast.title,
+            broadcast.status.to_string(),
+            broadcast.created_on,
+            heartbeat_on,
+        )
+    ).await?;
+
+    sess.query_prepared(
+        r#"
+        UPDATE notification_broadcasts
+        SET description = ?, icon = ?, action_kind = ?, action_target = ?, expires_at = ?, user_ids = ?
+        WHERE id = ?;
+        "#,
+        (
+            &content.description,
+            content.icon.map(|v| v.to_string()),
+            content.action.as_ref().map(|v| v.kind.to_string()),
+            content.action.as_ref().map(|v| v.target.clone()),
+            content.expires_at,
+            &user_ids,
+            broadcast.id,
+        )
+    ).await?;
+
+    let result = sess.query_prepared(
+        "INSERT INTO notification_broadcast_keys (idempotency_key, broadcast_id) VALUES (?, ?) IF NOT EXISTS;",
+        (&broadcast.idempotency_key, id)
+    ).await?;
+
+    if !db::was_applied(&result) {
+        sess.query_prepared(
+            "DELETE FROM notification_broadcasts WHERE id = ?;",
+            (id,)
+        ).await?;
+
+        let existing_id = match get_broadcast_id_for_key(sess, &broadcast.idempotency_key).await? {
+            None => return Ok(Started::Conflict),
+            Some(existing_id) => existing_id,
+        };
+
+        return match get_broadcast(sess, existing_id).await? {
+            None => Ok(Started::Conflict),
+            Some(broadcast) => Ok(Started::Existing(broadcast)),
+        }
+    }
+
+    sess.query_prepared(
+        "INSERT INTO running_broadcasts (bucket, id) VALUES (0, ?);",
+        (id,)
+    ).await?;
+
+    spawn_broadcast(sess.clone(), BroadcastJob {
+        id,
+        segment,
+        guild_id,
+        user_ids,
+        content,
+        progress: Progress::default(),
+        heartbeat_on,
+    });
+
+    Ok(Started::New(broadcast))
+}
+
+/// Gets the broadcast with the given id.
+pub async fn get_broadcast(sess: &Session, id: Uuid) -> Result<Option<Broadcast>> {
+    let result = sess.query_prepared(
+        r#"
+        SELECT
+            id,
+            idempotency_key,
+            segment,
+            guild_id,
+            title,
+            status,
+            total,
+            sent,
+            failed,
+            suppressed,
+            created_on,
+            finished_on
+        FROM notification_broadcasts
+        WHERE id = ?;
+        "#,
+        (id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    type BroadcastInfo = (
+        Uuid,
+        String,
+        String,
+        Option<JsSafeBigInt>,
+        String,
+        String,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Timestamp,
+        Option<Timestamp>,
+    );
+
+    let v = match rows.into_typed::<BroadcastInfo>().next() {
+        None => return Ok(None),
+        Some(v) => v?,
+    };
+
+    Ok(Some(Broadcast {
+        id: v.0,
+        idempotency_key: v.1,
+        segment: Segment::from_str(&v.2)?,
+        guild_id: v.3,
+        title: v.4,
+        status: BroadcastStatus::from_str(&v.5)?,
+        total: v.6,
+        sent: v.7.unwrap_or(0),
+        failed: v.8.unwrap_or(0),
+        suppressed: v.9.unwrap_or(0),
+        created_on: v.10,
+        finished_on: v.11,
+    }))
+}
+
+/// Starts resuming broadcasts which stopped part way in the background.
+///
+/// A running broadcast which hasn't saved progress in
+/// `STALLED_AFTER_MILLIS` is claimed with a lightweight transaction and
+/// picks up after the last recipient it was sent to.
+pub fn start_resumer(sess: Session) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RESUME_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = resume_stalled(&sess).await {
+                error!("failed to resume stalled broadcasts: {}", e);
+            }
+        }
+    });
+}
+
+
+async fn get_broadcast_id_for_key(sess: &Session, idempotency_key: &str) -> Result<Option<Uuid>> {
+    let result = sess.query_prepared(
+        "SELECT broadcast_id FROM notification_broadcast_keys WHERE idempotency_key = ?;",
+        (idempotency_key.to_string(),)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let id = match rows.into_typed::<(Uuid,)>().next() {
+        None => None,
+        Some(v) => Some(v?.0),
+    };
+
+    Ok(id)
+}
+
+/// Sends the broadcast in the background, marking it as finished once
+/// it's done unless another instance took it over.
+fn spawn_broadcast(sess: Session, mut job: BroadcastJob) {
+    tokio::spawn(async move {
+        let id = job.id;
+        let status = match run_broadcast(&sess, &mut job).await {
+            Ok(true) => BroadcastStatus::Completed,
+            Ok(false) => {
+                warn!("broadcast {} was taken over by another instance", id);
+                return
+            },
+            Err(e) => {
+                error!("broadcast {} failed: {}", id, e);
+                BroadcastStatus::Failed
+            },
+        };
+
+        if let Err(e) = finish_broadcast(&sess, id, status).await {
+            error!("failed to mark broadcast {} as {}: {}", id, status, e);
+        }
+    });
+}
+
+async fn finish_broadcast(sess: &Session, id: Uuid, status: BroadcastStatus) -> Result<()> {
+    sess.query_prepared(
+        "UPDATE notification_broadcasts SET status = ?, finished_on = ? WHERE id = ?;",
+        (status.to_string(), Timestamp::now(), id)
+    ).await?;
+
+    sess.query_prepared(
+        "DELETE FROM running_broadcasts WHERE bucket = 0 AND id = ?;",
+        (id,)
+    ).await?;
+
+    Ok(())
+}
+
+/// Sends the broadcast to every recipient after the last one it was sent
+/// to, saving progress after each.
+///
+/// Recipients who can't be sent a notification are counted as failed
+/// rather than stopping the broadcast, those who muted the category are
+/// counted as suppressed. Returns `false` if another instance took the
+/// broadcast over.
+async fn run_broadcast(sess: &Session, job: &mut BroadcastJob) -> Result<bool> {
+    let recipients = resolve_recipients(sess, job.segment, job.guild_id, &job.user_ids).await?;
+
+    sess.query_prepared(
+        "UPDATE notification_broadcasts SET total = ? WHERE id = ?;",
+        (recipients.len() as i32, job.id)
+    ).await?;
+
+    let resume_after = job.progress.last_recipient_id;
+    let remaining = recipients.into_iter()
+        .filter(|v| resume_after.map(|last| *v > last).unwrap_or(true));
+
+    for recipient_id in remaining {
+        let result = insert_notification(sess, recipient_id, job.content.clone()).await;
+
+        match result {
+            Ok(Delivery::Suppressed) => job.progress.suppressed += 1,
+            Ok(_) => job.progress.sent += 1,
+            Err(e) => {
+                warn!("broadcast {} failed to notify user {}: {}", job.id, recipient_id, e);
+                job.progress.failed += 1;
+            },
+        }
+
+        job.progress.last_recipient_id = Some(recipient_id);
+        if !save_progress(sess, job).await? {
+            return Ok(false)
+        }
+    }
+
+    Ok(true)
+}
+
+/// Saves the broadcast's progress, returning `false` if another instance
+/// has taken it over since it was last saved.
+async fn save_progress(sess: &Session, job: &mut BroadcastJob) -> Result<bool> {
+    let heartbeat_on = Timestamp::now();
+
+    let result = sess.query_prepared(
+        r#"
+        UPDATE notification_broadcasts
+        SET sent = ?, failed = ?, suppressed = ?, last_recipient_id = ?, heartbeat_on = ?
+        WHERE id = ?
+        IF heartbeat_on = ?;
+        "#,
+        (
+            job.progress.sent,
+            job.progress.failed,
+            job.progress.suppressed,
+            job.progress.last_recipient_id,
+            heartbeat_on,
+            job.id,
+            job.heartbeat_on,
+        )
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return Ok(false)
+    }
+
+    job.heartbeat_on = heartbeat_on;
+    Ok(true)
+}
+
+/// Claims and resumes every running broadcast which has stalled.
+async fn resume_stalled(sess: &Session) -> Result<()> {
+    let result = sess.query_prepared(
+        "SELECT id FROM running_broadcasts WHERE bucket = 0;",
+        &[]
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let ids: Vec<Uuid> = rows.into_typed::<(Uuid,)>()
+        .filter_map(|v| v.ok())
+        .map(|v| v.0)
+        .collect();
+
+    for id in ids {
+        let mut job = match get_stalled_job(sess, id).await? {
+            None => continue,
+            Some(job) => job,
+        };
+
+        // Claiming is the same as saving progress, whoever saves first
+        // carries on sending.
+        if save_progress(sess, &mut job).await? {
+            info!("resuming stalled broadcast {}", id);
+            spawn_broadcast(sess.clone(), job);
+        }
+    }
+
+    Ok(())
+}
+
+/// Gets the broadcast with the given id if it's running but hasn't saved
+/// progress in `STALLED_AFTER_MILLIS`.
+async fn get_stalled_job(sess: &Session, id: Uuid) -> Result<Option<BroadcastJob>> {
+    let result = sess.query_prepared(
+        r#"
+        SELECT
+            segment,
+            guild_id,
+            title,
+            status,
+            sent,
+            failed,
+            suppressed,
+            description,
+            icon,
+            action_kind,
+            action_target,
+            expires_at,
+            user_ids,
+            last_recipient_id,
+            heartbeat_on
+        FROM notification_broadcasts
+        WHERE id = ?;
+        "#,
+        (id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    type JobInfo = (
+        String,
+        Option<i64>,
+        String,
+        String,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<Timestamp>,
+        Option<Vec<i64>>,
+        Option<i64>,
+        Option<Timestamp>,
+    );
+
+    let v = match rows.into_typed::<JobInfo>().next() {
+        None => return Ok(None),
+        Some(v) => v?,
+    };
+
+    if BroadcastStatus::from_str(&v.3)? != BroadcastStatus::Running {
+        sess.query_prepared(
+            "DELETE FROM running_broadcasts WHERE bucket = 0 AND id = ?;",
+            (id,)
+        ).await?;
+
+        return Ok(None)
+    }
+
+    // Broadcasts started before progress was saved can't be resumed.
+    let heartbeat_on = match v.14 {
+        None => {
+            finish_broadcast(sess, id, BroadcastStatus::Failed).await?;
+            return Ok(None)
+        },
+        Some(heartbeat_on) => heartbeat_on,
+    };
+
+    if *Timestamp::now() - *heartbeat_on < STALLED_AFTER_MILLIS {
+        return Ok(None)
+    }
+
+    Ok(Some(BroadcastJob {
+        id,
+        segment: Segment::from_str(&v.0)?,
+        guild_id: v.1,
+        user_ids: v.12.unwrap_or_default(),
+        content: NotificationContent {
+            title: v.2,
+            description: v.7,
+            icon: v.8.and_then(|v| Icons::from_str(&v).ok()),
+            action: parse_action(v.9, v.10),
+            expires_at: v.11,
+        },
+        progress: Progress {
+            last_recipient_id: v.13,
+            sent: v.4.unwrap_or(0),
+            failed: v.5.unwrap_or(0),
+            suppressed: v.6.unwrap_or(0),
+        },
+        heartbeat_on,
+    }))
+}
+
+/// Gets the ids of every existing user in the segment.
+///
+/// The `all` and `guild` segments scan the whole users table a page at a
+/// time.
+async fn resolve_recipients(
+    sess: &Session,
+    segment: Segment,
+    guild_id: Option<i64>,
+    user_ids: &[i64],
+) -> Result<Vec<i64>> {
+    let recipients: BTreeSet<i64> = match segment {
+        Segment::All => {
+            let rows = sess.query_iter("SELECT id FROM users;", &[], RECIPIENT_PAGE_SIZE).await?;
+            let mut users = rows.into_typed::<(i64,)>();
+
+            let mut recipients = BTreeSet::new();
+            while let Some(v) = users.next().await {
+                recipients.insert(v?.0);
+            }
+
+            recipients
+        },
+        Segment::Guild => {
+            let guild_id = guild_id
+                .ok_or_else(|| anyhow!("guild broadcasts require a guild id"))?;
+
+            let rows = sess.query_iter(
+                "SELECT id, access_servers FROM users;",
+                &[],
+                RECIPIENT_PAGE_SIZE,
+            ).await?;
+            let mut users = rows.into_typed::<(i64, Option<HashMap<i64, bool>>)>();
+
+            let mut recipients = BTreeSet::new();
+            while let Some(v) = users.next().await {
+                let (user_id, servers) = v?;
+
+                let is_member = servers.as_ref()
+                    .map(|v| v.contains_key(&guild_id))
+                    .unwrap_or(false);
+
+                if is_member {
+                    recipients.insert(user_id);
+                }
+            }
+
+            recipients
+        },
+        Segment::Users => {
+            let mut existing = BTreeSet::new();
+            for chunk in user_ids.chunks(db::MAX_IN_VALUES) {
+                let result = sess.query_prepared(
+                    "SELECT id FROM users WHERE id IN ?;",
+                    (chunk.to_vec(),)
+                ).await?;
+
+                let rows = result.rows
+                    .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+                existing.extend(
+                    rows.into_typed::<(i64,)>()
+                        .filter_map(|v| v.ok())
+                        .map(|v| v.0)
+                );
+            }
+
+            existing
+        },
+    };
+
+    Ok(recipients.into_iter().collect())
+}
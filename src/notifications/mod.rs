@@ -1,3 +1,7 @@
+pub mod broadcasts;
+pub mod scheduled;
+pub mod system;
+
 use anyhow::anyhow;
 use poem::web::Data;
 use poem::Result;
@@ -7,11 +11,25 @@ use poem_openapi::param::Query;
 use poem_openapi::types::ToJSON;
 use scylla::IntoTypedRows;
 use serde_json::{json, Value};
+use uuid::Uuid;
 
-use crate::utils::{JsonResponse, SuperUserBearer};
+use crate::utils::{JsonResponse, SuperUserBearer, Timestamp};
 use crate::ApiTags;
+use crate::admin::audit::{self, AuditAction, AuditRecord, TargetKind};
+use crate::admin::roles::Permission;
 use crate::db::Session;
-use crate::users::notifications::{get_user_notifications, Icons, Notification};
+use crate::notifications::broadcasts::{Broadcast, Segment, Started};
+use crate::users::preferences;
+use crate::users::notifications::{
+    self,
+    get_user_notifications,
+    insert_notification,
+    Delivery,
+    Icons,
+    NotificationAction,
+    NotificationContent,
+    NotificationPage,
+};
 
 
 
@@ -27,12 +45,57 @@ pub struct NotificationCreation {
     description: Option<String>,
 
     icon: Option<Icons>,
+
+    /// What opens when the notification is clicked.
+    action: Option<NotificationAction>,
+
+    /// When the notification is removed, kept until deleted if not set.
+    expires_at: Option<Timestamp>,
+
+    /// Hides the notification until this time if set.
+    deliver_at: Option<Timestamp>,
+}
+
+#[derive(Object)]
+pub struct BroadcastCreation {
+    /// A unique key for this broadcast, retrying a request with the same
+    /// key returns the original broadcast instead of sending it again.
+    #[oai(validator(max_length = 64, min_length = 8))]
+    idempotency_key: String,
+
+    segment: Segment,
+
+    /// The guild to broadcast to, required for the `guild` segment.
+    #[oai(validator(minimum(value = "0")))]
+    guild_id: Option<i64>,
+
+    /// The users to broadcast to, required for the `users` segment.
+    #[oai(validator(max_items = 1000))]
+    user_ids: Option<Vec<i64>>,
+
+    #[oai(validator(max_length = 32, min_length = 2))]
+    title: String,
+
+    #[oai(validator(max_length = 256))]
+    description: Option<String>,
+
+    icon: Option<Icons>,
+
+    /// What opens when the notification is clicked.
+    action: Option<NotificationAction>,
+
+    /// When the notifications are removed, kept until deleted if not set.
+    expires_at: Option<Timestamp>,
 }
 
 #[derive(Object)]
 pub struct Created {
     id: String,
     username: String,
+
+    /// If the notification wasn't created because the user muted its
+    /// category.
+    suppressed: bool,
 }
 
 #[derive(ApiResponse)]
@@ -40,11 +103,22 @@ pub enum NotificationResponse<T: Send + Sync + ToJSON> {
     #[oai(status = 200)]
     Ok(Json<T>),
 
+    #[oai(status = 400)]
+    BadRequest(Json<Value>),
+
+    #[oai(status = 403)]
+    Forbidden,
+
     #[oai(status = 422)]
     NotFound(Json<Value>),
 }
 
 
+fn default_page_size() -> u32 {
+    50
+}
+
+
 pub struct NotificationsApi;
 
 #[OpenApi]
@@ -52,13 +126,19 @@ impl NotificationsApi {
     /// Create Notification
     ///
     /// Creates a notification for a user.
+    ///
+    /// Nothing is created if the user has muted the notification's
+    /// category, this is reported with `suppressed`.
     #[oai(path = "/notifications", method = "post", tag = "ApiTags::Notifications")]
     pub async fn create_notification(
         &self,
-        _token: SuperUserBearer,
+        superuser: SuperUserBearer,
         session: Data<&Session>,
         payload: Json<NotificationCreation>,
     ) -> Result<NotificationResponse<Created>> {
+        if !superuser.0.has(Permission::ManageNotifications) {
+            return Ok(NotificationResponse::Forbidden)
+        }
 
         let result = session.query_prepared(
             "SELECT username FROM users WHERE id = ?",
@@ -77,41 +157,212 @@ impl NotificationsApi {
             Some(v) => v.map_err(anyhow::Error::from)?.0,
         };
 
-        session.query(
-            r#"
-            INSERT INTO notifications (
-                id,
-                recipient_id,
-                title,
-                description,
-                created_on,
-                icon
-            ) VALUES (uuid(), ?, ?, ?, toTimeStamp(now()), ?)"#,
-            (
-                payload.0.recipient_id,
-                payload.0.title,
-                payload.0.description,
-                payload.0.icon.map(|v| v.to_string()),
-                )
+        let problem = validate_content(
+            &session,
+            payload.0.action.as_ref(),
+            payload.0.expires_at,
+            payload.0.deliver_at,
         ).await?;
 
+        if let Some(detail) = problem {
+            return Ok(NotificationResponse::BadRequest(Json(json!({
+                "detail": detail,
+            }))))
+        }
+
+        let content = NotificationContent {
+            title: payload.0.title,
+            description: payload.0.description,
+            icon: payload.0.icon,
+            action: payload.0.action,
+            expires_at: payload.0.expires_at,
+        };
+
+        let title = content.title.clone();
+        let suppressed = match payload.0.deliver_at {
+            Some(_) if preferences::is_muted(&session, payload.0.recipient_id, content.icon).await? => true,
+            Some(deliver_at) if *deliver_at > *Timestamp::now() => {
+                notifications::schedule_notification(
+                    &session,
+                    payload.0.recipient_id,
+                    deliver_at,
+                    content,
+                ).await?;
+
+                false
+            },
+            _ => {
+                let delivery = insert_notification(&session, payload.0.recipient_id, content).await?;
+                delivery == Delivery::Suppressed
+            },
+        };
+
+        audit::record(&session, AuditRecord {
+            actor_id: superuser.0.user_id,
+            action: AuditAction::NotificationCreated,
+            target_kind: TargetKind::User,
+            target_id: payload.0.recipient_id.to_string(),
+            before: None,
+            after: Some(json!({
+                "title": title,
+                "deliver_at": payload.0.deliver_at.map(|v| *v),
+                "suppressed": suppressed,
+            })),
+        }).await;
+
         Ok(NotificationResponse::Ok(Json(Created {
             id: payload.0.recipient_id.to_string(),
-            username
+            username,
+            suppressed,
         })))
     }
 
     /// List Notifications
     ///
-    /// Creates a notification for a user.
+    /// Lists a page of a user's notifications, newest first.
     #[oai(path = "/notifications", method = "get", tag = "ApiTags::Notifications")]
     pub async fn get_notification(
         &self,
-        _token: SuperUserBearer,
+        superuser: SuperUserBearer,
         session: Data<&Session>,
         user_id: Query<i64>,
-    ) -> Result<JsonResponse<Vec<Notification>>> {
-        let notifications = get_user_notifications(&session, user_id.0).await?;
+        before: Query<Option<Uuid>>,
+        #[oai(default = "default_page_size", validator(minimum(value = "1"), maximum(value = "100")))]
+        limit: Query<u32>,
+    ) -> Result<JsonResponse<NotificationPage>> {
+        if !superuser.0.has(Permission::ManageNotifications) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let notifications = get_user_notifications(&session, user_id.0, before.0, limit.0).await?;
         Ok(JsonResponse::Ok(Json(notifications)))
     }
+
+    /// Broadcast Notification
+    ///
+    /// Sends a notification to every user in a segment, either every user,
+    /// every member of a guild or an explicit list of users.
+    ///
+    /// The broadcast is sent in the background, its progress can be
+    /// checked with the returned broadcast's id.
+    #[oai(path = "/notifications/broadcasts", method = "post", tag = "ApiTags::Notifications")]
+    pub async fn create_broadcast(
+        &self,
+        superuser: SuperUserBearer,
+        session: Data<&Session>,
+        payload: Json<BroadcastCreation>,
+    ) -> Result<JsonResponse<Broadcast>> {
+        if !superuser.0.has(Permission::ManageNotifications) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let payload = payload.0;
+
+        let user_ids = payload.user_ids.unwrap_or_default();
+        match payload.segment {
+            Segment::Guild if payload.guild_id.is_none() => {
+                return Ok(JsonResponse::bad_request("A guild id is required to broadcast to a guild."))
+            },
+            Segment::Users if user_ids.is_empty() => {
+                return Ok(JsonResponse::bad_request("User ids are required to broadcast to users."))
+            },
+            _ => {},
+        }
+
+        let problem = validate_content(
+            &session,
+            payload.action.as_ref(),
+            payload.expires_at,
+            None,
+        ).await?;
+
+        if let Some(detail) = problem {
+            return Ok(JsonResponse::bad_request(detail))
+        }
+
+        let content = NotificationContent {
+            title: payload.title,
+            description: payload.description,
+            icon: payload.icon,
+            action: payload.action,
+            expires_at: payload.expires_at,
+        };
+
+        let started = broadcasts::start_broadcast(
+            &session,
+            payload.idempotency_key,
+            payload.segment,
+            payload.guild_id,
+            user_ids,
+            content,
+        ).await?;
+
+        match started {
+            Started::New(broadcast) => {
+                audit::record(&session, AuditRecord {
+                    actor_id: superuser.0.user_id,
+                    action: AuditAction::BroadcastStarted,
+                    target_kind: TargetKind::Broadcast,
+                    target_id: broadcast.id.to_string(),
+                    before: None,
+                    after: Some(broadcast.to_json()),
+                }).await;
+
+                Ok(JsonResponse::ok(broadcast))
+            },
+            Started::Existing(broadcast) => Ok(JsonResponse::ok(broadcast)),
+            Started::Conflict => Ok(JsonResponse::bad_request(
+                "This idempotency key was already used by a broadcast which no longer exists.",
+            )),
+        }
+    }
+
+    /// Get Broadcast
+    ///
+    /// Gets a broadcast and its progress.
+    #[oai(path = "/notifications/broadcasts", method = "get", tag = "ApiTags::Notifications")]
+    pub async fn get_broadcast(
+        &self,
+        superuser: SuperUserBearer,
+        session: Data<&Session>,
+        id: Query<Uuid>,
+    ) -> Result<JsonResponse<Broadcast>> {
+        if !superuser.0.has(Permission::ManageNotifications) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        match broadcasts::get_broadcast(&session, id.0).await? {
+            None => Ok(JsonResponse::bad_request("No broadcast exists with this id.")),
+            Some(broadcast) => Ok(JsonResponse::ok(broadcast)),
+        }
+    }
+}
+
+
+/// Checks the notification's action and timings are valid, returning why
+/// they aren't if not.
+async fn validate_content(
+    sess: &Session,
+    action: Option<&NotificationAction>,
+    expires_at: Option<Timestamp>,
+    deliver_at: Option<Timestamp>,
+) -> anyhow::Result<Option<&'static str>> {
+    if let Some(expires_at) = expires_at {
+        if *expires_at <= *Timestamp::now() {
+            return Ok(Some("Notification must expire in the future."))
+        }
+
+        if *expires_at - *Timestamp::now() > notifications::MAX_EXPIRY_SECS * 1000 {
+            return Ok(Some("Notification must expire within 20 years."))
+        }
+
+        if deliver_at.map(|v| *v >= *expires_at).unwrap_or(false) {
+            return Ok(Some("Notification must be delivered before it expires."))
+        }
+    }
+
+    match action {
+        None => Ok(None),
+        Some(action) => notifications::validate_action(sess, action).await,
+    }
 }
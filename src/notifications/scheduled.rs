@@ -0,0 +1,144 @@
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+use uuid::Uuid;
+
+use crate::db::{self, Session};
+use crate::users::notifications::{insert_notification_with_id, parse_action, Icons, NotificationContent};
+use crate::utils::{time_uuid_from, Timestamp};
+
+
+/// How often scheduled notifications are checked for delivery.
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The most scheduled notifications delivered each interval, the rest are
+/// left for the next one.
+const DELIVERY_BATCH_SIZE: i32 = 100;
+
+
+type ScheduledInfo = (
+    Timestamp,
+    Uuid,
+    i64,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<Timestamp>,
+);
+
+
+/// Starts delivering scheduled notifications in the background.
+///
+/// Notifications are delivered up to `DELIVERY_INTERVAL` after their
+/// `deliver_at`.
+pub fn start_delivery(sess: Session) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(DELIVERY_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = deliver_due(&sess).await {
+                error!("failed to deliver scheduled notifications: {}", e);
+            }
+        }
+    });
+}
+
+
+/// Creates every scheduled notification which is due and removes it from
+/// the schedule.
+///
+/// A notification which fails to be delivered is logged and doesn't stop
+/// the rest of the batch, see `deliver`.
+async fn deliver_due(sess: &Session) -> Result<()> {
+    let result = sess.query_prepared(
+        r#"
+        SELECT
+            deliver_at,
+            id,
+            recipient_id,
+            title,
+            description,
+            icon,
+            action_kind,
+            action_target,
+            expires_at
+        FROM scheduled_notifications
+        WHERE bucket = 0 AND deliver_at <= ?
+        LIMIT ?;
+        "#,
+        (Timestamp::now(), DELIVERY_BATCH_SIZE)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    for v in rows.into_typed::<ScheduledInfo>().filter_map(|v| v.ok()) {
+        if let Err(e) = deliver(sess, &v).await {
+            error!("failed to deliver scheduled notification {}: {}", v.1, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a scheduled notification and removes it from the schedule.
+///
+/// The notification is claimed by removing it from the schedule with a
+/// lightweight transaction before it's created, so when several instances
+/// deliver at once only one of them creates it. One which fails to be
+/// created is put back to be retried, its id is derived from the scheduled
+/// id so a retry rewrites the same notification rather than adding another.
+async fn deliver(sess: &Session, v: &ScheduledInfo) -> Result<()> {
+    let result = sess.query_prepared(
+        "DELETE FROM scheduled_notifications WHERE bucket = 0 AND deliver_at = ? AND id = ? IF EXISTS;",
+        (v.0, v.1)
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return Ok(())
+    }
+
+    let content = NotificationContent {
+        title: v.3.clone(),
+        description: v.4.clone(),
+        icon: Option::flatten(v.5.as_ref().map(|v| Icons::from_str(v).ok())),
+        action: parse_action(v.6.clone(), v.7.clone()),
+        expires_at: v.8,
+    };
+
+    let id = time_uuid_from(v.0, v.1);
+    if let Err(e) = insert_notification_with_id(sess, id, v.2, content).await {
+        reschedule(sess, v).await?;
+        return Err(e)
+    }
+
+    Ok(())
+}
+
+/// Puts a claimed notification back on the schedule.
+async fn reschedule(sess: &Session, v: &ScheduledInfo) -> Result<()> {
+    sess.query_prepared(
+        r#"
+        INSERT INTO scheduled_notifications (
+            bucket,
+            deliver_at,
+            id,
+            recipient_id,
+            title,
+            description,
+            icon,
+            action_kind,
+            action_target,
+            expires_at
+        ) VALUES (0, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+        "#,
+        v
+    ).await?;
+
+    Ok(())
+}
@@ -0,0 +1,272 @@
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+use uuid::Uuid;
+
+use crate::db::{self, Session};
+use crate::playlists::{Playlist, PlaylistEntry};
+use crate::rooms::models::Room;
+use crate::users::notifications::{
+    self,
+    ActionKind,
+    Delivery,
+    Icons,
+    NotificationAction,
+    NotificationContent,
+};
+use crate::utils::Timestamp;
+
+
+/// How long upvotes are collected into the same notification, in seconds.
+const VOTE_DIGEST_WINDOW_SECS: i64 = 3600;
+
+/// How many times an upvote is retried when it races another to update
+/// the digest.
+const MAX_DIGEST_ATTEMPTS: usize = 5;
+
+
+/// Something which can be upvoted.
+#[derive(Debug, Copy, Clone)]
+enum VoteTarget {
+    Playlist,
+    Entry,
+}
+
+
+/// Lets the owner know their playlist was upvoted.
+///
+/// Like every notification sent by the system this never fails, errors
+/// are logged so they don't fail the request which caused them.
+pub async fn playlist_upvoted(sess: &Session, playlist: &Playlist, voter_id: i64) {
+    let action = NotificationAction {
+        kind: ActionKind::Playlist,
+        target: playlist.id.to_string(),
+    };
+
+    let result = notify_upvoted(
+        sess,
+        VoteTarget::Playlist,
+        playlist.id,
+        *playlist.owner_id,
+        voter_id,
+        &playlist.title,
+        Some(action),
+    ).await;
+
+    log_failure("playlist upvote", result);
+}
+
+/// Lets the owner know their playlist entry was upvoted.
+pub async fn entry_upvoted(sess: &Session, entry: &PlaylistEntry, voter_id: i64) {
+    let result = notify_upvoted(
+        sess,
+        VoteTarget::Entry,
+        entry.id,
+        *entry.owner_id,
+        voter_id,
+        &entry.title,
+        None,
+    ).await;
+
+    log_failure("entry upvote", result);
+}
+
+/// Lets the owner know a superuser closed their room.
+pub async fn room_force_closed(sess: &Session, room: &Room) {
+    let content = NotificationContent {
+        title: "Your room was closed".to_string(),
+        description: Some(format!("{} was closed by a moderator.", &room.title)),
+        icon: Some(Icons::Issues),
+        action: None,
+        expires_at: None,
+    };
+
+    let result = notifications::insert_notification(sess, *room.owner_id, content).await;
+    log_failure("room closure", result);
+}
+
+/// Lets the owner know a superuser removed their playlist.
+pub async fn playlist_removed(sess: &Session, playlist: &Playlist) {
+    content_removed(sess, *playlist.owner_id, "playlist", &playlist.title).await
+}
+
+/// Lets the owner know a superuser removed their playlist entry.
+pub async fn entry_removed(sess: &Session, entry: &PlaylistEntry) {
+    content_removed(sess, *entry.owner_id, "entry", &entry.title).await
+}
+
+/// Lets the user know they were granted credits.
+pub async fn credits_granted(sess: &Session, user_id: i64, amount: i32) {
+    let plural = if amount == 1 { "" } else { "s" };
+    let content = NotificationContent {
+        title: "You received credits".to_string(),
+        description: Some(format!("You were given {} vote credit{}.", amount, plural)),
+        icon: Some(Icons::Coins),
+        action: None,
+        expires_at: None,
+    };
+
+    let result = notifications::insert_notification(sess, user_id, content).await;
+    log_failure("credit grant", result);
+}
+
+
+async fn content_removed(sess: &Session, owner_id: i64, kind: &str, title: &str) {
+    let content = NotificationContent {
+        title: format!("Your {} was removed", kind),
+        description: Some(format!("{} was removed by a moderator.", title)),
+        icon: Some(Icons::Issues),
+        action: None,
+        expires_at: None,
+    };
+
+    let result = notifications::insert_notification(sess, owner_id, content).await;
+    log_failure("content removal", result);
+}
+
+/// Notifies the owner of an upvote, collecting every upvote within
+/// `VOTE_DIGEST_WINDOW_SECS` of the first into a single notification.
+///
+/// The first upvote claims the digest and creates the notification, later
+/// ones bump the digest's count with a lightweight transaction and update
+/// its notification with the new total instead. Users upvoting their own
+/// content are ignored.
+async fn notify_upvoted(
+    sess: &Session,
+    target: VoteTarget,
+    target_id: Uuid,
+    owner_id: i64,
+    voter_id: i64,
+    title: &str,
+    action: Option<NotificationAction>,
+) -> Result<()> {
+    if owner_id == voter_id {
+        return Ok(())
+    }
+
+    let kind = match target {
+        VoteTarget::Playlist => "playlist",
+        VoteTarget::Entry => "entry",
+    };
+
+    for _ in 0..MAX_DIGEST_ATTEMPTS {
+        let (notification_id, votes, started_on) = match get_vote_digest(sess, target_id).await? {
+            None => {
+                if start_vote_digest(sess, target_id, owner_id, kind, title, action.clone()).await? {
+                    return Ok(())
+                }
+
+                continue;
+            },
+            Some(digest) => digest,
+        };
+
+        let remaining_secs = VOTE_DIGEST_WINDOW_SECS - (*Timestamp::now() - *started_on) / 1000;
+        if remaining_secs <= 0 {
+            // The window just ended, start over.
+            end_vote_digest(sess, target_id, votes).await?;
+            continue;
+        }
+
+        let result = sess.query_prepared(
+            "UPDATE vote_digests USING TTL ? SET votes = ? WHERE target_id = ? IF votes = ?;",
+            (remaining_secs as i32, votes + 1, target_id, votes)
+        ).await?;
+
+        if !db::was_applied(&result) {
+            continue;
+        }
+
+        // The owner had the category muted when the digest started.
+        let notification_id = match notification_id {
+            None => return Ok(()),
+            Some(notification_id) => notification_id,
+        };
+
+        let updated = notifications::update_notification_text(
+            sess,
+            owner_id,
+            notification_id,
+            format!("Your {} got {} votes", kind, votes + 1),
+            Some(format!("{} got {} votes in the last hour.", title, votes + 1)),
+        ).await?;
+
+        if updated {
+            return Ok(())
+        }
+
+        // The notification is gone, start over.
+        end_vote_digest(sess, target_id, votes + 1).await?;
+    }
+
+    Err(anyhow!("failed to update vote digest for {} after {} attempts", target_id, MAX_DIGEST_ATTEMPTS))
+}
+
+/// Claims the digest for the target and notifies the owner of their first
+/// vote, returning `false` if another upvote claimed it first.
+async fn start_vote_digest(
+    sess: &Session,
+    target_id: Uuid,
+    owner_id: i64,
+    kind: &str,
+    title: &str,
+    action: Option<NotificationAction>,
+) -> Result<bool> {
+    let result = sess.query_prepared(
+        "INSERT INTO vote_digests (target_id, started_on, votes) VALUES (?, ?, 1) IF NOT EXISTS;",
+        (target_id, Timestamp::now())
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return Ok(false)
+    }
+
+    let content = NotificationContent {
+        title: format!("Your {} got a vote", kind),
+        description: Some(format!("Someone upvoted {}.", title)),
+        icon: Some(Icons::Votes),
+        action,
+        expires_at: None,
+    };
+
+    if let Delivery::Delivered(notification_id) = notifications::insert_notification(sess, owner_id, content).await? {
+        sess.query_prepared(
+            "UPDATE vote_digests SET notification_id = ? WHERE target_id = ? IF EXISTS;",
+            (notification_id, target_id)
+        ).await?;
+    }
+
+    Ok(true)
+}
+
+/// Removes the target's digest unless another upvote changed it since it
+/// had `votes` votes.
+async fn end_vote_digest(sess: &Session, target_id: Uuid, votes: i32) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM vote_digests WHERE target_id = ? IF votes = ?;",
+        (target_id, votes)
+    ).await?;
+
+    Ok(())
+}
+
+async fn get_vote_digest(sess: &Session, target_id: Uuid) -> Result<Option<(Option<Uuid>, i32, Timestamp)>> {
+    let result = sess.query_prepared(
+        "SELECT notification_id, votes, started_on FROM vote_digests WHERE target_id = ?;",
+        (target_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let digest = rows.into_typed::<(Option<Uuid>, i32, Timestamp)>()
+        .next()
+        .transpose()?;
+
+    Ok(digest)
+}
+
+fn log_failure<T>(event: &str, result: Result<T>) {
+    if let Err(e) = result {
+        warn!("failed to send {} notification: {}", event, e);
+    }
+}
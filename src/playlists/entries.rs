@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use anyhow::anyhow;
 use uuid::Uuid;
 use poem_openapi::Object;
@@ -38,17 +39,24 @@ pub async fn get_entry_by_id(sess: &Session, id: Uuid) -> anyhow::Result<Option<
     Ok(Some(entry))
 }
 
+/// Gets the entries with the given ids in the order the ids are given,
+/// ids which are repeated or have no entry are skipped.
 pub async fn get_entries_with_ids(sess: &Session, ids: Vec<Uuid>) -> anyhow::Result<Vec<PlaylistEntry>> {
     let result = sess.query_prepared(
         "SELECT * FROM playlist_entries WHERE id IN ?;",
-        (ids,)
+        (&ids,)
     ).await?;
 
     let rows = result.rows
         .ok_or_else(|| anyhow!("expected returned rows"))?;
 
-    let entries = rows.into_typed::<PlaylistEntry>()
+    let mut entries: HashMap<Uuid, PlaylistEntry> = rows.into_typed::<PlaylistEntry>()
         .filter_map(|v| v.ok())
+        .map(|v| (v.id, v))
+        .collect();
+
+    let entries = ids.into_iter()
+        .filter_map(|id| entries.remove(&id))
         .collect();
 
     Ok(entries)
@@ -57,7 +65,7 @@ pub async fn get_entries_with_ids(sess: &Session, ids: Vec<Uuid>) -> anyhow::Res
 pub async fn upvote_playlist(sess: &Session, user_id: i64, entry_id: Uuid) -> anyhow::Result<()> {
     sess.query_prepared(
         "INSERT INTO playlist_entries_votes (user_id, entry_id) VALUES (?, ?);",
-        (user_id, entry_id.clone()),
+        (user_id, entry_id),
     ).await?;
 
     sess.query_prepared(
@@ -7,15 +7,19 @@ use poem::Result;
 use poem::web::Data;
 use poem_openapi::{Object, OpenApi};
 use poem_openapi::param::Query;
+use poem_openapi::types::ToJSON;
 use poem_openapi::payload::Json;
 use serde_json::Value;
 
 pub use playlist::*;
 pub use entries::*;
 use crate::ApiTags;
+use crate::admin::audit::{self, AuditAction, AuditRecord, TargetKind};
+use crate::admin::roles::Permission;
 use crate::db::Session;
-use crate::users::user_info;
-use crate::utils::{JsonResponse, SuperUserBearer, TokenBearer};
+use crate::notifications::system;
+use crate::users::credits::{self, CreditOutcome, CreditReason};
+use crate::utils::{JsonResponse, SuperUserBearer, UserBearer, WritePlaylistsBearer};
 
 
 #[derive(Object, Debug)]
@@ -32,6 +36,7 @@ pub struct PlaylistCreationPayload {
     #[oai(default)]
     is_public: bool,
 
+    /// The entries in the order they're played.
     items: Vec<Uuid>,
 }
 
@@ -70,7 +75,7 @@ impl PlaylistsApi {
         Ok(
             playlist::get_playlist_by_id(&session, id.0)
                 .await
-                .map(|v| Json(v))?
+                .map(Json)?
         )
     }
 
@@ -86,36 +91,70 @@ impl PlaylistsApi {
         Ok(
             entries::get_entry_by_id(&session, id.0)
                 .await
-                .map(|v| Json(v))?
+                .map(Json)?
         )
     }
 
     /// Superuser Remove Playlist
     ///
-    /// Forcefully removes a playlist by a superuser.
+    /// Forcefully removes a playlist by a superuser, letting its owner know.
     #[oai(path = "/playlists/override", method = "delete", tag = "ApiTags::Playlists")]
     pub async fn remove_playlist_superuser(
         &self,
         id: Query<Uuid>,
-        _token: SuperUserBearer,
+        superuser: SuperUserBearer,
         session: Data<&Session>,
     ) -> Result<JsonResponse<Value>> {
+        if !superuser.0.has(Permission::ModerateContent) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let playlist = playlist::get_playlist_by_id(&session, id.0).await?;
         playlist::remove_playlist(&session, id.0).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: superuser.0.user_id,
+            action: AuditAction::PlaylistRemoved,
+            target_kind: TargetKind::Playlist,
+            target_id: id.0.to_string(),
+            before: playlist.as_ref().map(|v| v.to_json()),
+            after: None,
+        }).await;
+
+        if let Some(playlist) = playlist {
+            system::playlist_removed(&session, &playlist).await;
+        }
 
         Ok(JsonResponse::Ok(Json(Value::Null)))
     }
 
     /// Superuser Remove Entry
     ///
-    /// Forcefully removes a playlist entry by a superuser.
+    /// Forcefully removes a playlist entry by a superuser, letting its owner know.
     #[oai(path = "/entries/override", method = "delete", tag = "ApiTags::Playlists")]
     pub async fn remove_entry_superuser(
         &self,
         id: Query<Uuid>,
-        _token: SuperUserBearer,
+        superuser: SuperUserBearer,
         session: Data<&Session>,
     ) -> Result<JsonResponse<Value>> {
+        if !superuser.0.has(Permission::ModerateContent) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let entry = entries::get_entry_by_id(&session, id.0).await?;
         entries::remove_entry(&session, id.0).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: superuser.0.user_id,
+            action: AuditAction::EntryRemoved,
+            target_kind: TargetKind::Entry,
+            target_id: id.0.to_string(),
+            before: entry.as_ref().map(|v| v.to_json()),
+            after: None,
+        }).await;
+
+        if let Some(entry) = entry {
+            system::entry_removed(&session, &entry).await;
+        }
 
         Ok(JsonResponse::Ok(Json(Value::Null)))
     }
@@ -128,12 +167,9 @@ impl PlaylistsApi {
         &self,
         id: Query<Uuid>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: WritePlaylistsBearer,
     ) -> Result<JsonResponse<Value>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
         let playlist = match playlist::get_playlist_by_id(&session, id.0).await? {
             None => return Ok(JsonResponse::bad_request("Playlist does not exist.")),
@@ -145,6 +181,14 @@ impl PlaylistsApi {
         }
 
         playlist::remove_playlist(&session, playlist.id).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: Some(user_id),
+            action: AuditAction::PlaylistRemoved,
+            target_kind: TargetKind::Playlist,
+            target_id: playlist.id.to_string(),
+            before: Some(playlist.to_json()),
+            after: None,
+        }).await;
 
         Ok(JsonResponse::ok(Value::Null))
     }
@@ -157,12 +201,9 @@ impl PlaylistsApi {
         &self,
         id: Query<Uuid>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: WritePlaylistsBearer,
     ) -> Result<JsonResponse<Value>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
         let entry = match entries::get_entry_by_id(&session, id.0).await? {
             None => return Ok(JsonResponse::bad_request("Playlist does not exist.")),
@@ -174,6 +215,14 @@ impl PlaylistsApi {
         }
 
         entries::remove_entry(&session, entry.id).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: Some(user_id),
+            action: AuditAction::EntryRemoved,
+            target_kind: TargetKind::Entry,
+            target_id: entry.id.to_string(),
+            before: Some(entry.to_json()),
+            after: None,
+        }).await;
 
         Ok(JsonResponse::ok(Value::Null))
     }
@@ -186,34 +235,37 @@ impl PlaylistsApi {
         &self,
         id: Query<Uuid>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<Playlist>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
         let mut playlist = match playlist::get_playlist_by_id(&session, id.0).await? {
             None => return Ok(JsonResponse::bad_request("Playlist does not exist.")),
             Some(v) => v,
         };
 
-        if playlist::has_user_voted(&session, user_id, playlist.id.clone()).await? {
+        if playlist::has_user_voted(&session, user_id, playlist.id).await? {
             return Ok(JsonResponse::bad_request(
                 "You have already up-voted this playlist in the last 12 hours."
             ))
         }
 
-        let credits = user_info::get_user_vote_credits(&session, user_id).await?;
-
-        if credits <= 0 {
-            return Ok(JsonResponse::bad_request("You do not have enough credits."))
+        let key = credits::vote_key(CreditReason::PlaylistVote, playlist.id);
+        match credits::apply(&session, user_id, -1, CreditReason::PlaylistVote, &key).await? {
+            CreditOutcome::Applied(_) => {},
+            CreditOutcome::Duplicate => return Ok(JsonResponse::bad_request(
+                "You have already up-voted this playlist in the last 12 hours."
+            )),
+            CreditOutcome::Insufficient => return Ok(JsonResponse::bad_request("You do not have enough credits.")),
         }
 
-        user_info::adjust_user_credits(&session, user_id, -1).await?;
-        playlist::upvote_playlist(&session, user_id, playlist.id.clone()).await?;
+        if let Err(e) = playlist::upvote_playlist(&session, user_id, playlist.id).await {
+            credits::refund(&session, user_id, 1, &key).await?;
+            return Err(e.into())
+        }
 
         playlist.votes += 1;
+        system::playlist_upvoted(&session, &playlist, user_id).await;
 
         Ok(JsonResponse::ok(playlist))
     }
@@ -226,34 +278,37 @@ impl PlaylistsApi {
         &self,
         id: Query<Uuid>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<PlaylistEntry>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
         let mut entry = match entries::get_entry_by_id(&session, id.0).await? {
             None => return Ok(JsonResponse::bad_request("Entry does not exist.")),
             Some(v) => v,
         };
 
-        if entries::has_user_voted(&session, user_id, entry.id.clone()).await? {
+        if entries::has_user_voted(&session, user_id, entry.id).await? {
             return Ok(JsonResponse::bad_request(
                 "You have already up-voted this entry in the last 12 hours.",
             ))
         }
 
-        let credits = user_info::get_user_vote_credits(&session, user_id).await?;
-
-        if credits <= 0 {
-            return Ok(JsonResponse::bad_request("You do not have enough credits."))
+        let key = credits::vote_key(CreditReason::EntryVote, entry.id);
+        match credits::apply(&session, user_id, -1, CreditReason::EntryVote, &key).await? {
+            CreditOutcome::Applied(_) => {},
+            CreditOutcome::Duplicate => return Ok(JsonResponse::bad_request(
+                "You have already up-voted this entry in the last 12 hours."
+            )),
+            CreditOutcome::Insufficient => return Ok(JsonResponse::bad_request("You do not have enough credits.")),
         }
 
-        user_info::adjust_user_credits(&session, user_id, -1).await?;
-        entries::upvote_playlist(&session, user_id, entry.id.clone()).await?;
+        if let Err(e) = entries::upvote_playlist(&session, user_id, entry.id).await {
+            credits::refund(&session, user_id, 1, &key).await?;
+            return Err(e.into())
+        }
 
         entry.votes += 1;
+        system::entry_upvoted(&session, &entry, user_id).await;
 
         Ok(JsonResponse::ok(entry))
     }
@@ -272,12 +327,9 @@ impl PlaylistsApi {
         &self,
         payload: Json<PlaylistCreationPayload>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: WritePlaylistsBearer,
     ) -> Result<JsonResponse<Playlist>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
         let items = entries::get_entries_with_ids(&session, payload.0.items).await?;
         let is_nsfw = items.iter().any(|v|  v.nsfw);
@@ -313,12 +365,9 @@ impl PlaylistsApi {
         &self,
         payload: Json<EntryCreationPayload>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: WritePlaylistsBearer,
     ) -> Result<JsonResponse<PlaylistEntry>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
         let entry_id = Uuid::new_v4();
         let entry = insert_entry(
@@ -351,14 +400,11 @@ impl PlaylistsApi {
         id: Query<Uuid>,
         payload: Json<PlaylistCreationPayload>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: WritePlaylistsBearer,
     ) -> Result<JsonResponse<Playlist>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
-        let mut playlist = match playlist::get_playlist_by_id(&session, id.0.clone()).await? {
+        let mut playlist = match playlist::get_playlist_by_id(&session, id.0).await? {
             Some(p) => p,
             None => return Ok(JsonResponse::bad_request("No playlist exists with this id.")),
         };
@@ -382,9 +428,9 @@ impl PlaylistsApi {
                 user_id,
                 payload.0.banner.clone(),
                 payload.0.description.clone(),
-                payload.0.is_public.clone(),
+                payload.0.is_public,
                 items.clone(),
-                is_nsfw.clone(),
+                is_nsfw,
                 payload.0.title.clone(),
             false,
         ).await?;
@@ -412,14 +458,11 @@ impl PlaylistsApi {
         id: Query<Uuid>,
         payload: Json<EntryCreationPayload>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: WritePlaylistsBearer,
     ) -> Result<JsonResponse<PlaylistEntry>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user_id = token.0.id;
 
-        let mut entry = match entries::get_entry_by_id(&session, id.0.clone()).await? {
+        let mut entry = match entries::get_entry_by_id(&session, id.0).await? {
             Some(p) => p,
             None => return Ok(JsonResponse::bad_request("No playlist entry exists with this id.")),
         };
@@ -433,8 +476,8 @@ impl PlaylistsApi {
             id.0,
             user_id,
             payload.0.description.clone(),
-            payload.0.is_public.clone(),
-            payload.0.nsfw.clone(),
+            payload.0.is_public,
+            payload.0.nsfw,
             payload.0.ref_link.clone(),
             payload.0.title.clone(),
             false,
@@ -460,6 +503,7 @@ fn filter_valid_entries(owner_id: i64, is_public: bool, entries: Vec<PlaylistEnt
 }
 
 
+#[allow(clippy::too_many_arguments)]
 async fn insert_playlist(
     sess: &Session,
     id: Uuid,
@@ -486,7 +530,7 @@ async fn insert_playlist(
             banner,
             description,
             is_public,
-            items,
+            item_order,
             nsfw,
             title,
             votes
@@ -513,6 +557,7 @@ async fn insert_playlist(
 }
 
 
+#[allow(clippy::too_many_arguments)]
 async fn insert_entry(
     sess: &Session,
     id: Uuid,
@@ -2,24 +2,69 @@ use anyhow::anyhow;
 use uuid::Uuid;
 use poem_openapi::Object;
 use scylla::{FromRow, IntoTypedRows};
+use scylla::cql_to_rust::FromRowError;
+use scylla::frame::response::result::Row;
 
 use crate::db::Session;
 use crate::utils::JsSafeBigInt;
 
 
-#[derive(Object, FromRow)]
+#[derive(Object)]
 pub struct Playlist {
     pub id: Uuid,
     pub owner_id: JsSafeBigInt,
     pub banner: Option<String>,
     pub description: Option<String>,
     pub is_public: bool,
+
+    /// The playlist's entries in the order they're played.
     pub items: Vec<Uuid>,
     pub nsfw: bool,
     pub title: String,
     pub votes: i32,
 }
 
+impl FromRow for Playlist {
+    fn from_row(row: Row) -> Result<Self, FromRowError> {
+        PlaylistRow::from_row(row).map(Self::from)
+    }
+}
+
+/// A playlist as it's stored.
+///
+/// Entries used to be kept in the `items` set which lost their order,
+/// they're now kept in `item_order`. Playlists which haven't been saved
+/// since only have the set.
+#[derive(FromRow)]
+struct PlaylistRow {
+    id: Uuid,
+    owner_id: JsSafeBigInt,
+    banner: Option<String>,
+    description: Option<String>,
+    is_public: bool,
+    item_order: Option<Vec<Uuid>>,
+    items: Option<Vec<Uuid>>,
+    nsfw: bool,
+    title: String,
+    votes: i32,
+}
+
+impl From<PlaylistRow> for Playlist {
+    fn from(row: PlaylistRow) -> Self {
+        Self {
+            id: row.id,
+            owner_id: row.owner_id,
+            banner: row.banner,
+            description: row.description,
+            is_public: row.is_public,
+            items: row.item_order.or(row.items).unwrap_or_default(),
+            nsfw: row.nsfw,
+            title: row.title,
+            votes: row.votes,
+        }
+    }
+}
+
 
 pub async fn get_playlist_by_id(sess: &Session, id: Uuid) -> anyhow::Result<Option<Playlist>> {
     let result = sess.query_prepared(
@@ -45,7 +90,7 @@ pub async fn get_playlist_by_id(sess: &Session, id: Uuid) -> anyhow::Result<Opti
 pub async fn upvote_playlist(sess: &Session, user_id: i64, playlist_id: Uuid) -> anyhow::Result<()> {
     sess.query_prepared(
         "INSERT INTO playlist_votes (user_id, playlist_id) VALUES (?, ?);",
-        (user_id, playlist_id.clone()),
+        (user_id, playlist_id),
     ).await?;
 
     sess.query_prepared(
@@ -0,0 +1,101 @@
+use anyhow::Result;
+
+use crate::db::Session;
+use crate::rooms::{invites, members, roles};
+use crate::rooms::models::{GuildVisibility, Room, RoomRole};
+use crate::users::user_info::User;
+
+
+/// Checks if the given user is allowed to see and join the room.
+///
+/// This is the case if any of the following conditions are met:
+/// - The user owns the room.
+/// - The room belongs to a guild the user can manage.
+/// - The room is invite only and the user has redeemed an invite or
+///   been invited directly.
+/// - The room is public and not invite only.
+/// - The room is private but allows guild members to join and the user
+///   is a member of said guild, see `has_guild_access`.
+///
+/// Guild rooms visible to managers only are never open to anyone else,
+/// even if they're public or the user has an invite. Users who have
+/// recently been kicked from the room are always denied unless they own
+/// or moderate it.
+pub async fn has_room_access(sess: &Session, room: &Room, user: &User) -> Result<bool> {
+    if can_moderate_room(room, user) {
+        return Ok(true)
+    }
+
+    if room.is_managers_only() {
+        return Ok(false)
+    }
+
+    if members::is_kicked(sess, room.id, *user.id).await? {
+        return Ok(false)
+    }
+
+    if room.invite_only {
+        return invites::has_grant(sess, room.id, *user.id).await
+    }
+
+    if room.is_public {
+        return Ok(true)
+    }
+
+    Ok(has_guild_access(room, user))
+}
+
+/// Checks if the user holds at least the given role within the room.
+///
+/// All role based permission checks, such as who may drive playback,
+/// should go through this.
+pub async fn has_room_role(sess: &Session, room: &Room, user_id: i64, role: RoomRole) -> Result<bool> {
+    Ok(roles::get_role(sess, room, user_id).await? >= role)
+}
+
+/// Checks if the user is currently present in the room and holds at least
+/// the given role within it.
+///
+/// Actions taken from within the room, such as driving playback or
+/// calling its members, should go through this so a user who has left or
+/// been kicked can't keep taking them.
+pub async fn is_present_with_role(sess: &Session, room: &Room, user_id: i64, role: RoomRole) -> Result<bool> {
+    if !has_room_role(sess, room, user_id, role).await? {
+        return Ok(false)
+    }
+
+    if members::is_kicked(sess, room.id, user_id).await? {
+        return Ok(false)
+    }
+
+    members::is_room_member(sess, room.id, user_id).await
+}
+
+/// Checks if the user can moderate the room, closing it or kicking
+/// its members.
+///
+/// Only the room owner and managers of the room's guild can do this.
+pub fn can_moderate_room(room: &Room, user: &User) -> bool {
+    (room.owner_id == user.id) | is_guild_manager(room, user)
+}
+
+/// Checks if the user is a manager of the guild the room belongs to.
+pub fn is_guild_manager(room: &Room, user: &User) -> bool {
+    room.guild_id
+        .and_then(|guild_id| user.access_servers.get(&guild_id).copied())
+        .unwrap_or(false)
+}
+
+/// Checks if the user is allowed in the room by being part of the room's
+/// guild, respecting the room's guild visibility.
+pub fn has_guild_access(room: &Room, user: &User) -> bool {
+    let guild_id = match room.guild_id {
+        None => return false,
+        Some(guild_id) => guild_id,
+    };
+
+    match room.guild_visibility.unwrap_or(GuildVisibility::Members) {
+        GuildVisibility::Members => user.access_servers.contains_key(&guild_id),
+        GuildVisibility::Managers => is_guild_manager(room, user),
+    }
+}
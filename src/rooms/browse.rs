@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::rooms::models::Room;
+use crate::utils::Timestamp;
+
+
+/// All public rooms currently live in a single browse partition.
+///
+/// The number of concurrently open public rooms is small enough that
+/// one partition comfortably holds them, if that changes this becomes
+/// a time bucket.
+const BROWSE_BUCKET: i32 = 0;
+
+
+/// A position within one of the browse listings.
+///
+/// Cursors point at the last room of a page rather than at a page number
+/// so rooms being opened or closed don't shift the following pages.
+#[derive(Debug, Copy, Clone)]
+pub struct Cursor {
+    key: i64,
+    id: Uuid,
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let raw = format!("{}:{}", self.key, self.id);
+        write!(f, "{}", base64::encode_config(raw, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw = base64::decode_config(s, base64::URL_SAFE_NO_PAD)?;
+        let raw = String::from_utf8(raw)?;
+
+        let (key, id) = raw.split_once(':')
+            .ok_or_else(|| anyhow!("malformed cursor"))?;
+
+        Ok(Self {
+            key: key.parse()?,
+            id: Uuid::parse_str(id)?,
+        })
+    }
+}
+
+
+/// Adds a room to the browse listings.
+///
+/// Only rooms which are `Room::is_listed` should be added.
+pub async fn add_room(sess: &Session, room: &Room) -> Result<()> {
+    sess.query_prepared(
+        "INSERT INTO rooms_by_creation (bucket, created_on, id) VALUES (?, ?, ?);",
+        (BROWSE_BUCKET, room.created_on, room.id)
+    ).await?;
+
+    sess.query_prepared(
+        "INSERT INTO rooms_by_viewers (bucket, viewers, id) VALUES (?, ?, ?);",
+        (BROWSE_BUCKET, room.viewers, room.id)
+    ).await?;
+
+    Ok(())
+}
+
+/// Removes a room from the browse listings.
+pub async fn remove_room(sess: &Session, room: &Room) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM rooms_by_creation WHERE bucket = ? AND created_on = ? AND id = ?;",
+        (BROWSE_BUCKET, room.created_on, room.id)
+    ).await?;
+
+    sess.query_prepared(
+        "DELETE FROM rooms_by_viewers WHERE bucket = ? AND viewers = ? AND id = ?;",
+        (BROWSE_BUCKET, room.viewers, room.id)
+    ).await?;
+
+    Ok(())
+}
+
+/// Moves a room to its new position in the viewer listing.
+///
+/// The room is expected to still hold its old viewer count. Updates which
+/// race each other can leave the room listed under an old count as well,
+/// those rows are skipped and removed when they're read, see
+/// `get_top_rooms`.
+pub async fn set_room_viewers(sess: &Session, room: &Room, viewers: i32) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM rooms_by_viewers WHERE bucket = ? AND viewers = ? AND id = ?;",
+        (BROWSE_BUCKET, room.viewers, room.id)
+    ).await?;
+
+    sess.query_prepared(
+        "INSERT INTO rooms_by_viewers (bucket, viewers, id) VALUES (?, ?, ?);",
+        (BROWSE_BUCKET, viewers, room.id)
+    ).await?;
+
+    Ok(())
+}
+
+
+/// Gets a page of public rooms ordered by creation time, newest first.
+pub async fn get_new_rooms(
+    sess: &Session,
+    cursor: Option<Cursor>,
+    limit: i32,
+) -> Result<(Vec<Room>, Option<Cursor>)> {
+    let result = if let Some(cursor) = cursor {
+        sess.query_prepared(
+            r#"
+            SELECT created_on, id FROM rooms_by_creation
+            WHERE bucket = ? AND (created_on, id) < (?, ?)
+            LIMIT ?;
+            "#,
+            (BROWSE_BUCKET, Timestamp(cursor.key), cursor.id, limit)
+        ).await?
+    } else {
+        sess.query_prepared(
+            "SELECT created_on, id FROM rooms_by_creation WHERE bucket = ? LIMIT ?;",
+            (BROWSE_BUCKET, limit)
+        ).await?
+    };
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let keys: Vec<(i64, Uuid)> = rows.into_typed::<(Timestamp, Uuid)>()
+        .filter_map(|v| v.ok())
+        .map(|(created_on, id)| (*created_on, id))
+        .collect();
+
+    let (rooms, next_cursor, _) = get_page(sess, keys, limit, |room| *room.created_on).await?;
+
+    Ok((rooms, next_cursor))
+}
+
+/// Gets a page of public rooms ordered by their viewer count, highest first.
+///
+/// Listing rows left behind under a room's old viewer count are removed.
+pub async fn get_top_rooms(
+    sess: &Session,
+    cursor: Option<Cursor>,
+    limit: i32,
+) -> Result<(Vec<Room>, Option<Cursor>)> {
+    let result = if let Some(cursor) = cursor {
+        sess.query_prepared(
+            r#"
+            SELECT viewers, id FROM rooms_by_viewers
+            WHERE bucket = ? AND (viewers, id) < (?, ?)
+            LIMIT ?;
+            "#,
+            (BROWSE_BUCKET, cursor.key as i32, cursor.id, limit)
+        ).await?
+    } else {
+        sess.query_prepared(
+            "SELECT viewers, id FROM rooms_by_viewers WHERE bucket = ? LIMIT ?;",
+            (BROWSE_BUCKET, limit)
+        ).await?
+    };
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let keys: Vec<(i64, Uuid)> = rows.into_typed::<(i32, Uuid)>()
+        .filter_map(|v| v.ok())
+        .map(|(viewers, id)| (viewers as i64, id))
+        .collect();
+
+    let (rooms, next_cursor, stale) = get_page(sess, keys, limit, |room| room.viewers as i64).await?;
+
+    for (viewers, id) in stale {
+        sess.query_prepared(
+            "DELETE FROM rooms_by_viewers WHERE bucket = ? AND viewers = ? AND id = ?;",
+            (BROWSE_BUCKET, viewers as i32, id)
+        ).await?;
+    }
+
+    Ok((rooms, next_cursor))
+}
+
+
+/// Resolves the listing keys into their rooms, keeping the listing order.
+///
+/// Rooms which have since closed or stopped being listed are skipped, the cursor
+/// is still derived from the listing itself so paging carries on past them.
+/// Keys which no longer match the room's `key_of` are skipped as well and
+/// returned so they can be removed from the listing.
+async fn get_page(
+    sess: &Session,
+    keys: Vec<(i64, Uuid)>,
+    limit: i32,
+    key_of: impl Fn(&Room) -> i64,
+) -> Result<(Vec<Room>, Option<Cursor>, Vec<(i64, Uuid)>)> {
+    let next_cursor = if keys.len() >= limit as usize {
+        keys.last().map(|(key, id)| Cursor { key: *key, id: *id })
+    } else {
+        None
+    };
+
+    if keys.is_empty() {
+        return Ok((vec![], next_cursor, vec![]))
+    }
+
+    let ids: Vec<Uuid> = keys.iter()
+        .map(|(_, id)| *id)
+        .collect();
+
+    let result = sess.query_prepared(
+        "SELECT * FROM active_rooms WHERE id IN ?;",
+        (ids,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let mut rooms: HashMap<Uuid, Room> = rows.into_typed::<Room>()
+        .filter_map(|v| v.ok())
+        .filter(|v| v.is_listed())
+        .map(|v| (v.id, v))
+        .collect();
+
+    let mut page = vec![];
+    let mut stale = vec![];
+    for (key, id) in keys {
+        match rooms.remove(&id) {
+            None => {},
+            Some(room) if key_of(&room) != key => {
+                stale.push((key, id));
+                rooms.insert(id, room);
+            },
+            Some(room) => page.push(room),
+        }
+    }
+
+    Ok((page, next_cursor, stale))
+}
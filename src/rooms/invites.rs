@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+use uuid::Uuid;
+
+use crate::db::{self, Session};
+use crate::rooms::models::RoomInvite;
+use crate::utils::{generate_token, JsSafeBigInt, Timestamp};
+
+
+/// The length of generated invite codes.
+const INVITE_CODE_LENGTH: usize = 10;
+
+/// How many times redeeming a code is attempted when racing other users
+/// redeeming the same code.
+const MAX_REDEEM_ATTEMPTS: usize = 3;
+
+
+/// The outcome of trying to redeem an invite code.
+pub enum Redeemed {
+    /// The code was used and the user has been granted access to the room.
+    Granted(Uuid),
+
+    /// The code does not exist, has expired or has been used up.
+    Invalid,
+}
+
+
+/// Creates a new invite code for the room.
+///
+/// The code is removed once it expires, `max_uses` of `None` allows the
+/// code to be used any number of times until then.
+pub async fn create_invite(
+    sess: &Session,
+    room_id: Uuid,
+    created_by: i64,
+    max_uses: Option<i32>,
+    expires_in: u32,
+) -> Result<RoomInvite> {
+    let invite = RoomInvite {
+        code: generate_token(INVITE_CODE_LENGTH),
+        room_id,
+        created_by: JsSafeBigInt(created_by),
+        expires_on: Timestamp(*Timestamp::now() + (expires_in as i64 * 1000)),
+        max_uses,
+        uses: 0,
+    };
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO room_invites (
+            code,
+            room_id,
+            created_by,
+            expires_on,
+            max_uses,
+            uses
+        ) VALUES (?, ?, ?, ?, ?, 0) USING TTL ?;
+        "#,
+        (
+            invite.code.clone(),
+            invite.room_id,
+            created_by,
+            invite.expires_on,
+            invite.max_uses,
+            expires_in as i32,
+        )
+    ).await?;
+
+    Ok(invite)
+}
+
+/// Gets an invite by its code if it exists.
+pub async fn get_invite(sess: &Session, code: &str) -> Result<Option<RoomInvite>> {
+    let result = sess.query_prepared(
+        "SELECT code, room_id, created_by, expires_on, max_uses, uses FROM room_invites WHERE code = ?;",
+        (code.to_string(),)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let invite = match rows.into_typed::<RoomInvite>().next() {
+        None => return Ok(None),
+        Some(v) => v?,
+    };
+
+    Ok(Some(invite))
+}
+
+/// Revokes an invite code so it can no longer be redeemed.
+pub async fn remove_invite(sess: &Session, code: &str) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM room_invites WHERE code = ?;",
+        (code.to_string(),)
+    ).await?;
+
+    Ok(())
+}
+
+/// Uses up one of the code's uses and grants the user access to the
+/// room the code belongs to.
+///
+/// Uses are claimed with a lightweight transaction so concurrent
+/// redemptions can't go over the code's `max_uses`.
+pub async fn redeem_invite(sess: &Session, code: &str, user_id: i64) -> Result<Redeemed> {
+    for _ in 0..MAX_REDEEM_ATTEMPTS {
+        let invite = match get_invite(sess, code).await? {
+            None => return Ok(Redeemed::Invalid),
+            Some(invite) => invite,
+        };
+
+        let remaining_secs = (*invite.expires_on - *Timestamp::now()) / 1000;
+        if remaining_secs <= 0 {
+            return Ok(Redeemed::Invalid)
+        }
+
+        if has_grant(sess, invite.room_id, user_id).await? {
+            return Ok(Redeemed::Granted(invite.room_id))
+        }
+
+        if let Some(max_uses) = invite.max_uses {
+            if invite.uses >= max_uses {
+                return Ok(Redeemed::Invalid)
+            }
+        }
+
+        let result = sess.query_prepared(
+            "UPDATE room_invites USING TTL ? SET uses = ? WHERE code = ? IF uses = ?;",
+            (remaining_secs as i32, invite.uses + 1, invite.code, invite.uses)
+        ).await?;
+
+        if db::was_applied(&result) {
+            add_grant(sess, invite.room_id, user_id, *invite.created_by).await?;
+            return Ok(Redeemed::Granted(invite.room_id))
+        }
+    }
+
+    Err(anyhow!("failed to redeem invite code after {} attempts", MAX_REDEEM_ATTEMPTS))
+}
+
+
+/// Grants the user access to an invite only room.
+pub async fn add_grant(sess: &Session, room_id: Uuid, user_id: i64, granted_by: i64) -> Result<()> {
+    sess.query_prepared(
+        "INSERT INTO room_grants (room_id, user_id, granted_by, granted_on) VALUES (?, ?, ?, ?);",
+        (room_id, user_id, granted_by, Timestamp::now())
+    ).await?;
+
+    Ok(())
+}
+
+/// Checks if the user has been granted access to the room.
+pub async fn has_grant(sess: &Session, room_id: Uuid, user_id: i64) -> Result<bool> {
+    let result = sess.query_prepared(
+        "SELECT true FROM room_grants WHERE room_id = ? AND user_id = ?;",
+        (room_id, user_id)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let has_grant = Option::flatten(
+        rows.into_typed::<(bool,)>()
+            .next()
+            .map(|v| v.ok().map(|v| v.0))
+    ).unwrap_or(false);
+
+    Ok(has_grant)
+}
+
+/// Removes all access grants for the room.
+pub async fn clear_grants(sess: &Session, room_id: Uuid) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM room_grants WHERE room_id = ?;",
+        (room_id,)
+    ).await?;
+
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+use uuid::Uuid;
+
+use crate::db::{self, Session};
+use crate::rooms::{browse, roles};
+use crate::rooms::models::{Room, RoomMember, RoomRole};
+use crate::rtc::events::{self, RoomUpdated};
+use crate::utils::{JsSafeBigInt, Timestamp};
+
+
+/// How long a member stays in a room without sending a heartbeat.
+///
+/// Clients are expected to heartbeat at roughly half this interval.
+pub const PRESENCE_TTL_SECS: i32 = 60;
+
+/// How many times a viewer count update is retried when it races another.
+const MAX_SYNC_ATTEMPTS: usize = 3;
+
+
+/// Gets when the user joined the room if they're currently a member of it.
+pub async fn get_member_joined_on(
+    sess: &Session,
+    room_id: Uuid,
+    user_id: i64,
+) -> Result<Option<Timestamp>> {
+    let result = sess.query_prepared(
+        "SELECT joined_on FROM room_members WHERE room_id = ? AND user_id = ?;",
+        (room_id, user_id)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let joined_on = match rows.into_typed::<(Timestamp,)>().next() {
+        None => return Ok(None),
+        Some(v) => v?.0,
+    };
+
+    Ok(Some(joined_on))
+}
+
+/// Checks if the user is currently present in the room.
+pub async fn is_room_member(sess: &Session, room_id: Uuid, user_id: i64) -> Result<bool> {
+    Ok(get_member_joined_on(sess, room_id, user_id).await?.is_some())
+}
+
+/// Adds the user to the room or refreshes their presence if they're
+/// already a member.
+pub async fn touch_member(
+    sess: &Session,
+    room_id: Uuid,
+    user_id: i64,
+    joined_on: Timestamp,
+) -> Result<()> {
+    sess.query_prepared(
+        "INSERT INTO room_members (room_id, user_id, joined_on) VALUES (?, ?, ?) USING TTL ?;",
+        (room_id, user_id, joined_on, PRESENCE_TTL_SECS)
+    ).await?;
+
+    Ok(())
+}
+
+/// Removes the user from the room.
+pub async fn remove_member(sess: &Session, room_id: Uuid, user_id: i64) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM room_members WHERE room_id = ? AND user_id = ?;",
+        (room_id, user_id)
+    ).await?;
+
+    Ok(())
+}
+
+/// Removes the user from the room and prevents them from accessing it
+/// again for a while.
+///
+/// Any role they held is taken away so they come back as a listener.
+/// Kicks expire after 10 minutes, see the `room_kicks` table.
+pub async fn kick_member(sess: &Session, room_id: Uuid, user_id: i64, kicked_by: i64) -> Result<()> {
+    remove_member(sess, room_id, user_id).await?;
+    roles::set_role(sess, room_id, user_id, RoomRole::Listener).await?;
+
+    sess.query_prepared(
+        "INSERT INTO room_kicks (room_id, user_id, kicked_by) VALUES (?, ?, ?);",
+        (room_id, user_id, kicked_by)
+    ).await?;
+
+    Ok(())
+}
+
+/// Checks if the user has recently been kicked from the room.
+pub async fn is_kicked(sess: &Session, room_id: Uuid, user_id: i64) -> Result<bool> {
+    let result = sess.query_prepared(
+        "SELECT true FROM room_kicks WHERE room_id = ? AND user_id = ?;",
+        (room_id, user_id)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let is_kicked = Option::flatten(
+        rows.into_typed::<(bool,)>()
+            .next()
+            .map(|v| v.ok().map(|v| v.0))
+    ).unwrap_or(false);
+
+    Ok(is_kicked)
+}
+
+/// Removes every member and any kicks from the room.
+pub async fn clear_members(sess: &Session, room_id: Uuid) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM room_members WHERE room_id = ?;",
+        (room_id,)
+    ).await?;
+
+    sess.query_prepared(
+        "DELETE FROM room_kicks WHERE room_id = ?;",
+        (room_id,)
+    ).await?;
+
+    Ok(())
+}
+
+/// Gets the ids of all members currently present in the room along with
+/// when they joined, earliest first.
+pub async fn get_present_members(sess: &Session, room_id: Uuid) -> Result<Vec<(i64, Timestamp)>> {
+    let result = sess.query_prepared(
+        "SELECT user_id, joined_on FROM room_members WHERE room_id = ?;",
+        (room_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let mut members: Vec<(i64, Timestamp)> = rows.into_typed::<(i64, Timestamp)>()
+        .filter_map(|v| v.ok())
+        .collect();
+
+    members.sort_by_key(|(_, joined_on)| *joined_on);
+
+    Ok(members)
+}
+
+/// Gets all members currently present in the room along with their
+/// user info and role.
+pub async fn get_room_members(sess: &Session, room: &Room) -> Result<Vec<RoomMember>> {
+    let members = get_present_members(sess, room.id).await?;
+
+    if members.is_empty() {
+        return Ok(vec![])
+    }
+
+    let ids: Vec<i64> = members.iter()
+        .map(|(id, _)| *id)
+        .collect();
+
+    let mut users: HashMap<i64, (String, Option<String>)> = HashMap::new();
+    for chunk in ids.chunks(db::MAX_IN_VALUES) {
+        let result = sess.query_prepared(
+            "SELECT id, username, avatar FROM users WHERE id IN ?;",
+            (chunk.to_vec(),)
+        ).await?;
+
+        let rows = result.rows
+            .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+        users.extend(
+            rows.into_typed::<(i64, String, Option<String>)>()
+                .filter_map(|v| v.ok())
+                .map(|v| (v.0, (v.1, v.2)))
+        );
+    }
+
+    let co_hosts = roles::get_co_hosts(sess, room.id).await?;
+
+    let members = members.into_iter()
+        .filter_map(|(id, joined_on)| {
+            let (username, avatar) = users.remove(&id)?;
+
+            let role = if id == *room.owner_id {
+                RoomRole::Owner
+            } else if co_hosts.contains(&id) {
+                RoomRole::CoHost
+            } else {
+                RoomRole::Listener
+            };
+
+            Some(RoomMember {
+                id: JsSafeBigInt(id),
+                username,
+                avatar,
+                joined_on,
+                role,
+            })
+        })
+        .collect();
+
+    Ok(members)
+}
+
+/// Recounts the members present in the room and updates the room's
+/// viewer count to match.
+///
+/// Presence rows expire on their own so this is what keeps the count
+/// in line with members that stopped sending heartbeats. The count is
+/// only changed from the value it was read as, so of several concurrent
+/// syncs only one moves the room in the viewer listing.
+pub async fn sync_viewer_count(sess: &Session, room: &mut Room) -> Result<()> {
+    let result = sess.query_prepared(
+        "SELECT COUNT(*) FROM room_members WHERE room_id = ?;",
+        (room.id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let viewers = match rows.into_typed::<(i64,)>().next() {
+        None => 0,
+        Some(v) => v?.0 as i32,
+    };
+
+    for _ in 0..MAX_SYNC_ATTEMPTS {
+        if viewers == room.viewers {
+            return Ok(())
+        }
+
+        let result = sess.query_prepared(
+            "UPDATE active_rooms SET viewers = ? WHERE id = ? IF viewers = ?;",
+            (viewers, room.id, room.viewers)
+        ).await?;
+
+        if !db::was_applied(&result) {
+            room.viewers = match get_viewer_count(sess, room.id).await? {
+                None => return Ok(()),
+                Some(viewers) => viewers,
+            };
+            continue
+        }
+
+        if room.is_listed() {
+            browse::set_room_viewers(sess, room, viewers).await?;
+        }
+
+        room.viewers = viewers;
+        events::emit(room.id, RoomUpdated { room: room.clone() }).await;
+
+        return Ok(())
+    }
+
+    Err(anyhow!("failed to sync viewer count of {} after {} attempts", room.id, MAX_SYNC_ATTEMPTS))
+}
+
+/// Gets the room's stored viewer count, `None` if the room has closed.
+async fn get_viewer_count(sess: &Session, room_id: Uuid) -> Result<Option<i32>> {
+    let result = sess.query_prepared(
+        "SELECT viewers FROM active_rooms WHERE id = ?;",
+        (room_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let viewers = rows.into_typed::<(Option<i32>,)>()
+        .next()
+        .transpose()?
+        .map(|v| v.0.unwrap_or(0));
+
+    Ok(viewers)
+}
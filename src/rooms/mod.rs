@@ -4,16 +4,30 @@ use poem::Result;
 use poem_openapi::payload::Json;
 use poem_openapi::{Object, OpenApi};
 use poem_openapi::param::Query;
+use poem_openapi::types::ToJSON;
 use scylla::IntoTypedRows;
+use serde_json::{json, Value};
 use uuid::Uuid;
 
-use crate::utils::{JsonResponse, SuperUserBearer, TokenBearer};
+use crate::utils::{JsSafeBigInt, JsonResponse, RoomsControlBearer, SuperUserBearer, Timestamp, UserBearer};
 use crate::ApiTags;
+use crate::admin::audit::{self, AuditAction, AuditRecord, TargetKind};
+use crate::admin::roles::Permission;
 use crate::db::Session;
-use crate::rooms::models::Room;
-use crate::users::{room_info, user_info};
+use crate::notifications::system;
+use crate::rooms::playback::Skip;
+use crate::rooms::models::{GuildVisibility, Room, RoomInvite, RoomMember, RoomPage, RoomRole};
+use crate::rtc::events::{self, CloseReason, MemberJoined, MemberLeft, PlaylistSelected, RoomClosed};
+use crate::users::{notifications, room_info, user_info};
+use crate::users::notifications::{ActionKind, Icons, NotificationAction, NotificationContent};
 
 pub mod models;
+pub mod members;
+pub mod access;
+pub mod browse;
+mod invites;
+mod playback;
+mod roles;
 
 
 #[derive(Object, Debug)]
@@ -29,6 +43,11 @@ pub struct RoomCreationPayload {
     #[oai(validator(minimum(value = "0")))]
     guild_id: Option<i64>,
 
+    /// Who in the guild can see the room, defaults to all guild members.
+    ///
+    /// This only applies if the room belongs to a guild.
+    guild_visibility: Option<GuildVisibility>,
+
     #[oai(validator(max_length = 256, pattern=r"https://i\.imgur\.com/[0-9a-z]+\.jpeg|https://i\.imgur\.com/[0-9a-z]+\.png|https://i\.imgur\.com/[0-9a-z]+\.webp"))]
     banner: Option<String>,
 
@@ -40,6 +59,33 @@ pub struct RoomCreationPayload {
 }
 
 
+#[derive(Object, Debug)]
+pub struct InviteCreationPayload {
+    /// How long the invite code is valid for in seconds, defaults to 1 day.
+    #[oai(default = "default_invite_expiry", validator(minimum(value = "60"), maximum(value = "604800")))]
+    expires_in: u32,
+
+    /// How many times the code can be redeemed, unlimited if not set.
+    #[oai(validator(minimum(value = "1"), maximum(value = "1000")))]
+    max_uses: Option<i32>,
+}
+
+#[derive(Object, Debug)]
+pub struct UserInvitePayload {
+    /// The Discord ids of the users to invite.
+    #[oai(validator(max_items = 25, min_items = 1))]
+    user_ids: Vec<i64>,
+}
+
+
+fn default_page_size() -> u32 {
+    20
+}
+
+fn default_invite_expiry() -> u32 {
+    86400
+}
+
 
 pub struct RoomsApi;
 
@@ -48,43 +94,86 @@ impl RoomsApi {
     /// Get Top Public Rooms
     ///
     /// Gets the top public rooms which are sorted by viewing count.
+    ///
+    /// Pages are fetched by passing the `next_cursor` of the previous page
+    /// as the `cursor`, omitting it returns the first page.
     #[oai(path = "/rooms/browse/top", method = "get", tag = "ApiTags::Rooms")]
     pub async fn get_top_rooms(
         &self,
-        _page: Query<u32>,
-        _session: Data<&Session>,
-    ) -> Result<JsonResponse<Vec<Room>>> {
-        todo!()
+        cursor: Query<Option<String>>,
+        #[oai(default = "default_page_size", validator(minimum(value = "1"), maximum(value = "50")))]
+        limit: Query<u32>,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<RoomPage>> {
+        let cursor = match cursor.0.map(|v| v.parse::<browse::Cursor>()).transpose() {
+            Err(_) => return Ok(JsonResponse::bad_request("Invalid cursor.")),
+            Ok(cursor) => cursor,
+        };
+
+        let (rooms, next_cursor) = browse::get_top_rooms(&session, cursor, limit.0 as i32).await?;
+
+        Ok(JsonResponse::ok(RoomPage {
+            rooms,
+            next_cursor: next_cursor.map(|v| v.to_string()),
+        }))
     }
 
     /// Get New Public Rooms
     ///
     /// Gets the newest public rooms which are sorted by creation time.
+    ///
+    /// Pages are fetched by passing the `next_cursor` of the previous page
+    /// as the `cursor`, omitting it returns the first page.
     #[oai(path = "/rooms/browse/new", method = "get", tag = "ApiTags::Rooms")]
     pub async fn get_new_rooms(
         &self,
-        _page: Query<u32>,
-        _session: Data<&Session>,
-    ) -> Result<JsonResponse<Vec<Room>>> {
-        todo!()
+        cursor: Query<Option<String>>,
+        #[oai(default = "default_page_size", validator(minimum(value = "1"), maximum(value = "50")))]
+        limit: Query<u32>,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<RoomPage>> {
+        let cursor = match cursor.0.map(|v| v.parse::<browse::Cursor>()).transpose() {
+            Err(_) => return Ok(JsonResponse::bad_request("Invalid cursor.")),
+            Ok(cursor) => cursor,
+        };
+
+        let (rooms, next_cursor) = browse::get_new_rooms(&session, cursor, limit.0 as i32).await?;
+
+        Ok(JsonResponse::ok(RoomPage {
+            rooms,
+            next_cursor: next_cursor.map(|v| v.to_string()),
+        }))
     }
 
     /// Superuser Close Room
     ///
-    /// Forcefully closes a room by a superuser.
+    /// Forcefully closes a room by a superuser, letting its owner know.
     #[oai(path = "/rooms/close", method = "delete", tag = "ApiTags::Rooms")]
     pub async fn close_room(
         &self,
         id: Query<Uuid>,
-        _token: SuperUserBearer,
+        superuser: SuperUserBearer,
         session: Data<&Session>,
     ) -> Result<JsonResponse<Room>> {
+        if !superuser.0.has(Permission::CloseRooms) {
+            return Ok(JsonResponse::forbidden())
+        }
+
         let room = match get_room_by_id(&session, id.0).await? {
             None => return Ok(JsonResponse::bad_request("User already has an active room")),
             Some(r) => r,
         };
 
-        set_room_inactive(&session, room.clone()).await?;
+        set_room_inactive(&session, room.clone(), CloseReason::Superuser).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: superuser.0.user_id,
+            action: AuditAction::RoomClosed,
+            target_kind: TargetKind::Room,
+            target_id: room.id.to_string(),
+            before: Some(room.to_json()),
+            after: None,
+        }).await;
+        system::room_force_closed(&session, &room).await;
 
         Ok(JsonResponse::ok(room))
     }
@@ -92,28 +181,38 @@ impl RoomsApi {
     /// Create Room
     ///
     /// Creates a new room for a given user
+    ///
+    /// Guild rooms can only be created by members of the guild, rooms
+    /// visible to guild managers only can only be created by managers.
     #[oai(path = "/rooms", method = "post", tag = "ApiTags::Rooms")]
     pub async fn create_room(
         &self,
         payload: Json<RoomCreationPayload>,
-        token: TokenBearer,
+        token: RoomsControlBearer,
         session: Data<&Session>,
     ) -> Result<JsonResponse<Room>> {
-        let user_id = match user_info::get_user_id_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
-        };
+        let user = token.0.user;
 
-        let active_room = Option::flatten(room_info::get_active_room_for_token(
-            &session,
-            &token.0.token,
-        ).await?);
+        let active_room = room_info::get_active_room_for_user_id(&session, *user.id).await?;
 
         if active_room.is_some() {
             return Ok(JsonResponse::bad_request("User already has an active room"))
         }
 
-        let room = create_room_from_payload(&session, user_id, payload.0).await?;
+        if let Some(guild_id) = payload.guild_id {
+            let is_manager = match user.access_servers.get(&guild_id) {
+                None => return Ok(JsonResponse::bad_request("User is not a member of this guild.")),
+                Some(is_manager) => *is_manager,
+            };
+
+            if (payload.guild_visibility == Some(GuildVisibility::Managers)) & !is_manager {
+                return Ok(JsonResponse::bad_request(
+                    "Only guild managers can create rooms visible to managers only."
+                ))
+            }
+        }
+
+        let room = create_room_from_payload(&session, *user.id, payload.0).await?;
         Ok(JsonResponse::ok(room))
     }
 
@@ -123,42 +222,687 @@ impl RoomsApi {
     ///
     /// This will return the room info if any of the following conditions are met:
     /// - The user owns the room.
-    /// - The room is invite only.
-    /// - The room is public.
+    /// - The room belongs to a guild the user can manage.
+    /// - The room is invite only and the user has been invited.
+    /// - The room is public and not invite only.
     /// - The room is private but allows guild members to join and the user
-    ///   requesting the room is a member of said guild.
+    ///   requesting the room is a member of said guild, or a manager if the
+    ///   room is only visible to managers.
     #[oai(path = "/rooms", method = "get", tag = "ApiTags::Rooms")]
     pub async fn get_room(
         &self,
         id: Query<Uuid>,
-        token: TokenBearer,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let user = token.0.user;
+
+        let room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        if access::has_room_access(&session, &room, &user).await? {
+            Ok(JsonResponse::ok(room))
+        } else {
+            Ok(JsonResponse::forbidden())
+        }
+    }
+
+    /// Join Room
+    ///
+    /// Joins the room with the given ID, returning the room with its
+    /// updated viewer count.
+    ///
+    /// Membership expires unless it's kept alive by heartbeats, see
+    /// `Room Heartbeat`.
+    #[oai(path = "/rooms/join", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn join_room(
+        &self,
+        id: Query<Uuid>,
+        token: UserBearer,
         session: Data<&Session>,
     ) -> Result<JsonResponse<Room>> {
-        let user = match user_info::get_user_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(v) => v,
+        let user = token.0.user;
+
+        let mut room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
         };
 
+        if !access::has_room_access(&session, &room, &user).await? {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let existing = members::get_member_joined_on(&session, room.id, *user.id).await?;
+        let joined_on = existing.unwrap_or_else(Timestamp::now);
+
+        members::touch_member(&session, room.id, *user.id, joined_on).await?;
+
+        if existing.is_none() {
+            let member = RoomMember {
+                id: user.id,
+                role: roles::get_role(&session, &room, *user.id).await?,
+                username: user.username,
+                avatar: user.avatar,
+                joined_on,
+            };
+
+            events::emit(room.id, MemberJoined { member }).await;
+        }
+
+        members::sync_viewer_count(&session, &mut room).await?;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Leave Room
+    ///
+    /// Leaves the room with the given ID.
+    ///
+    /// If the owner leaves, the room is handed over to the longest present
+    /// co-host, or failing that the longest present listener.
+    #[oai(path = "/rooms/leave", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn leave_room(
+        &self,
+        id: Query<Uuid>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+
+        if !members::is_room_member(&session, id.0, user_id).await? {
+            return Ok(JsonResponse::ok(Value::Null))
+        }
+
+        members::remove_member(&session, id.0, user_id).await?;
+        events::emit(id.0, MemberLeft { user_id: JsSafeBigInt(user_id), kicked: false }).await;
+
+        if let Some(mut room) = get_room_by_id(&session, id.0).await? {
+            roles::hand_off_if_owner_absent(&session, &mut room).await?;
+            members::sync_viewer_count(&session, &mut room).await?;
+        }
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Room Heartbeat
+    ///
+    /// Keeps the user's membership of the room with the given ID alive,
+    /// returning the room with its updated viewer count.
+    ///
+    /// Members that haven't sent a heartbeat in the last 60 seconds are
+    /// removed from the room and must join again. If the owner has been
+    /// removed this way the room is handed over as if they had left.
+    #[oai(path = "/rooms/heartbeat", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn room_heartbeat(
+        &self,
+        id: Query<Uuid>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let user_id = token.0.id;
+
+        let mut room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        let joined_on = match members::get_member_joined_on(&session, room.id, user_id).await? {
+            None => return Ok(JsonResponse::bad_request("User is not a member of this room.")),
+            Some(joined_on) => joined_on,
+        };
+
+        members::touch_member(&session, room.id, user_id, joined_on).await?;
+        roles::hand_off_if_owner_absent(&session, &mut room).await?;
+        members::sync_viewer_count(&session, &mut room).await?;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Get Room Members
+    ///
+    /// Get the users currently present in the room with the given ID.
+    #[oai(path = "/rooms/members", method = "get", tag = "ApiTags::Rooms")]
+    pub async fn get_room_members(
+        &self,
+        id: Query<Uuid>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Vec<RoomMember>>> {
+        let user = token.0.user;
+
         let room = match get_room_by_id(&session, id.0).await? {
             None => return Ok(JsonResponse::bad_request("Room does not exist.")),
             Some(room) => room,
         };
 
-        if room.is_public | room.invite_only | (room.owner_id == user.id) {
-            return Ok(JsonResponse::ok(room))
+        if !access::has_room_access(&session, &room, &user).await? {
+            return Ok(JsonResponse::forbidden())
         }
 
-        let guild_id = match room.guild_id {
-            None => return Ok(JsonResponse::forbidden()),
-            Some(guild_id) => guild_id,
+        let members = members::get_room_members(&session, &room).await?;
+
+        Ok(JsonResponse::ok(members))
+    }
+
+    /// Close Room
+    ///
+    /// Closes the room with the given ID.
+    ///
+    /// This can be done by the room owner or, for guild rooms, by any
+    /// manager of the room's guild.
+    #[oai(path = "/rooms", method = "delete", tag = "ApiTags::Rooms")]
+    pub async fn moderator_close_room(
+        &self,
+        id: Query<Uuid>,
+        token: RoomsControlBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let user = token.0.user;
+
+        let room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
         };
 
-        if user.access_servers.contains_key(&guild_id) {
-           Ok(JsonResponse::ok(room))
+        if !access::can_moderate_room(&room, &user) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let reason = if room.owner_id == user.id {
+            CloseReason::Owner
         } else {
-            Ok(JsonResponse::forbidden())
+            CloseReason::Moderator
+        };
+
+        set_room_inactive(&session, room.clone(), reason).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: Some(*user.id),
+            action: AuditAction::RoomClosed,
+            target_kind: TargetKind::Room,
+            target_id: room.id.to_string(),
+            before: Some(room.to_json()),
+            after: None,
+        }).await;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Kick Room Member
+    ///
+    /// Removes the given user from the room with the given ID, they will be
+    /// unable to rejoin the room for the next 10 minutes.
+    ///
+    /// This can be done by the room owner or, for guild rooms, by any
+    /// manager of the room's guild.
+    #[oai(path = "/rooms/kick", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn kick_room_member(
+        &self,
+        id: Query<Uuid>,
+        user_id: Query<i64>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let user = token.0.user;
+
+        let mut room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        if !access::can_moderate_room(&room, &user) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        if *room.owner_id == user_id.0 {
+            return Ok(JsonResponse::bad_request("The room owner cannot be kicked."))
+        }
+
+        members::kick_member(&session, room.id, user_id.0, *user.id).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: Some(*user.id),
+            action: AuditAction::MemberKicked,
+            target_kind: TargetKind::User,
+            target_id: user_id.0.to_string(),
+            before: Some(json!({ "room_id": room.id })),
+            after: None,
+        }).await;
+        events::emit(room.id, MemberLeft { user_id: JsSafeBigInt(user_id.0), kicked: true }).await;
+        members::sync_viewer_count(&session, &mut room).await?;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Set Room Member Role
+    ///
+    /// Promotes or demotes a user within the user's room with the given ID.
+    ///
+    /// Co-hosts can control playback alongside the owner, ownership itself
+    /// can only be changed with `Transfer Room Ownership`.
+    #[oai(path = "/rooms/roles", method = "put", tag = "ApiTags::Rooms")]
+    pub async fn set_room_role(
+        &self,
+        id: Query<Uuid>,
+        user_id: Query<i64>,
+        role: Query<RoomRole>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Value>> {
+        let owner_id = token.0.id;
+
+        let room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        if !access::has_room_role(&session, &room, owner_id, RoomRole::Owner).await? {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        if role.0 == RoomRole::Owner {
+            return Ok(JsonResponse::bad_request("Room ownership must be transferred."))
+        }
+
+        if *room.owner_id == user_id.0 {
+            return Ok(JsonResponse::bad_request("The room owner's role cannot be changed."))
+        }
+
+        if user_info::get_user_from_id(&session, user_id.0).await?.is_none() {
+            return Ok(JsonResponse::bad_request("This user does not exist."))
+        }
+
+        let previous = roles::get_role(&session, &room, user_id.0).await?;
+        roles::set_role(&session, room.id, user_id.0, role.0).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: Some(owner_id),
+            action: AuditAction::RoomRoleChanged,
+            target_kind: TargetKind::User,
+            target_id: user_id.0.to_string(),
+            before: Some(json!({ "room_id": room.id, "role": previous.to_string() })),
+            after: Some(json!({ "room_id": room.id, "role": role.0.to_string() })),
+        }).await;
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Transfer Room Ownership
+    ///
+    /// Transfers ownership of the user's room with the given ID to another
+    /// member of the room, the previous owner becomes a co-host.
+    ///
+    /// The new owner must currently be present in the room and not own
+    /// an active room of their own.
+    #[oai(path = "/rooms/transfer", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn transfer_room_ownership(
+        &self,
+        id: Query<Uuid>,
+        user_id: Query<i64>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let owner_id = token.0.id;
+
+        let mut room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        if !access::has_room_role(&session, &room, owner_id, RoomRole::Owner).await? {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        if *room.owner_id == user_id.0 {
+            return Ok(JsonResponse::bad_request("User already owns this room."))
+        }
+
+        if !members::is_room_member(&session, room.id, user_id.0).await? {
+            return Ok(JsonResponse::bad_request("User is not a member of this room."))
+        }
+
+        if room_info::get_active_room_for_user_id(&session, user_id.0).await?.is_some() {
+            return Ok(JsonResponse::bad_request("User already has an active room"))
+        }
+
+        let before = room.to_json();
+        if !roles::transfer_ownership(&session, &mut room, user_id.0).await? {
+            return Ok(JsonResponse::bad_request("Room ownership has changed, try again."))
+        }
+
+        audit::record(&session, AuditRecord {
+            actor_id: Some(owner_id),
+            action: AuditAction::OwnershipTransferred,
+            target_kind: TargetKind::Room,
+            target_id: room.id.to_string(),
+            before: Some(before),
+            after: Some(room.to_json()),
+        }).await;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Play Room Playback
+    ///
+    /// Resumes playback of the current entry in the room with the given ID,
+    /// optionally changing the playback rate.
+    #[oai(path = "/rooms/playback/play", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn play_room(
+        &self,
+        id: Query<Uuid>,
+        #[oai(validator(minimum(value = "0.25"), maximum(value = "4")))]
+        rate: Query<Option<f32>>,
+        token: RoomsControlBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let mut room = match get_playback_room(&session, token.0.id, id.0).await? {
+            Err(resp) => return Ok(resp),
+            Ok(room) => room,
+        };
+
+        if room.playing_now.is_none() {
+            return Ok(JsonResponse::bad_request("Nothing is currently playing."))
+        }
+
+        playback::play(&session, &mut room, rate.0).await?;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Pause Room Playback
+    ///
+    /// Pauses playback of the current entry in the room with the given ID.
+    #[oai(path = "/rooms/playback/pause", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn pause_room(
+        &self,
+        id: Query<Uuid>,
+        token: RoomsControlBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let mut room = match get_playback_room(&session, token.0.id, id.0).await? {
+            Err(resp) => return Ok(resp),
+            Ok(room) => room,
+        };
+
+        playback::pause(&session, &mut room).await?;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Seek Room Playback
+    ///
+    /// Moves playback of the current entry in the room with the given ID to
+    /// the given position in milliseconds, at most 24 hours.
+    #[oai(path = "/rooms/playback/seek", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn seek_room(
+        &self,
+        id: Query<Uuid>,
+        #[oai(validator(minimum(value = "0"), maximum(value = "86400000")))]
+        position: Query<i64>,
+        token: RoomsControlBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let mut room = match get_playback_room(&session, token.0.id, id.0).await? {
+            Err(resp) => return Ok(resp),
+            Ok(room) => room,
+        };
+
+        if room.playing_now.is_none() {
+            return Ok(JsonResponse::bad_request("Nothing is currently playing."))
+        }
+
+        playback::seek(&session, &mut room, position.0).await?;
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Next Room Entry
+    ///
+    /// Starts playing the next entry of the active playlist in the room with
+    /// the given ID, or the first entry if nothing is playing.
+    #[oai(path = "/rooms/playback/next", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn next_room_entry(
+        &self,
+        id: Query<Uuid>,
+        token: RoomsControlBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let mut room = match get_playback_room(&session, token.0.id, id.0).await? {
+            Err(resp) => return Ok(resp),
+            Ok(room) => room,
+        };
+
+        if !playback::skip(&session, &mut room, Skip::Next).await? {
+            return Ok(JsonResponse::bad_request("There is no next entry to play."))
         }
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Previous Room Entry
+    ///
+    /// Starts playing the previous entry of the active playlist in the room
+    /// with the given ID.
+    #[oai(path = "/rooms/playback/previous", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn previous_room_entry(
+        &self,
+        id: Query<Uuid>,
+        token: RoomsControlBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let mut room = match get_playback_room(&session, token.0.id, id.0).await? {
+            Err(resp) => return Ok(resp),
+            Ok(room) => room,
+        };
+
+        if !playback::skip(&session, &mut room, Skip::Previous).await? {
+            return Ok(JsonResponse::bad_request("There is no previous entry to play."))
+        }
+
+        Ok(JsonResponse::ok(room))
+    }
+
+    /// Room Entry Finished
+    ///
+    /// Reports that the given entry has finished playing in the room with
+    /// the given ID, automatically advancing to the next entry of the
+    /// active playlist.
+    ///
+    /// Reports for an entry that is no longer playing are ignored so every
+    /// host client can safely report the same entry.
+    #[oai(path = "/rooms/playback/finished", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn finish_room_entry(
+        &self,
+        id: Query<Uuid>,
+        entry_id: Query<Uuid>,
+        token: RoomsControlBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let mut room = match get_playback_room(&session, token.0.id, id.0).await? {
+            Err(resp) => return Ok(resp),
+            Ok(room) => room,
+        };
+
+        playback::entry_finished(&session, &mut room, entry_id.0).await?;
+
+        Ok(JsonResponse::ok(room))
     }
+
+    /// Create Room Invite
+    ///
+    /// Creates an invite code for the user's room with the given ID which
+    /// other users can redeem to gain access to the room.
+    #[oai(path = "/rooms/invites", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn create_room_invite(
+        &self,
+        id: Query<Uuid>,
+        payload: Json<InviteCreationPayload>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<RoomInvite>> {
+        let user_id = token.0.id;
+
+        let room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        if *room.owner_id != user_id {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let invite = invites::create_invite(
+            &session,
+            room.id,
+            user_id,
+            payload.0.max_uses,
+            payload.0.expires_in,
+        ).await?;
+
+        Ok(JsonResponse::ok(invite))
+    }
+
+    /// Revoke Room Invite
+    ///
+    /// Revokes an invite code so it can no longer be redeemed, only users
+    /// who can currently moderate the invite's room can do this.
+    ///
+    /// Users who have already redeemed the code keep their access.
+    #[oai(path = "/rooms/invites", method = "delete", tag = "ApiTags::Rooms")]
+    pub async fn revoke_room_invite(
+        &self,
+        code: Query<String>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+        let user = token.0.user;
+
+        let invite = match invites::get_invite(&session, &code.0).await? {
+            None => return Ok(JsonResponse::bad_request("Invite does not exist.")),
+            Some(invite) => invite,
+        };
+
+        let room = match get_room_by_id(&session, invite.room_id).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        if !access::can_moderate_room(&room, &user) {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        invites::remove_invite(&session, &invite.code).await?;
+        audit::record(&session, AuditRecord {
+            actor_id: Some(user_id),
+            action: AuditAction::InviteRevoked,
+            target_kind: TargetKind::Invite,
+            target_id: invite.code.clone(),
+            before: Some(invite.to_json()),
+            after: None,
+        }).await;
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Redeem Room Invite
+    ///
+    /// Redeems an invite code, granting the user access to the room it
+    /// belongs to and returning the room.
+    #[oai(path = "/rooms/invites/redeem", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn redeem_room_invite(
+        &self,
+        code: Query<String>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Room>> {
+        let user_id = token.0.id;
+
+        let room_id = match invites::redeem_invite(&session, &code.0, user_id).await? {
+            invites::Redeemed::Invalid => return Ok(JsonResponse::bad_request(
+                "Invite code is invalid, expired or has been used up."
+            )),
+            invites::Redeemed::Granted(room_id) => room_id,
+        };
+
+        match get_room_by_id(&session, room_id).await? {
+            None => Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => Ok(JsonResponse::ok(room)),
+        }
+    }
+
+    /// Invite Users To Room
+    ///
+    /// Directly grants the given users access to the user's room with the
+    /// given ID and sends each of them a notification.
+    ///
+    /// Returns the ids of the users who were invited, ids which don't
+    /// belong to a known user are skipped.
+    #[oai(path = "/rooms/invites/users", method = "post", tag = "ApiTags::Rooms")]
+    pub async fn invite_users_to_room(
+        &self,
+        id: Query<Uuid>,
+        payload: Json<UserInvitePayload>,
+        token: UserBearer,
+        session: Data<&Session>,
+    ) -> Result<JsonResponse<Vec<JsSafeBigInt>>> {
+        let user = token.0.user;
+
+        let room = match get_room_by_id(&session, id.0).await? {
+            None => return Ok(JsonResponse::bad_request("Room does not exist.")),
+            Some(room) => room,
+        };
+
+        if room.owner_id != user.id {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let mut invited = vec![];
+        for user_id in payload.0.user_ids {
+            if user_info::get_user_from_id(&session, user_id).await?.is_none() {
+                continue;
+            }
+
+            invites::add_grant(&session, room.id, user_id, *user.id).await?;
+            let content = NotificationContent {
+                title: "You've been invited to a room".to_string(),
+                description: Some(format!("{} invited you to join {}.", &user.username, &room.title)),
+                icon: Some(Icons::Info),
+                action: Some(NotificationAction {
+                    kind: ActionKind::Room,
+                    target: room.id.to_string(),
+                }),
+                expires_at: None,
+            };
+
+            notifications::insert_notification(&session, user_id, content).await?;
+
+            invited.push(JsSafeBigInt(user_id));
+        }
+
+        Ok(JsonResponse::ok(invited))
+    }
+}
+
+
+/// Gets the room with the given id if the user is allowed to control its
+/// playback, otherwise the response to return.
+///
+/// Only co-hosts who are currently in the room can control it.
+async fn get_playback_room(
+    sess: &Session,
+    user_id: i64,
+    id: Uuid,
+) -> anyhow::Result<std::result::Result<Room, JsonResponse<Room>>> {
+    let room = match get_room_by_id(sess, id).await? {
+        None => return Ok(Err(JsonResponse::bad_request("Room does not exist."))),
+        Some(room) => room,
+    };
+
+    if !access::is_present_with_role(sess, &room, user_id, RoomRole::CoHost).await? {
+        return Ok(Err(JsonResponse::forbidden()))
+    }
+
+    Ok(Ok(room))
 }
 
 
@@ -173,39 +917,58 @@ async fn create_room_from_payload(
         None
     };
 
+    let guild_visibility = payload.guild_id
+        .map(|_| payload.guild_visibility.unwrap_or(GuildVisibility::Members).to_string());
+
+    let room_id = Uuid::new_v4();
+    let created_on = Timestamp::now();
+    members::touch_member(sess, room_id, user_id, created_on).await?;
+
     sess.query(
         r#"
-        INSERT INTO rooms (
+        INSERT INTO active_rooms (
             id,
             owner_id,
             active_playlist,
             banner,
+            created_on,
             guild_id,
+            guild_visibility,
             invite_only,
             is_public,
+            playback_paused,
+            playback_position,
+            playback_rate,
+            playback_started_on,
             playing_now,
             title,
-            topic
-        ) VALUES (uuid(), ?, ?, ?, ?, ?, ?, null, ?, ?);
+            topic,
+            viewers
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, true, 0, 1.0, null, null, ?, ?, 1);
         "#,
         (
-            user_id, payload.active_playlist, banner,
-            payload.guild_id, payload.invite_only, payload.is_public,
-            payload.title, payload.topic,
+            room_id, user_id, payload.active_playlist, banner,
+            created_on, payload.guild_id, guild_visibility,
+            payload.invite_only, payload.is_public, payload.title,
+            payload.topic,
             )
     ).await?;
 
-    let room = room_info::get_active_room_for_user_id(sess, user_id)
+    let room = get_room_by_id(sess, room_id)
         .await?
         .ok_or_else(|| anyhow!("expected room in database after creation"))?;
 
+    if room.is_listed() {
+        browse::add_room(sess, &room).await?;
+    }
+
     Ok(room)
 }
 
 
 pub async fn get_room_by_id(sess: &Session, id: Uuid) -> anyhow::Result<Option<Room>> {
     let result = sess.query_prepared(
-        "SELECT * FROM rooms WHERE id = ?;",
+        "SELECT * FROM active_rooms WHERE id = ?;",
         (id,)
     ).await?;
 
@@ -221,12 +984,19 @@ pub async fn get_room_by_id(sess: &Session, id: Uuid) -> anyhow::Result<Option<R
     Ok(Some(room))
 }
 
-pub async fn set_room_inactive(sess: &Session, room: Room) -> anyhow::Result<()> {
+pub async fn set_room_inactive(sess: &Session, room: Room, reason: CloseReason) -> anyhow::Result<()> {
     sess.query_prepared(
-        "DELETE FROM rooms WHERE id = ?;",
+        "DELETE FROM active_rooms WHERE id = ?;",
         (room.id,)
     ).await?;
 
+    browse::remove_room(sess, &room).await?;
+    members::clear_members(sess, room.id).await?;
+    invites::clear_grants(sess, room.id).await?;
+    roles::clear_roles(sess, room.id).await?;
+
+    events::emit(room.id, RoomClosed { reason }).await;
+
     sess.query_prepared(
         r#"
         INSERT INTO room_archive (
@@ -259,18 +1029,15 @@ pub async fn set_room_inactive(sess: &Session, room: Room) -> anyhow::Result<()>
 
 pub async fn set_room_playlist(sess: &Session, id: Uuid, playlist_id: Uuid) -> anyhow::Result<()> {
     sess.query_prepared(
-        "UPDATE rooms SET active_playlist = ? WHERE id = ?;",
+        "UPDATE active_rooms SET active_playlist = ? WHERE id = ?;",
         (playlist_id, id)
     ).await?;
 
+    events::emit(id, PlaylistSelected { playlist_id }).await;
+
     Ok(())
 }
 
-pub async fn set_room_currently_playing(sess: &Session, id: Uuid, entry_id: Uuid) -> anyhow::Result<()> {
-    sess.query_prepared(
-        "UPDATE rooms SET playing_now = ? WHERE id = ?;",
-        (entry_id, id)
-    ).await?;
-
-    Ok(())
-}
\ No newline at end of file
+pub async fn set_room_currently_playing(sess: &Session, room: &mut Room, entry_id: Uuid) -> anyhow::Result<()> {
+    playback::set_entry(sess, room, entry_id).await
+}
@@ -1,22 +1,189 @@
-use poem_openapi::Object;
+use std::str::FromStr;
+use poem_openapi::{Enum, Object};
 use uuid::Uuid;
 use scylla::FromRow;
+use scylla::cql_to_rust::{FromCqlVal, FromCqlValError, FromRowError};
+use scylla::frame::response::result::{CqlValue, Row};
+use strum::{Display, EnumString};
 
-use crate::utils::JsSafeBigInt;
+use crate::utils::{JsSafeBigInt, Timestamp};
 
 
-#[derive(Object, FromRow, Clone)]
+/// Who in the room's guild is allowed to see and join a guild room.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+#[oai(rename_all = "lowercase")]
+pub enum GuildVisibility {
+    /// Any member of the guild.
+    Members,
+
+    /// Only members who can manage the guild.
+    Managers,
+}
+
+impl FromCqlVal<CqlValue> for GuildVisibility {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
+        cql_val.into_string()
+            .and_then(|v| Self::from_str(&v).ok())
+            .ok_or(FromCqlValError::BadCqlType)
+    }
+}
+
+
+/// A user's role within a room.
+///
+/// Roles are ordered so a role grants everything the roles below it do.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+#[oai(rename_all = "lowercase")]
+pub enum RoomRole {
+    /// Can listen along but not control playback.
+    Listener,
+
+    /// Can control playback alongside the owner.
+    CoHost,
+
+    /// Owns the room, there is only ever one owner.
+    Owner,
+}
+
+
+#[derive(Object, Clone)]
 pub struct Room {
     pub id: Uuid,
-    pub owner_id: JsSafeBigInt,
     pub active_playlist: Option<Uuid>,
     pub banner: Option<String>,
+    pub created_on: Timestamp,
     pub guild_id: Option<JsSafeBigInt>,
+    pub guild_visibility: Option<GuildVisibility>,
     pub invite_only: bool,
     pub is_public: bool,
+    pub owner_id: JsSafeBigInt,
+
+    /// Whether playback of the current entry is paused.
+    pub playback_paused: bool,
+
+    /// The playback position in milliseconds as of `playback_started_on`.
+    pub playback_position: i64,
+
+    /// The speed the current entry is played back at, `1.0` being normal.
+    pub playback_rate: f32,
+
+    /// When playback last changed, the current position is
+    /// `playback_position` plus the time since this scaled by the playback
+    /// rate, unless paused.
+    pub playback_started_on: Option<Timestamp>,
     pub playing_now: Option<Uuid>,
     pub title: String,
     pub topic: Option<String>,
+    pub viewers: i32,
+}
+
+impl FromRow for Room {
+    fn from_row(row: Row) -> Result<Self, FromRowError> {
+        RoomRow::from_row(row).map(Self::from)
+    }
+}
+
+impl Room {
+    /// Whether the room shows up in the public browse listings.
+    pub fn is_listed(&self) -> bool {
+        self.is_public & !self.invite_only & !self.is_managers_only()
+    }
+
+    /// Whether the room belongs to a guild and only its managers can see
+    /// it.
+    pub fn is_managers_only(&self) -> bool {
+        self.guild_id.is_some() & (self.guild_visibility == Some(GuildVisibility::Managers))
+    }
+
+    /// The playback position in milliseconds at the given time.
+    pub fn position_at(&self, now: Timestamp) -> i64 {
+        let started_on = match self.playback_started_on {
+            None => return self.playback_position,
+            Some(started_on) => started_on,
+        };
+
+        if self.playback_paused {
+            return self.playback_position
+        }
+
+        let elapsed = now.saturating_sub(*started_on).max(0) as f64 * self.playback_rate as f64;
+        self.playback_position.saturating_add(elapsed as i64)
+    }
+}
+
+/// A room as it's stored.
+///
+/// The playback columns were added after rooms already existed so they
+/// can be null, they default to a paused room at the start of playback.
+#[derive(FromRow)]
+struct RoomRow {
+    id: Uuid,
+    active_playlist: Option<Uuid>,
+    banner: Option<String>,
+    created_on: Timestamp,
+    guild_id: Option<JsSafeBigInt>,
+    guild_visibility: Option<GuildVisibility>,
+    invite_only: bool,
+    is_public: bool,
+    owner_id: JsSafeBigInt,
+    playback_paused: Option<bool>,
+    playback_position: Option<i64>,
+    playback_rate: Option<f32>,
+    playback_started_on: Option<Timestamp>,
+    playing_now: Option<Uuid>,
+    title: String,
+    topic: Option<String>,
+    viewers: i32,
+}
+
+impl From<RoomRow> for Room {
+    fn from(row: RoomRow) -> Self {
+        Self {
+            id: row.id,
+            active_playlist: row.active_playlist,
+            banner: row.banner,
+            created_on: row.created_on,
+            guild_id: row.guild_id,
+            guild_visibility: row.guild_visibility,
+            invite_only: row.invite_only,
+            is_public: row.is_public,
+            owner_id: row.owner_id,
+            playback_paused: row.playback_paused.unwrap_or(true),
+            playback_position: row.playback_position.unwrap_or(0),
+            playback_rate: row.playback_rate.unwrap_or(1.0),
+            playback_started_on: row.playback_started_on,
+            playing_now: row.playing_now,
+            title: row.title,
+            topic: row.topic,
+            viewers: row.viewers,
+        }
+    }
+}
+
+/// The playback state of a room sent to listeners whenever it changes.
+#[derive(Object)]
+pub struct PlaybackState {
+    pub room_id: Uuid,
+    pub playing_now: Option<Uuid>,
+    pub paused: bool,
+    pub position: i64,
+    pub rate: f32,
+    pub started_on: Option<Timestamp>,
+}
+
+impl From<&Room> for PlaybackState {
+    fn from(room: &Room) -> Self {
+        Self {
+            room_id: room.id,
+            playing_now: room.playing_now,
+            paused: room.playback_paused,
+            position: room.playback_position,
+            rate: room.playback_rate,
+            started_on: room.playback_started_on,
+        }
+    }
 }
 
 #[derive(Object, FromRow, Clone)]
@@ -30,4 +197,34 @@ pub struct ArchivedRoom {
     pub is_public: bool,
     pub title: String,
     pub topic: Option<String>,
-}
\ No newline at end of file
+}
+#[derive(Object)]
+pub struct RoomMember {
+    pub id: JsSafeBigInt,
+    pub username: String,
+    pub avatar: Option<String>,
+    pub joined_on: Timestamp,
+    pub role: RoomRole,
+}
+
+#[derive(Object, FromRow)]
+pub struct RoomInvite {
+    /// The code used to redeem the invite.
+    pub code: String,
+    pub room_id: Uuid,
+    pub created_by: JsSafeBigInt,
+    pub expires_on: Timestamp,
+
+    /// The number of times the code can be redeemed, unlimited if not set.
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+}
+
+#[derive(Object)]
+pub struct RoomPage {
+    /// The rooms on this page in browse order.
+    pub rooms: Vec<Room>,
+
+    /// The cursor to pass to fetch the next page, if there is one.
+    pub next_cursor: Option<String>,
+}
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::playlists::get_playlist_by_id;
+use crate::rooms::models::{PlaybackState, Room};
+use crate::rtc::events::{self, TrackChange};
+use crate::utils::Timestamp;
+
+
+/// The direction to skip through the active playlist in.
+#[derive(Debug, Copy, Clone)]
+pub enum Skip {
+    Next,
+    Previous,
+}
+
+
+/// Resumes playback of the current entry, optionally changing the
+/// playback rate.
+pub async fn play(sess: &Session, room: &mut Room, rate: Option<f32>) -> Result<()> {
+    let now = Timestamp::now();
+
+    room.playback_position = room.position_at(now);
+    room.playback_started_on = Some(now);
+    room.playback_paused = false;
+
+    if let Some(rate) = rate {
+        room.playback_rate = rate;
+    }
+
+    save_playback(sess, room).await
+}
+
+/// Pauses playback at the current position.
+pub async fn pause(sess: &Session, room: &mut Room) -> Result<()> {
+    let now = Timestamp::now();
+
+    room.playback_position = room.position_at(now);
+    room.playback_started_on = Some(now);
+    room.playback_paused = true;
+
+    save_playback(sess, room).await
+}
+
+/// Moves playback of the current entry to the given position in
+/// milliseconds.
+pub async fn seek(sess: &Session, room: &mut Room, position: i64) -> Result<()> {
+    room.playback_position = position;
+    room.playback_started_on = Some(Timestamp::now());
+
+    save_playback(sess, room).await
+}
+
+/// Starts playing the given entry from the beginning.
+pub async fn set_entry(sess: &Session, room: &mut Room, entry_id: Uuid) -> Result<()> {
+    let previous_entry_id = room.playing_now.replace(entry_id);
+    room.playback_position = 0;
+    room.playback_started_on = Some(Timestamp::now());
+
+    save_playback(sess, room).await?;
+
+    if previous_entry_id != Some(entry_id) {
+        events::emit(room.id, TrackChange { entry_id, previous_entry_id }).await;
+    }
+
+    Ok(())
+}
+
+/// Moves to the next or previous entry of the room's active playlist and
+/// starts playing it.
+///
+/// If nothing is playing, skipping forwards starts the first entry.
+/// Returns `false` without changing anything if there is no playlist or
+/// no entry to move to.
+pub async fn skip(sess: &Session, room: &mut Room, direction: Skip) -> Result<bool> {
+    let playlist_id = match room.active_playlist {
+        None => return Ok(false),
+        Some(playlist_id) => playlist_id,
+    };
+
+    let playlist = match get_playlist_by_id(sess, playlist_id).await? {
+        None => return Ok(false),
+        Some(playlist) => playlist,
+    };
+
+    let current = room.playing_now
+        .and_then(|entry_id| playlist.items.iter().position(|v| *v == entry_id));
+
+    let target = match (current, direction) {
+        (None, Skip::Next) => Some(0),
+        (None, Skip::Previous) => None,
+        (Some(index), Skip::Next) => Some(index + 1),
+        (Some(index), Skip::Previous) => index.checked_sub(1),
+    };
+
+    let entry_id = match target.and_then(|index| playlist.items.get(index)) {
+        None => return Ok(false),
+        Some(entry_id) => *entry_id,
+    };
+
+    room.playback_paused = false;
+    set_entry(sess, room, entry_id).await?;
+
+    Ok(true)
+}
+
+/// Advances to the next entry once the given entry has finished playing.
+///
+/// Every listening client may report the same entry finishing, so this
+/// only advances if the entry is still the one playing. Once the last
+/// entry finishes playback is paused at its end.
+pub async fn entry_finished(sess: &Session, room: &mut Room, entry_id: Uuid) -> Result<()> {
+    if room.playing_now != Some(entry_id) {
+        return Ok(())
+    }
+
+    if !skip(sess, room, Skip::Next).await? {
+        pause(sess, room).await?;
+    }
+
+    Ok(())
+}
+
+
+/// Persists the room's playback state and lets listeners know it changed.
+async fn save_playback(sess: &Session, room: &Room) -> Result<()> {
+    sess.query_prepared(
+        r#"
+        UPDATE active_rooms SET
+            playing_now = ?,
+            playback_paused = ?,
+            playback_position = ?,
+            playback_rate = ?,
+            playback_started_on = ?
+        WHERE id = ?;
+        "#,
+        (
+            room.playing_now,
+            room.playback_paused,
+            room.playback_position,
+            room.playback_rate,
+            room.playback_started_on,
+            room.id,
+        )
+    ).await?;
+
+    events::emit(room.id, PlaybackState::from(room)).await;
+
+    Ok(())
+}
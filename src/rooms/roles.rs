@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+use uuid::Uuid;
+
+use crate::db::{self, Session};
+use crate::rooms::members;
+use crate::rooms::models::{Room, RoomRole};
+use crate::rtc::events::{self, RoomUpdated};
+use crate::users::room_info;
+use crate::utils::JsSafeBigInt;
+
+
+/// Gets the role the user holds within the room.
+///
+/// The owner is taken from the room itself, only co-hosts are stored,
+/// anyone else is a listener.
+pub async fn get_role(sess: &Session, room: &Room, user_id: i64) -> Result<RoomRole> {
+    if *room.owner_id == user_id {
+        return Ok(RoomRole::Owner)
+    }
+
+    let result = sess.query_prepared(
+        "SELECT role FROM room_roles WHERE room_id = ? AND user_id = ?;",
+        (room.id, user_id)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let role = match rows.into_typed::<(String,)>().next() {
+        None => RoomRole::Listener,
+        Some(v) => RoomRole::from_str(&v?.0).unwrap_or(RoomRole::Listener),
+    };
+
+    Ok(role)
+}
+
+/// Gets the ids of all co-hosts of the room.
+pub async fn get_co_hosts(sess: &Session, room_id: Uuid) -> Result<HashSet<i64>> {
+    let result = sess.query_prepared(
+        "SELECT user_id, role FROM room_roles WHERE room_id = ?;",
+        (room_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let co_hosts = rows.into_typed::<(i64, String)>()
+        .filter_map(|v| v.ok())
+        .filter(|(_, role)| RoomRole::from_str(role).ok() == Some(RoomRole::CoHost))
+        .map(|(user_id, _)| user_id)
+        .collect();
+
+    Ok(co_hosts)
+}
+
+/// Sets the user's role within the room.
+///
+/// This can't be used to change the owner, see `transfer_ownership`.
+pub async fn set_role(sess: &Session, room_id: Uuid, user_id: i64, role: RoomRole) -> Result<()> {
+    match role {
+        RoomRole::Owner => return Err(anyhow!("room ownership must be transferred")),
+        RoomRole::Listener => {
+            sess.query_prepared(
+                "DELETE FROM room_roles WHERE room_id = ? AND user_id = ?;",
+                (room_id, user_id)
+            ).await?;
+        },
+        RoomRole::CoHost => {
+            sess.query_prepared(
+                "INSERT INTO room_roles (room_id, user_id, role) VALUES (?, ?, ?);",
+                (room_id, user_id, role.to_string())
+            ).await?;
+        },
+    }
+
+    Ok(())
+}
+
+/// Removes all roles for the room.
+pub async fn clear_roles(sess: &Session, room_id: Uuid) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM room_roles WHERE room_id = ?;",
+        (room_id,)
+    ).await?;
+
+    Ok(())
+}
+
+/// Makes the given user the owner of the room, returning `false` if the
+/// room changed owner since it was read.
+///
+/// The previous owner stays on as a co-host so they can pick up where
+/// they left off if they come back. This assumes the new owner has no
+/// active room of their own.
+pub async fn transfer_ownership(sess: &Session, room: &mut Room, new_owner_id: i64) -> Result<bool> {
+    let result = sess.query_prepared(
+        "UPDATE active_rooms SET owner_id = ? WHERE id = ? IF owner_id = ?;",
+        (new_owner_id, room.id, *room.owner_id)
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return Ok(false)
+    }
+
+    set_role(sess, room.id, new_owner_id, RoomRole::Listener).await?;
+    set_role(sess, room.id, *room.owner_id, RoomRole::CoHost).await?;
+
+    room.owner_id = JsSafeBigInt(new_owner_id);
+    events::emit(room.id, RoomUpdated { room: room.clone() }).await;
+
+    Ok(true)
+}
+
+/// Hands the room over to another member if the owner is no longer
+/// present, returning if the room changed owner.
+///
+/// Present co-hosts are preferred over listeners and members who have
+/// been in the room longest are preferred over newer ones. Members who
+/// already own another room are skipped, if nobody is eligible the room
+/// is left as is until someone is. If another request hands the room
+/// over first this does nothing.
+pub async fn hand_off_if_owner_absent(sess: &Session, room: &mut Room) -> Result<bool> {
+    let present = members::get_present_members(sess, room.id).await?;
+
+    if present.iter().any(|(user_id, _)| *user_id == *room.owner_id) {
+        return Ok(false)
+    }
+
+    let co_hosts = get_co_hosts(sess, room.id).await?;
+
+    let (mut candidates, listeners): (Vec<i64>, Vec<i64>) = present.into_iter()
+        .map(|(user_id, _)| user_id)
+        .partition(|user_id| co_hosts.contains(user_id));
+
+    candidates.extend(listeners);
+
+    for user_id in candidates {
+        if room_info::get_active_room_for_user_id(sess, user_id).await?.is_some() {
+            continue;
+        }
+
+        return transfer_ownership(sess, room, user_id).await
+    }
+
+    Ok(false)
+}
@@ -1,63 +1,240 @@
-use reqwest::{StatusCode, header};
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use poem_openapi::{Enum, Object};
+use poem_openapi::types::ToJSON;
 use serde::Serialize;
 use serde_json::Value;
 use uuid::Uuid;
 
+use crate::rooms::models::{PlaybackState, Room, RoomMember};
+use crate::rtc::sink::SINKS;
+use crate::users::notifications::Notification;
+use crate::utils::JsSafeBigInt;
 
-lazy_static! {
-    static ref SOCKETEER_URI: String = {
-        std::env::var("SOCKETEER_URI")
-            .unwrap_or_else(|_| "http://127.0.0.1:8800".to_string())
-    };
 
-    static ref SOCKETEER_KEY: String = {
-        std::env::var("SOCKETEER_KEY")
-            .unwrap_or_else(|_| "hello".to_string())
-    };
-}
-
-
-#[derive(Serialize)]
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
 #[serde(rename_all = "SCREAMING-KEBAB-CASE")]
 pub enum EventType {
     CandidateCall,
     CandidateAnswer,
-    // PlaylistSelected,
-    // TrackChange,
-    // RoomClosed,
+    IceCandidate,
+    HangUp,
+    PlaybackUpdated,
+    PlaylistSelected,
+    TrackChange,
+    RoomClosed,
+    RoomUpdated,
+    MemberJoined,
+    MemberLeft,
+    NotificationCreated,
+    NotificationsUpdated,
+}
+
+
+/// A WebRTC signalling message relayed between two members of a room.
+///
+/// Signals with a target are only delivered to that user, a hang up
+/// without one is emitted to the whole room.
+#[derive(Object)]
+pub struct Signal {
+    pub room_id: Uuid,
+    pub from: JsSafeBigInt,
+
+    /// The user the signal is for, everyone in the room if not set.
+    pub to: Option<JsSafeBigInt>,
+    pub data: Value,
+}
+
+
+/// A typed event payload which knows which event it belongs to.
+pub trait EventPayload: ToJSON {
+    const TYPE: EventType;
+}
+
+/// Emitted when a new playlist is selected for the room.
+#[derive(Object)]
+pub struct PlaylistSelected {
+    pub playlist_id: Uuid,
+}
+
+impl EventPayload for PlaylistSelected {
+    const TYPE: EventType = EventType::PlaylistSelected;
+}
+
+/// Emitted when the room starts playing a different entry.
+#[derive(Object)]
+pub struct TrackChange {
+    pub entry_id: Uuid,
+    pub previous_entry_id: Option<Uuid>,
+}
+
+impl EventPayload for TrackChange {
+    const TYPE: EventType = EventType::TrackChange;
+}
+
+/// Why a room was closed.
+#[derive(Enum, Debug, Copy, Clone)]
+#[oai(rename_all = "lowercase")]
+pub enum CloseReason {
+    /// The owner closed their room.
+    Owner,
+
+    /// A manager of the room's guild closed the room.
+    Moderator,
+
+    /// A superuser forcefully closed the room.
+    Superuser,
+}
+
+/// Emitted when the room is closed, no further events are sent for it.
+#[derive(Object)]
+pub struct RoomClosed {
+    pub reason: CloseReason,
+}
+
+impl EventPayload for RoomClosed {
+    const TYPE: EventType = EventType::RoomClosed;
+}
+
+/// Emitted when the room's info changes, e.g. its owner or viewer count.
+#[derive(Object)]
+pub struct RoomUpdated {
+    pub room: Room,
+}
+
+impl EventPayload for RoomUpdated {
+    const TYPE: EventType = EventType::RoomUpdated;
+}
+
+/// Emitted when a user joins the room.
+#[derive(Object)]
+pub struct MemberJoined {
+    pub member: RoomMember,
+}
+
+impl EventPayload for MemberJoined {
+    const TYPE: EventType = EventType::MemberJoined;
+}
+
+/// Emitted when a user leaves or is kicked from the room.
+#[derive(Object)]
+pub struct MemberLeft {
+    pub user_id: JsSafeBigInt,
+    pub kicked: bool,
+}
+
+impl EventPayload for MemberLeft {
+    const TYPE: EventType = EventType::MemberLeft;
 }
 
+impl EventPayload for PlaybackState {
+    const TYPE: EventType = EventType::PlaybackUpdated;
+}
+
+/// Emitted to the recipient when they receive a new notification.
+#[derive(Object)]
+pub struct NotificationCreated {
+    pub notification: Notification,
+
+    /// How many unread notifications the user now has.
+    pub unread: i64,
+}
+
+impl EventPayload for NotificationCreated {
+    const TYPE: EventType = EventType::NotificationCreated;
+}
+
+/// Emitted to the user when notifications are read or removed so all of
+/// their clients can keep their badges in sync.
+#[derive(Object)]
+pub struct NotificationsUpdated {
+    /// How many unread notifications the user now has.
+    pub unread: i64,
+}
+
+impl EventPayload for NotificationsUpdated {
+    const TYPE: EventType = EventType::NotificationsUpdated;
+}
+
+
+/// Who an event is delivered to.
+///
+/// This is flattened into the event, so room events keep their
+/// `room_id` field and user events have a `user_id` field instead.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EventTarget {
+    /// Everyone subscribed to the room.
+    Room { room_id: Uuid },
 
-#[derive(Serialize)]
+    /// Every connection of the user, regardless of their room.
+    User { user_id: JsSafeBigInt },
+}
+
+
+#[derive(Serialize, Clone)]
 pub struct Event {
-    room_id: Uuid,
+    #[serde(flatten)]
+    pub target: EventTarget,
 
     #[serde(rename = "type")]
-    type_: EventType,
+    pub type_: EventType,
 
-    data: Value,
+    pub data: Value,
 }
 
 
+/// Delivers a room event to every configured event sink.
 pub async fn emit_event(room_id: Uuid, type_: EventType, data: Value) -> Result<()> {
-    let event = Event {
-        room_id,
+    publish(Event {
+        target: EventTarget::Room { room_id },
+        type_,
+        data
+    }).await
+}
+
+/// Delivers an event addressed to a single user to every configured
+/// event sink.
+pub async fn emit_user_event(user_id: i64, type_: EventType, data: Value) -> Result<()> {
+    publish(Event {
+        target: EventTarget::User { user_id: JsSafeBigInt(user_id) },
         type_,
         data
-    };
+    }).await
+}
 
-    let client = reqwest::Client::new();
 
-    let resp = client.post(format!("{}/api/v0/emit", SOCKETEER_URI.as_str()))
-        .json(&event)
-        .header(header::AUTHORIZATION, format!("Bearer {}", SOCKETEER_KEY.as_str()))
-        .send()
-        .await?;
+/// Emits a typed event for the room.
+///
+/// Unlike `emit_event` this never fails, delivery errors are logged
+/// instead so they don't fail the request which caused the event.
+pub async fn emit<P: EventPayload>(room_id: Uuid, payload: P) {
+    if let Err(e) = emit_event(room_id, P::TYPE, payload.to_json()).await {
+        warn!("failed to emit {:?} event for room {}: {}", P::TYPE, room_id, e);
+    }
+}
+
+/// Emits a typed event to the user, logging any delivery errors like
+/// `emit`.
+pub async fn emit_to_user<P: EventPayload>(user_id: i64, payload: P) {
+    if let Err(e) = emit_user_event(user_id, P::TYPE, payload.to_json()).await {
+        warn!("failed to emit {:?} event for user {}: {}", P::TYPE, user_id, e);
+    }
+}
+
 
-    if resp.status() == StatusCode::OK {
-        Ok(())
-    } else {
-        Err(anyhow!("Socketeer responded with bad status code {}", resp.status()))
+/// Publishes the event to every sink.
+///
+/// Every sink is tried even if an earlier one fails, the first error is
+/// returned afterwards.
+async fn publish(event: Event) -> Result<()> {
+    let mut result = Ok(());
+    for sink in SINKS.iter() {
+        if let Err(e) = sink.publish(&event).await {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
     }
-}
\ No newline at end of file
+
+    result
+}
@@ -0,0 +1,291 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, StreamExt};
+use poem::{handler, IntoResponse};
+use poem::web::Data;
+use poem::web::websocket::{Message, WebSocket, WebSocketStream};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::auth::identity::{self, UserCache};
+use crate::db::Session;
+use crate::rooms;
+use crate::rtc::events::{Event, EventTarget, EventType};
+use crate::rtc::sink::EventSink;
+use crate::users::user_info::User;
+use crate::utils::JsSafeBigInt;
+
+
+/// How many events can be buffered for a slow connection before it
+/// starts missing them.
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// How long a connection has to identify itself before it's closed.
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a connection's access token and room subscriptions are
+/// checked again, this matches how long `UserCache` trusts a token.
+const REVALIDATE_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<Arc<Event>> = {
+        broadcast::channel(EVENT_BUFFER_SIZE).0
+    };
+}
+
+
+/// Delivers events to clients connected to the built in gateway.
+pub struct GatewaySink;
+
+#[async_trait::async_trait]
+impl EventSink for GatewaySink {
+    async fn publish(&self, event: &Event) -> Result<()> {
+        // This only fails if nobody is connected, which is fine.
+        let _ = EVENTS.send(Arc::new(event.clone()));
+        Ok(())
+    }
+}
+
+
+/// An operation sent by the client.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum ClientOp {
+    /// Authenticates the connection, this must be the first op sent.
+    Identify { token: String },
+
+    /// Starts receiving events for the given room.
+    Subscribe { room_id: Uuid },
+
+    /// Stops receiving events for the given room.
+    Unsubscribe { room_id: Uuid },
+}
+
+/// An operation sent to the client, these are sent alongside the
+/// room events themselves which don't have an `op` field.
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum ServerOp {
+    Ready { user_id: JsSafeBigInt },
+    Subscribed { room_id: Uuid },
+    Unsubscribed { room_id: Uuid },
+    Error { message: String },
+}
+
+
+/// Gateway
+///
+/// Upgrades the connection to a WebSocket which room events are
+/// delivered over.
+///
+/// Clients must send an `identify` op with their access token within
+/// 10 seconds, after which they can `subscribe` and `unsubscribe` to any
+/// rooms they can access. Subscriptions end when the room closes, the
+/// user is kicked from it or they otherwise lose access to it. Events
+/// addressed to the user are always delivered.
+///
+/// The access token is checked again every 30 seconds, the connection
+/// is closed with code 4001 once it's no longer valid.
+#[handler]
+pub async fn connect(
+    ws: WebSocket,
+    session: Data<&Session>,
+    cache: Data<&Arc<UserCache>>,
+) -> impl IntoResponse {
+    let session = session.0.clone();
+    let cache = cache.0.clone();
+
+    ws.on_upgrade(move |socket| async move {
+        if let Err(e) = run_connection(socket, session, cache).await {
+            debug!("gateway connection closed with error: {}", e);
+        }
+    })
+}
+
+
+async fn run_connection(mut socket: WebSocketStream, sess: Session, cache: Arc<UserCache>) -> Result<()> {
+    let (token, mut user) = match tokio::time::timeout(IDENTIFY_TIMEOUT, identify(&mut socket, &sess, &cache)).await {
+        Ok(Ok(Some(identified))) => identified,
+        Ok(Ok(None)) => {
+            let close = Message::close_with(4001u16, "Invalid access token.");
+            return socket.send(close).await.map_err(anyhow::Error::from)
+        },
+        Ok(Err(e)) => return Err(e),
+        Err(_) => {
+            let close = Message::close_with(4000u16, "Connection did not identify in time.");
+            return socket.send(close).await.map_err(anyhow::Error::from)
+        },
+    };
+
+    // Subscribe before acknowledging so no events are missed in between.
+    let mut events = EVENTS.subscribe();
+    let mut rooms: HashSet<Uuid> = HashSet::new();
+    let user_id = user.id.to_string();
+
+    send_op(&mut socket, ServerOp::Ready { user_id: user.id }).await?;
+
+    let start = tokio::time::Instant::now() + REVALIDATE_INTERVAL;
+    let mut revalidate = tokio::time::interval_at(start, REVALIDATE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = revalidate.tick() => {
+                user = match identity::authenticate(&sess, &cache, &token, None).await? {
+                    None => {
+                        let close = Message::close_with(4001u16, "Access token is no longer valid.");
+                        return socket.send(close).await.map_err(anyhow::Error::from)
+                    },
+                    Some(authenticated) => authenticated.user,
+                };
+
+                for room_id in remove_inaccessible_rooms(&sess, &user, &mut rooms).await? {
+                    send_op(&mut socket, ServerOp::Unsubscribed { room_id }).await?;
+                }
+            },
+            msg = socket.next() => {
+                let text = match msg {
+                    None | Some(Ok(Message::Close(_))) => break,
+                    Some(Err(e)) => return Err(e.into()),
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(_)) => continue,
+                };
+
+                let reply = match serde_json::from_str::<ClientOp>(&text) {
+                    Err(e) => ServerOp::Error { message: e.to_string() },
+                    Ok(op) => handle_op(&sess, &user, &mut rooms, op).await?,
+                };
+
+                send_op(&mut socket, reply).await?;
+            },
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(missed)) => {
+                        let close = Message::close_with(4002u16, format!("Connection missed {} events.", missed));
+                        return socket.send(close).await.map_err(anyhow::Error::from)
+                    },
+                };
+
+                let room_id = match event.target {
+                    EventTarget::User { user_id } if user_id == user.id => {
+                        socket.send(Message::text(serde_json::to_string(&*event)?)).await?;
+                        continue;
+                    },
+                    EventTarget::Room { room_id } if rooms.contains(&room_id) => room_id,
+                    _ => continue,
+                };
+
+                socket.send(Message::text(serde_json::to_string(&*event)?)).await?;
+
+                let was_kicked = (event.type_ == EventType::MemberLeft)
+                    & (event.data["kicked"].as_bool() == Some(true))
+                    & (event.data["user_id"].as_str() == Some(&user_id));
+
+                if (event.type_ == EventType::RoomClosed) | was_kicked {
+                    rooms.remove(&room_id);
+                    send_op(&mut socket, ServerOp::Unsubscribed { room_id }).await?;
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Waits for the client to identify itself, returning the token it sent
+/// and its user if the token is valid.
+async fn identify(socket: &mut WebSocketStream, sess: &Session, cache: &UserCache) -> Result<Option<(String, User)>> {
+    while let Some(msg) = socket.next().await {
+        let text = match msg? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        return match serde_json::from_str::<ClientOp>(&text) {
+            Ok(ClientOp::Identify { token }) => {
+                let user = identity::authenticate(sess, cache, &token, None).await?;
+                Ok(user.map(|v| (token, v.user)))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    Err(anyhow!("connection closed before identifying"))
+}
+
+
+async fn handle_op(
+    sess: &Session,
+    user: &User,
+    rooms: &mut HashSet<Uuid>,
+    op: ClientOp,
+) -> Result<ServerOp> {
+    let reply = match op {
+        ClientOp::Identify { .. } => ServerOp::Error {
+            message: "Connection is already identified.".to_string(),
+        },
+        ClientOp::Unsubscribe { room_id } => {
+            rooms.remove(&room_id);
+            ServerOp::Unsubscribed { room_id }
+        },
+        ClientOp::Subscribe { room_id } => {
+            let room = match rooms::get_room_by_id(sess, room_id).await? {
+                None => return Ok(ServerOp::Error {
+                    message: "No active room exists with this id.".to_string(),
+                }),
+                Some(room) => room,
+            };
+
+            if !rooms::access::has_room_access(sess, &room, user).await? {
+                return Ok(ServerOp::Error {
+                    message: "You do not have access to this room.".to_string(),
+                })
+            }
+
+            rooms.insert(room_id);
+            ServerOp::Subscribed { room_id }
+        },
+    };
+
+    Ok(reply)
+}
+
+
+/// Unsubscribes from every room which has closed or which the user can
+/// no longer access, returning the rooms that were removed.
+async fn remove_inaccessible_rooms(
+    sess: &Session,
+    user: &User,
+    rooms: &mut HashSet<Uuid>,
+) -> Result<Vec<Uuid>> {
+    let mut removed = Vec::new();
+    for room_id in rooms.iter().copied() {
+        let has_access = match rooms::get_room_by_id(sess, room_id).await? {
+            None => false,
+            Some(room) => rooms::access::has_room_access(sess, &room, user).await?,
+        };
+
+        if !has_access {
+            removed.push(room_id);
+        }
+    }
+
+    for room_id in removed.iter() {
+        rooms.remove(room_id);
+    }
+
+    Ok(removed)
+}
+
+
+async fn send_op(socket: &mut WebSocketStream, op: ServerOp) -> Result<()> {
+    socket.send(Message::text(serde_json::to_string(&op)?)).await?;
+    Ok(())
+}
@@ -1,25 +1,45 @@
-mod events;
+pub mod events;
+pub mod gateway;
+pub mod sink;
+mod turn;
 
 use poem::Result;
 use poem::web::Data;
 use poem_openapi::OpenApi;
 use poem_openapi::payload::Json;
 use poem_openapi::Object;
+use poem_openapi::types::ToJSON;
 use serde_json::Value;
 use uuid::Uuid;
 
 use crate::ApiTags;
 use crate::db::Session;
-use crate::utils::{JsonResponse, TokenBearer};
-use crate::users::user_info;
+use crate::utils::{JsSafeBigInt, JsonResponse, UserBearer};
 use crate::rooms;
-use crate::rtc::events::EventType;
+use crate::rooms::models::RoomRole;
+use crate::rtc::events::{EventType, Signal};
+use crate::rtc::turn::IceServers;
 
 
 #[derive(Object)]
-pub struct CandidateInfo {
+pub struct SignalPayload {
     room_id: Uuid,
-    candidate: Value,
+
+    /// The user the signal is addressed to.
+    #[oai(validator(minimum(value = "0")))]
+    target_id: i64,
+
+    /// The SDP description or ICE candidate, relayed as is.
+    data: Value,
+}
+
+#[derive(Object)]
+pub struct HangUpPayload {
+    room_id: Uuid,
+
+    /// The user to hang up on, hangs up on everyone if not set.
+    #[oai(validator(minimum(value = "0")))]
+    target_id: Option<i64>,
 }
 
 
@@ -29,77 +49,164 @@ pub struct RtcApi;
 impl RtcApi {
     /// Create Offer
     ///
-    /// Creates a RTC ICE offer.
+    /// Sends a RTC SDP offer to a member of the room.
+    ///
+    /// Only the room owner and co-hosts who are present in the room can
+    /// start a call and the target must have joined the room.
     #[oai(path = "/rtc/call/offer", method = "post", tag = "ApiTags::Rtc")]
     pub async fn create_call(
         &self,
-        payload: Json<CandidateInfo>,
+        payload: Json<SignalPayload>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<Value>> {
-        let user = match user_info::get_user_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(u) => u,
-        };
+        let user_id = token.0.id;
 
-        let room = match rooms::get_room_by_id(&session, payload.room_id.clone()).await? {
+        let room = match rooms::get_room_by_id(&session, payload.room_id).await? {
             None => return Ok(JsonResponse::bad_request("No active room exists with this id.")),
             Some(room) => room,
         };
 
-        if *room.owner_id != *user.id {
+        if !rooms::access::is_present_with_role(&session, &room, user_id, RoomRole::CoHost).await? {
             return Ok(JsonResponse::forbidden());
         }
 
-        events::emit_event(
-            room.id,
-            EventType::CandidateCall,
-            payload.0.candidate,
-        ).await?;
+        if !rooms::members::is_room_member(&session, room.id, payload.target_id).await? {
+            return Ok(JsonResponse::bad_request("Target is not a member of this room."))
+        }
+
+        relay_signal(room.id, EventType::CandidateCall, user_id, Some(payload.target_id), payload.0.data).await?;
 
         Ok(JsonResponse::ok(Value::Null))
     }
 
     /// Create Answer
     ///
-    /// Creates a RTC ICE answer.
+    /// Sends a RTC SDP answer back to the host who sent the offer.
+    ///
+    /// The user must have joined the room before they can answer a call
+    /// and the target must be the room owner or a co-host.
     #[oai(path = "/rtc/call/answer", method = "post", tag = "ApiTags::Rtc")]
     pub async fn create_answer(
         &self,
-        payload: Json<CandidateInfo>,
+        payload: Json<SignalPayload>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<Value>> {
-        let user = match user_info::get_user_from_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(u) => u,
-        };
+        let user = token.0.user;
 
-        let room = match rooms::get_room_by_id(&session, payload.room_id.clone()).await? {
+        let room = match rooms::get_room_by_id(&session, payload.room_id).await? {
             None => return Ok(JsonResponse::bad_request("No active room exists with this id.")),
             Some(room) => room,
         };
 
-        let has_guild_access = if let Some(guild_id) = room.guild_id.as_ref() {
-          user.access_servers.contains_key(&*guild_id)
-        } else {
-            false
+        if !rooms::access::has_room_access(&session, &room, &user).await? {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        if !rooms::members::is_room_member(&session, room.id, *user.id).await? {
+            return Ok(JsonResponse::bad_request("User is not a member of this room."))
+        }
+
+        if !rooms::access::has_room_role(&session, &room, payload.target_id, RoomRole::CoHost).await? {
+            return Ok(JsonResponse::bad_request("Target is not a host of this room."))
+        }
+
+        relay_signal(room.id, EventType::CandidateAnswer, *user.id, Some(payload.target_id), payload.0.data).await?;
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Send ICE Candidate
+    ///
+    /// Relays a trickled ICE candidate to the other side of a call.
+    ///
+    /// Both the user and the target must have joined the room.
+    #[oai(path = "/rtc/call/candidate", method = "post", tag = "ApiTags::Rtc")]
+    pub async fn send_candidate(
+        &self,
+        payload: Json<SignalPayload>,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+
+        if !rooms::members::is_room_member(&session, payload.room_id, user_id).await? {
+            return Ok(JsonResponse::bad_request("User is not a member of this room."))
+        }
+
+        if !rooms::members::is_room_member(&session, payload.room_id, payload.target_id).await? {
+            return Ok(JsonResponse::bad_request("Target is not a member of this room."))
+        }
+
+        relay_signal(payload.room_id, EventType::IceCandidate, user_id, Some(payload.target_id), payload.0.data).await?;
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Hang Up
+    ///
+    /// Ends the user's call with the target, or all of the user's calls
+    /// in the room if no target is given.
+    #[oai(path = "/rtc/call/hangup", method = "post", tag = "ApiTags::Rtc")]
+    pub async fn hang_up(
+        &self,
+        payload: Json<HangUpPayload>,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+
+        let room = match rooms::get_room_by_id(&session, payload.room_id).await? {
+            None => return Ok(JsonResponse::bad_request("No active room exists with this id.")),
+            Some(room) => room,
         };
 
-        if (!room.is_public)                // The room is not public
-            & (!room.invite_only)           // The room is not invite only
-            & (room.owner_id != user.id)    // They are not the owner of the room
-            & (!has_guild_access)           // They don't have access via guilds.
-        {
-            return Ok(JsonResponse::forbidden())
+        let is_host = rooms::access::has_room_role(&session, &room, user_id, RoomRole::CoHost).await?;
+        if !is_host & !rooms::members::is_room_member(&session, room.id, user_id).await? {
+            return Ok(JsonResponse::bad_request("User is not a member of this room."))
         }
 
-        events::emit_event(
-            room.id,
-            EventType::CandidateAnswer,
-            payload.0.candidate,
-        ).await?;
+        relay_signal(room.id, EventType::HangUp, user_id, payload.target_id, Value::Null).await?;
 
         Ok(JsonResponse::ok(Value::Null))
     }
+
+    /// Get ICE Servers
+    ///
+    /// Gets the STUN and TURN servers clients should use to connect along
+    /// with time limited TURN credentials for the user.
+    #[oai(path = "/rtc/credentials", method = "get", tag = "ApiTags::Rtc")]
+    pub async fn get_ice_servers(
+        &self,
+        token: UserBearer,
+    ) -> Result<JsonResponse<IceServers>> {
+        Ok(JsonResponse::ok(turn::issue_credentials(token.0.id)))
+    }
+}
+
+
+/// Relays a signalling message to its target, or to the whole room if it
+/// has none.
+///
+/// Unlike room lifecycle events, a signal which fails to deliver fails
+/// the request so the client knows to retry.
+async fn relay_signal(
+    room_id: Uuid,
+    type_: EventType,
+    from: i64,
+    to: Option<i64>,
+    data: Value,
+) -> anyhow::Result<()> {
+    let signal = Signal {
+        room_id,
+        from: JsSafeBigInt(from),
+        to: to.map(JsSafeBigInt),
+        data,
+    };
+
+    match to {
+        Some(to) => events::emit_user_event(to, type_, signal.to_json()).await,
+        None => events::emit_event(room_id, type_, signal.to_json()).await,
+    }
 }
@@ -0,0 +1,102 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use reqwest::{StatusCode, header};
+use strum::EnumString;
+
+use crate::rtc::events::Event;
+use crate::rtc::gateway;
+
+
+lazy_static! {
+    static ref SOCKETEER_URI: String = {
+        std::env::var("SOCKETEER_URI")
+            .unwrap_or_else(|_| "http://127.0.0.1:8800".to_string())
+    };
+
+    static ref SOCKETEER_KEY: String = {
+        std::env::var("SOCKETEER_KEY")
+            .unwrap_or_else(|_| "hello".to_string())
+    };
+
+    static ref SINK_KINDS: Vec<SinkKind> = {
+        std::env::var("EVENT_SINKS")
+            .unwrap_or_else(|_| "socketeer".to_string())
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .filter_map(|v| match SinkKind::from_str(v) {
+                Ok(kind) => Some(kind),
+                Err(_) => {
+                    warn!("ignoring unknown event sink {:?}", v);
+                    None
+                },
+            })
+            .collect()
+    };
+
+    pub static ref SINKS: Vec<Arc<dyn EventSink>> = {
+        SINK_KINDS.iter()
+            .map(|kind| kind.build())
+            .collect()
+    };
+}
+
+
+/// Somewhere room events can be delivered to.
+///
+/// Which sinks are used is picked at startup with the comma separated
+/// `EVENT_SINKS` env var, defaulting to just `socketeer`.
+#[async_trait::async_trait]
+pub trait EventSink: Send + Sync {
+    async fn publish(&self, event: &Event) -> Result<()>;
+}
+
+
+#[derive(EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+enum SinkKind {
+    /// The external Socketeer service.
+    Socketeer,
+
+    /// The built in WebSocket gateway, see `rtc::gateway`.
+    Gateway,
+}
+
+impl SinkKind {
+    fn build(self) -> Arc<dyn EventSink> {
+        match self {
+            Self::Socketeer => Arc::new(SocketeerSink::default()),
+            Self::Gateway => Arc::new(gateway::GatewaySink),
+        }
+    }
+}
+
+/// Checks if the built in WebSocket gateway should be served.
+pub fn gateway_enabled() -> bool {
+    SINK_KINDS.contains(&SinkKind::Gateway)
+}
+
+
+/// Delivers events by posting them to Socketeer.
+#[derive(Default)]
+pub struct SocketeerSink {
+    client: reqwest::Client,
+}
+
+#[async_trait::async_trait]
+impl EventSink for SocketeerSink {
+    async fn publish(&self, event: &Event) -> Result<()> {
+        let resp = self.client.post(format!("{}/api/v0/emit", SOCKETEER_URI.as_str()))
+            .json(event)
+            .header(header::AUTHORIZATION, format!("Bearer {}", SOCKETEER_KEY.as_str()))
+            .send()
+            .await?;
+
+        if resp.status() == StatusCode::OK {
+            Ok(())
+        } else {
+            Err(anyhow!("Socketeer responded with bad status code {}", resp.status()))
+        }
+    }
+}
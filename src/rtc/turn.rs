@@ -0,0 +1,76 @@
+use hmac::{Hmac, Mac};
+use poem_openapi::Object;
+use sha1::Sha1;
+
+
+lazy_static! {
+    static ref TURN_SECRET: Option<String> = {
+        std::env::var("TURN_SECRET").ok()
+    };
+
+    static ref ICE_URIS: Vec<String> = {
+        std::env::var("ICE_URIS")
+            .unwrap_or_else(|_| "stun:stun.l.google.com:19302".to_string())
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect()
+    };
+
+    static ref TURN_CREDENTIAL_TTL: i64 = {
+        std::env::var("TURN_CREDENTIAL_TTL")
+            .map(|v| v.parse::<i64>().unwrap_or(86400))
+            .unwrap_or(86400)
+    };
+}
+
+
+#[derive(Object)]
+pub struct IceServers {
+    /// The STUN and TURN server URIs clients should use.
+    pub urls: Vec<String>,
+
+    /// The TURN username, not set if no TURN server is configured.
+    pub username: Option<String>,
+
+    /// The TURN password, not set if no TURN server is configured.
+    pub credential: Option<String>,
+
+    /// How many seconds the credentials are valid for.
+    pub ttl: i64,
+}
+
+
+/// Issues time limited TURN credentials for the given user.
+///
+/// This follows the coturn REST API scheme, the username is the expiry
+/// unix timestamp and user id joined by a `:` and the password is the
+/// base64 encoded HMAC-SHA1 of the username keyed with the shared
+/// `TURN_SECRET`.
+pub fn issue_credentials(user_id: i64) -> IceServers {
+    let secret = match TURN_SECRET.as_ref() {
+        None => return IceServers {
+            urls: ICE_URIS.clone(),
+            username: None,
+            credential: None,
+            ttl: 0,
+        },
+        Some(secret) => secret,
+    };
+
+    let expires_on = chrono::Utc::now().timestamp() + *TURN_CREDENTIAL_TTL;
+    let username = format!("{}:{}", expires_on, user_id);
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(username.as_bytes());
+
+    let credential = base64::encode(mac.finalize().into_bytes());
+
+    IceServers {
+        urls: ICE_URIS.clone(),
+        username: Some(username),
+        credential: Some(credential),
+        ttl: *TURN_CREDENTIAL_TTL,
+    }
+}
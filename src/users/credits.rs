@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use poem_openapi::{Enum, Object};
+use scylla::IntoTypedRows;
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+use crate::db::{self, Session};
+use crate::utils::{new_time_uuid, Timestamp};
+
+
+/// How many times a balance update is retried when it races another.
+const MAX_APPLY_ATTEMPTS: usize = 5;
+
+/// How long a user has to wait between votes on the same target, in
+/// milliseconds.
+const VOTE_WINDOW_MILLIS: i64 = 43_200_000;
+
+
+/// Why a user's credits changed.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum CreditReason {
+    /// Credits granted by a superuser.
+    Grant,
+
+    /// A credit spent up-voting a playlist.
+    PlaylistVote,
+
+    /// A credit spent up-voting a playlist entry.
+    EntryVote,
+
+    /// Credits given back because what they were spent on failed.
+    Refund,
+}
+
+#[derive(Object)]
+pub struct CreditEntry {
+    pub id: Uuid,
+
+    /// How many credits were added, negative if they were spent.
+    pub amount: i32,
+
+    /// The user's balance after this change.
+    pub balance: i32,
+    pub reason: CreditReason,
+    pub created_at: Timestamp,
+}
+
+#[derive(Object)]
+pub struct CreditHistoryPage {
+    /// The entries on this page, newest first.
+    pub entries: Vec<CreditEntry>,
+
+    /// The id to pass as `before` to fetch the next page, if there is one.
+    pub next_cursor: Option<Uuid>,
+}
+
+/// The result of changing a user's credits.
+pub enum CreditOutcome {
+    /// The change was made, leaving the user with the given balance.
+    Applied(i32),
+
+    /// A change with the same idempotency key was already made.
+    Duplicate,
+
+    /// The user doesn't have enough credits to spend.
+    Insufficient,
+}
+
+struct StoredBalance {
+    credits: Option<i32>,
+
+    /// The balance each recently applied ledger entry left, see
+    /// `update_balance`.
+    applied_entries: HashMap<Uuid, i32>,
+}
+
+
+/// Gets the amount of credits the user currently has.
+pub async fn get_balance(sess: &Session, user_id: i64) -> Result<i32> {
+    let balance = get_stored_balance(sess, user_id).await?;
+    Ok(balance.and_then(|v| v.credits).unwrap_or(0))
+}
+
+/// Adds `amount` credits to the user's balance, or spends them if it's
+/// negative, recording the change in their ledger.
+///
+/// Each change has an idempotency key, a key repeated within 7 days is a
+/// `Duplicate` and changes nothing. The change is written to the ledger
+/// before the balance is touched so a change interrupted part way, e.g.
+/// by a timeout, is finished when it's retried with the same key. The
+/// balance is only ever updated with a lightweight transaction against
+/// its last value so concurrent spends can't take it below zero.
+pub async fn apply(
+    sess: &Session,
+    user_id: i64,
+    amount: i32,
+    reason: CreditReason,
+    idempotency_key: &str,
+) -> Result<CreditOutcome> {
+    let id = new_time_uuid();
+
+    let result = sess.query_prepared(
+        r#"
+        INSERT INTO credit_idempotency (user_id, idempotency_key, entry_id)
+        VALUES (?, ?, ?)
+        IF NOT EXISTS;
+        "#,
+        (user_id, idempotency_key, id)
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return resume(sess, user_id, idempotency_key).await
+    }
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO credit_ledger (
+            user_id,
+            id,
+            amount,
+            reason,
+            idempotency_key,
+            created_at
+        ) VALUES (?, ?, ?, ?, ?, ?);
+        "#,
+        (user_id, id, amount, reason.to_string(), idempotency_key, Timestamp::now())
+    ).await?;
+
+    finish_entry(sess, user_id, id, amount, idempotency_key).await
+}
+
+/// Gives back credits spent with the given idempotency key, e.g. because
+/// the vote they paid for couldn't be saved.
+///
+/// The refund is recorded in the ledger and the key is released so the
+/// change can be made again.
+pub async fn refund(sess: &Session, user_id: i64, spent: i32, idempotency_key: &str) -> Result<()> {
+    let entry_id = match get_claimed_entry(sess, user_id, idempotency_key).await? {
+        None => return Ok(()),
+        Some(entry_id) => entry_id,
+    };
+
+    let refund_key = format!("refund:{}", entry_id);
+    apply(sess, user_id, spent, CreditReason::Refund, &refund_key).await?;
+    release_key(sess, user_id, idempotency_key).await
+}
+
+/// The idempotency key for spending a credit on a vote.
+///
+/// Votes on the same target in the same 12 hour window share a key, so a
+/// repeated or concurrent vote only spends once.
+pub fn vote_key(reason: CreditReason, target_id: Uuid) -> String {
+    let window = *Timestamp::now() / VOTE_WINDOW_MILLIS;
+    format!("{}:{}:{}", reason, target_id, window)
+}
+
+/// Gets a page of the user's credit history, newest first.
+///
+/// Only entries older than `before` are returned if it's given.
+pub async fn get_history(
+    sess: &Session,
+    user_id: i64,
+    before: Option<Uuid>,
+    limit: u32,
+) -> Result<CreditHistoryPage> {
+    let result = match before {
+        None => sess.query_prepared(
+            r#"
+            SELECT id, amount, balance, reason, created_at
+            FROM credit_ledger
+            WHERE user_id = ?
+            LIMIT ?;
+            "#,
+            (user_id, limit as i32),
+        ).await?,
+        Some(before) => sess.query_prepared(
+            r#"
+            SELECT id, amount, balance, reason, created_at
+            FROM credit_ledger
+            WHERE user_id = ? AND id < ?
+            LIMIT ?;
+            "#,
+            (user_id, before, limit as i32),
+        ).await?,
+    };
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    // Entries without a balance haven't been applied yet.
+    let entries: Vec<CreditEntry> = rows.into_typed::<(Uuid, i32, Option<i32>, String, Timestamp)>()
+        .filter_map(|v| v.ok())
+        .filter_map(|v| {
+            Some(CreditEntry {
+                id: v.0,
+                amount: v.1,
+                balance: v.2?,
+                reason: CreditReason::from_str(&v.3).ok()?,
+                created_at: v.4,
+            })
+        })
+        .collect();
+
+    let next_cursor = if entries.len() as u32 >= limit {
+        entries.last().map(|v| v.id)
+    } else {
+        None
+    };
+
+    Ok(CreditHistoryPage { entries, next_cursor })
+}
+
+
+/// Gets the user's balance as it's stored along with the ledger entries
+/// which have been applied to it but not yet marked as such, `None` if
+/// the user has no balance yet.
+async fn get_stored_balance(sess: &Session, user_id: i64) -> Result<Option<StoredBalance>> {
+    let result = sess.query_prepared(
+        "SELECT credits, applied_entries FROM user_vote_credits WHERE user_id = ?;",
+        (user_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let balance = rows.into_typed::<(Option<i32>, Option<HashMap<Uuid, i32>>)>()
+        .next()
+        .transpose()?
+        .map(|v| StoredBalance {
+            credits: v.0,
+            applied_entries: v.1.unwrap_or_default(),
+        });
+
+    Ok(balance)
+}
+
+/// Finishes a change which has been written to the ledger, applying it to
+/// the balance if it hasn't been already.
+///
+/// Changes which would take the balance below zero are removed from the
+/// ledger and their key is released, errors leave both in place so the
+/// change can be finished by a retry.
+async fn finish_entry(
+    sess: &Session,
+    user_id: i64,
+    id: Uuid,
+    amount: i32,
+    idempotency_key: &str,
+) -> Result<CreditOutcome> {
+    let balance = match update_balance(sess, user_id, id, amount).await? {
+        Some(balance) => balance,
+        None => {
+            sess.query_prepared(
+                "DELETE FROM credit_ledger WHERE user_id = ? AND id = ?;",
+                (user_id, id)
+            ).await?;
+
+            release_key(sess, user_id, idempotency_key).await?;
+            return Ok(CreditOutcome::Insufficient)
+        },
+    };
+
+    sess.query_prepared(
+        "UPDATE credit_ledger SET balance = ? WHERE user_id = ? AND id = ?;",
+        (balance, user_id, id)
+    ).await?;
+
+    sess.query_prepared(
+        "DELETE applied_entries[?] FROM user_vote_credits WHERE user_id = ? IF EXISTS;",
+        (id, user_id)
+    ).await?;
+
+    Ok(CreditOutcome::Applied(balance))
+}
+
+/// Handles a change whose idempotency key was already claimed, finishing
+/// it if the earlier attempt was interrupted.
+async fn resume(sess: &Session, user_id: i64, idempotency_key: &str) -> Result<CreditOutcome> {
+    let entry_id = match get_claimed_entry(sess, user_id, idempotency_key).await? {
+        None => return Ok(CreditOutcome::Duplicate),
+        Some(entry_id) => entry_id,
+    };
+
+    let result = sess.query_prepared(
+        "SELECT amount, balance FROM credit_ledger WHERE user_id = ? AND id = ?;",
+        (user_id, entry_id)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let amount = match rows.into_typed::<(i32, Option<i32>)>().next().transpose()? {
+        Some((amount, None)) => amount,
+        _ => return Ok(CreditOutcome::Duplicate),
+    };
+
+    finish_entry(sess, user_id, entry_id, amount, idempotency_key).await
+}
+
+/// Gets the id of the ledger entry which claimed the idempotency key.
+async fn get_claimed_entry(sess: &Session, user_id: i64, idempotency_key: &str) -> Result<Option<Uuid>> {
+    let result = sess.query_prepared(
+        "SELECT entry_id FROM credit_idempotency WHERE user_id = ? AND idempotency_key = ?;",
+        (user_id, idempotency_key)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let entry_id = rows.into_typed::<(Option<Uuid>,)>()
+        .next()
+        .transpose()?
+        .and_then(|v| v.0);
+
+    Ok(entry_id)
+}
+
+/// Moves the user's balance by `amount` for the given ledger entry,
+/// returning the new balance or `None` if it would go below zero.
+///
+/// The entry is recorded alongside the balance in the same transaction
+/// so it's never applied twice, if it already has been the balance it
+/// left is returned instead.
+async fn update_balance(sess: &Session, user_id: i64, id: Uuid, amount: i32) -> Result<Option<i32>> {
+    for _ in 0..MAX_APPLY_ATTEMPTS {
+        let current = get_stored_balance(sess, user_id).await?;
+
+        if let Some(balance) = current.as_ref().and_then(|v| v.applied_entries.get(&id)) {
+            return Ok(Some(*balance))
+        }
+
+        let balance = current.as_ref().and_then(|v| v.credits).unwrap_or(0) + amount;
+        if balance < 0 {
+            return Ok(None)
+        }
+
+        let applied = HashMap::from([(id, balance)]);
+        let result = match current {
+            None => sess.query_prepared(
+                "INSERT INTO user_vote_credits (user_id, credits, applied_entries) VALUES (?, ?, ?) IF NOT EXISTS;",
+                (user_id, balance, applied)
+            ).await?,
+            Some(current) => sess.query_prepared(
+                "UPDATE user_vote_credits SET credits = ?, applied_entries = applied_entries + ? WHERE user_id = ? IF credits = ?;",
+                (balance, applied, user_id, current.credits)
+            ).await?,
+        };
+
+        if db::was_applied(&result) {
+            return Ok(Some(balance))
+        }
+    }
+
+    Err(anyhow!("failed to update credit balance after {} attempts", MAX_APPLY_ATTEMPTS))
+}
+
+/// Forgets an idempotency key so a change which wasn't made can be retried.
+async fn release_key(sess: &Session, user_id: i64, idempotency_key: &str) -> Result<()> {
+    sess.query_prepared(
+        "DELETE FROM credit_idempotency WHERE user_id = ? AND idempotency_key = ? IF EXISTS;",
+        (user_id, idempotency_key)
+    ).await?;
+
+    Ok(())
+}
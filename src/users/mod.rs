@@ -2,23 +2,37 @@ pub mod user_info;
 pub mod notifications;
 pub mod room_info;
 pub mod playlist_info;
+pub mod preferences;
+pub mod credits;
 
+use std::sync::Arc;
 use poem::web::Data;
 use poem::Result;
 use poem_openapi::payload::Json;
 use poem_openapi::{Object, OpenApi};
 use poem_openapi::param::Query;
-use serde_json::Value;
+use poem_openapi::types::ToJSON;
+use serde_json::{json, Value};
 use uuid::Uuid;
 
 use user_info::{User, Guild};
 
 use crate::ApiTags;
-use crate::utils::{JsonResponse, SuperUserBearer, TokenBearer};
+use crate::admin::audit::{self, AuditAction, AuditRecord, TargetKind};
+use crate::admin::roles::Permission;
+use crate::auth::api_keys::{self, ApiKey, ApiScope, CreatedApiKey};
+use crate::auth::identity::UserCache;
+use crate::auth::refresh;
+use crate::utils::{JsonResponse, NotificationsReadBearer, ReadPlaylistsBearer, RoomsControlBearer, SuperUserBearer, Timestamp, UserBearer};
 use crate::db::Session;
+use crate::notifications::system;
 use crate::playlists::{get_playlist_by_id, Playlist, PlaylistEntry};
-use crate::rooms::models::{ArchivedRoom, Room};
-use crate::users::notifications::Notification;
+use crate::rooms::access;
+use crate::rtc::events::CloseReason;
+use crate::rooms::models::{ArchivedRoom, Room, RoomRole};
+use crate::users::credits::{CreditHistoryPage, CreditOutcome, CreditReason};
+use crate::users::notifications::{Icons, NotificationPage};
+use crate::users::preferences::Preferences;
 
 
 #[derive(Object)]
@@ -26,6 +40,65 @@ pub struct CreditResponse {
     credits: i32,
 }
 
+#[derive(Object)]
+pub struct UnreadResponse {
+    unread: i64,
+}
+
+#[derive(Object)]
+pub struct NotificationIdsPayload {
+    #[oai(validator(min_items = 1, max_items = 100))]
+    ids: Vec<Uuid>,
+}
+
+#[derive(Object)]
+pub struct NotificationReadPayload {
+    #[oai(validator(min_items = 1, max_items = 100))]
+    ids: Vec<Uuid>,
+
+    /// Whether to mark the notifications as read or unread.
+    #[oai(default = "default_read")]
+    read: bool,
+}
+
+#[derive(Object)]
+pub struct PreferencesPayload {
+    /// The notification categories to mute, any others are unmuted.
+    muted_categories: Vec<Icons>,
+}
+
+#[derive(Object)]
+pub struct CategoryMutePayload {
+    category: Icons,
+    muted: bool,
+}
+
+#[derive(Object)]
+pub struct ApiKeyPayload {
+    #[oai(validator(max_length = 64, min_length = 1))]
+    name: String,
+
+    #[oai(validator(min_items = 1))]
+    scopes: Vec<ApiScope>,
+
+    /// When the key stops working, if not set the key lasts until it's
+    /// revoked.
+    expires_at: Option<Timestamp>,
+}
+
+
+fn default_notifications_page_size() -> u32 {
+    50
+}
+
+fn default_read() -> bool {
+    true
+}
+
+fn default_credit_history_page_size() -> u32 {
+    50
+}
+
 pub struct UsersApi;
 
 #[OpenApi]
@@ -36,14 +109,9 @@ impl UsersApi {
     #[oai(path = "/users/@me", method = "get", tag = "ApiTags::User")]
     pub async fn get_user(
         &self,
-        session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<User>> {
-        if let Some(user) = user_info::get_user_from_token(&session, &token.0.token).await? {
-            Ok(JsonResponse::ok(user))
-        } else {
-            Ok(JsonResponse::unauthorized())
-        }
+        Ok(JsonResponse::ok(token.0.user))
     }
 
     /// Get User Credits
@@ -53,13 +121,31 @@ impl UsersApi {
     pub async fn get_user_credits(
         &self,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<CreditResponse>> {
-        if let Some(credits) = user_info::get_vote_credits_for_token(&session, &token.0.token).await? {
-            Ok(JsonResponse::ok(CreditResponse { credits }))
-        } else {
-            Ok(JsonResponse::unauthorized())
-        }
+        let credits = credits::get_balance(&session, token.0.id).await?;
+
+        Ok(JsonResponse::ok(CreditResponse { credits }))
+    }
+
+    /// Get User Credit History
+    ///
+    /// Get a page of every change to the user's voting credits, newest
+    /// first.
+    ///
+    /// Pass the `next_cursor` of a page as `before` to get the next one.
+    #[oai(path = "/users/@me/credits/history", method = "get", tag = "ApiTags::User")]
+    pub async fn get_user_credit_history(
+        &self,
+        before: Query<Option<Uuid>>,
+        #[oai(default = "default_credit_history_page_size", validator(minimum(value = "1"), maximum(value = "100")))]
+        limit: Query<u32>,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<CreditHistoryPage>> {
+        let page = credits::get_history(&session, token.0.id, before.0, limit.0).await?;
+
+        Ok(JsonResponse::ok(page))
     }
 
     /// Get User Guilds
@@ -69,30 +155,71 @@ impl UsersApi {
     pub async fn get_user_guilds(
         &self,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<Vec<Guild>>> {
-        if let Some(guilds) = user_info::get_user_guilds_from_token(&session, &token.0.token).await? {
-            Ok(JsonResponse::ok(guilds))
-        } else {
-            Ok(JsonResponse::unauthorized())
+        let guilds = user_info::get_user_guilds(&session, &token.0.user).await?;
+
+        Ok(JsonResponse::ok(guilds))
+    }
+
+    /// Refresh User
+    ///
+    /// Refreshes the user's name, avatar and guilds from Discord using
+    /// their stored Discord authorization. Users who need to log in again
+    /// get a 400.
+    #[oai(path = "/users/@me/refresh", method = "post", tag = "ApiTags::User")]
+    pub async fn refresh_user(
+        &self,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<User>> {
+        let user_id = token.0.id;
+
+        if !refresh::resync_user(&session, user_id).await? {
+            return Ok(JsonResponse::bad_request("Your Discord authorization has expired, please log in again."))
+        }
+
+        match user_info::get_user_from_id(&session, user_id).await? {
+            None => Ok(JsonResponse::unauthorized()),
+            Some(user) => Ok(JsonResponse::ok(user)),
         }
     }
 
     /// Get User Notifications
     ///
-    /// Get the user's pending notifications.
+    /// Get a page of the user's notifications, newest first.
+    ///
+    /// Pass the `next_cursor` of a page as `before` to get the next one.
     #[oai(path = "/users/@me/notifications", method = "get", tag = "ApiTags::User")]
     pub async fn get_user_notifications(
         &self,
+        before: Query<Option<Uuid>>,
+        #[oai(default = "default_notifications_page_size", validator(minimum(value = "1"), maximum(value = "100")))]
+        limit: Query<u32>,
         session: Data<&Session>,
-        token: TokenBearer,
-    ) -> Result<JsonResponse<Vec<Notification>>> {
-        let res = notifications::get_user_notifications_for_token(&session, &token.0.token).await?;
-        if let Some(ns) = res {
-            Ok(JsonResponse::ok(ns))
-        } else {
-            Ok(JsonResponse::unauthorized())
-        }
+        token: NotificationsReadBearer,
+    ) -> Result<JsonResponse<NotificationPage>> {
+        let user_id = token.0.id;
+
+        let page = notifications::get_user_notifications(&session, user_id, before.0, limit.0).await?;
+
+        Ok(JsonResponse::ok(page))
+    }
+
+    /// Get User Unread Notification Count
+    ///
+    /// Get how many notifications the user has yet to read.
+    #[oai(path = "/users/@me/notifications/unread", method = "get", tag = "ApiTags::User")]
+    pub async fn get_user_unread_notifications(
+        &self,
+        session: Data<&Session>,
+        token: NotificationsReadBearer,
+    ) -> Result<JsonResponse<UnreadResponse>> {
+        let user_id = token.0.id;
+
+        let unread = notifications::count_unread_notifications(&session, user_id).await?;
+
+        Ok(JsonResponse::ok(UnreadResponse { unread }))
     }
 
     /// Remove User Notification
@@ -103,19 +230,123 @@ impl UsersApi {
         &self,
         id: Query<Uuid>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+
+        notifications::delete_notifications(&session, user_id, vec![id.0]).await?;
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Bulk Remove User Notifications
+    ///
+    /// Removes all of the given notifications from the user.
+    #[oai(path = "/users/@me/notifications/bulk-delete", method = "post", tag = "ApiTags::User")]
+    pub async fn bulk_remove_user_notifications(
+        &self,
+        payload: Json<NotificationIdsPayload>,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+
+        notifications::delete_notifications(&session, user_id, payload.0.ids).await?;
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Set User Notifications Read
+    ///
+    /// Marks the given notifications as read or unread.
+    #[oai(path = "/users/@me/notifications/read", method = "put", tag = "ApiTags::User")]
+    pub async fn set_user_notifications_read(
+        &self,
+        payload: Json<NotificationReadPayload>,
+        session: Data<&Session>,
+        token: UserBearer,
     ) -> Result<JsonResponse<Value>> {
-        let res = notifications::delete_user_notification(
+        let user_id = token.0.id;
+
+        notifications::set_notifications_read(
             &session,
-            &token.0.token,
-            id.0,
+            user_id,
+            payload.0.ids,
+            payload.0.read,
         ).await?;
 
-        if res.is_some() {
-            Ok(JsonResponse::ok(Value::Null))
-        } else {
-            Ok(JsonResponse::unauthorized())
-        }
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Mark All User Notifications Read
+    ///
+    /// Marks all of the user's notifications as read.
+    #[oai(path = "/users/@me/notifications/read-all", method = "post", tag = "ApiTags::User")]
+    pub async fn mark_all_user_notifications_read(
+        &self,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+
+        notifications::mark_all_read(&session, user_id).await?;
+
+        Ok(JsonResponse::ok(Value::Null))
+    }
+
+    /// Get User Preferences
+    ///
+    /// Get the user's notification preferences.
+    #[oai(path = "/users/@me/preferences", method = "get", tag = "ApiTags::User")]
+    pub async fn get_user_preferences(
+        &self,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Preferences>> {
+        let user_id = token.0.id;
+
+        let prefs = preferences::get_preferences(&session, user_id).await?;
+
+        Ok(JsonResponse::ok(prefs))
+    }
+
+    /// Update User Preferences
+    ///
+    /// Replaces the user's notification preferences.
+    #[oai(path = "/users/@me/preferences", method = "put", tag = "ApiTags::User")]
+    pub async fn update_user_preferences(
+        &self,
+        payload: Json<PreferencesPayload>,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Preferences>> {
+        let user_id = token.0.id;
+
+        let muted = payload.0.muted_categories.into_iter().collect();
+        preferences::set_muted_categories(&session, user_id, &muted).await?;
+
+        let prefs = preferences::get_preferences(&session, user_id).await?;
+
+        Ok(JsonResponse::ok(prefs))
+    }
+
+    /// Mute User Notification Category
+    ///
+    /// Mutes or unmutes a single notification category for the user.
+    #[oai(path = "/users/@me/preferences/mute", method = "put", tag = "ApiTags::User")]
+    pub async fn mute_user_notification_category(
+        &self,
+        payload: Json<CategoryMutePayload>,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Preferences>> {
+        let user_id = token.0.id;
+
+        preferences::set_category_muted(&session, user_id, payload.0.category, payload.0.muted).await?;
+
+        let prefs = preferences::get_preferences(&session, user_id).await?;
+
+        Ok(JsonResponse::ok(prefs))
     }
 
     /// Get User Active Room
@@ -125,12 +356,11 @@ impl UsersApi {
     pub async fn get_user_active_room(
         &self,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<Option<Room>>> {
-        match room_info::get_active_room_for_token(&session, &token.0.token).await? {
-            None => Ok(JsonResponse::unauthorized()),
-            Some(room) =>  Ok(JsonResponse::ok(room)),
-        }
+        let room = room_info::get_active_room_for_user_id(&session, token.0.id).await?;
+
+        Ok(JsonResponse::ok(room))
     }
 
     /// Close User Active Room
@@ -140,13 +370,20 @@ impl UsersApi {
     pub async fn close_user_active_room(
         &self,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<Value>> {
-        match room_info::get_active_room_for_token(&session, &token.0.token).await? {
-            None => Ok(JsonResponse::unauthorized()),
-            Some(None) => Ok(JsonResponse::ok(Value::Null)),
-            Some(Some(room)) => {
-                crate::rooms::set_room_inactive(&session, room).await?;
+        match room_info::get_active_room_for_user_id(&session, token.0.id).await? {
+            None => Ok(JsonResponse::ok(Value::Null)),
+            Some(room) => {
+                crate::rooms::set_room_inactive(&session, room.clone(), CloseReason::Owner).await?;
+                audit::record(&session, AuditRecord {
+                    actor_id: Some(*room.owner_id),
+                    action: AuditAction::RoomClosed,
+                    target_kind: TargetKind::Room,
+                    target_id: room.id.to_string(),
+                    before: Some(room.to_json()),
+                    after: None,
+                }).await;
 
                 Ok(JsonResponse::ok(Value::Null))
             }
@@ -156,29 +393,34 @@ impl UsersApi {
     /// Set Current Room Playlist
     ///
     /// Sets the user's active room playlist if applicable.
+    ///
+    /// Co-hosts can set the playlist of the room they co-host by passing
+    /// its `room_id`, otherwise the user's own room is used.
     #[oai(path = "/users/@me/rooms/playlist", method = "put", tag = "ApiTags::User")]
     pub async fn update_active_room_playlist(
         &self,
         playlist_id: Query<Uuid>,
+        room_id: Query<Option<Uuid>>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: RoomsControlBearer,
     ) -> Result<JsonResponse<Room>> {
         let playlist = match get_playlist_by_id(&session, playlist_id.0).await? {
             None => return Ok(JsonResponse::bad_request("No playlist exists with this id.")),
             Some(playlist) => playlist,
         };
 
-        let room = match room_info::get_active_room_for_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(room) =>  room,
-        };
+        let user_id = token.0.id;
 
-        let mut room = match room {
+        let mut room = match room_info::get_playback_room(&session, user_id, room_id.0).await? {
             None => return Ok(JsonResponse::bad_request("User has no active room.")),
             Some(room) => room,
         };
 
-        crate::rooms::set_room_playlist(&session, room.id.clone(), playlist.id).await?;
+        if !access::has_room_role(&session, &room, user_id, RoomRole::CoHost).await? {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        crate::rooms::set_room_playlist(&session, room.id, playlist.id).await?;
 
         room.active_playlist = Some(playlist.id);
 
@@ -188,24 +430,29 @@ impl UsersApi {
     /// Set Current Room Now Playing
     ///
     /// Sets the user's active room playing now entry if applicable.
+    ///
+    /// Co-hosts can set the entry of the room they co-host by passing
+    /// its `room_id`, otherwise the user's own room is used.
     #[oai(path = "/users/@me/rooms/entry", method = "put", tag = "ApiTags::User")]
     pub async fn update_active_room_active_entry(
         &self,
         entry_id: Query<Uuid>,
+        room_id: Query<Option<Uuid>>,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: RoomsControlBearer,
     ) -> Result<JsonResponse<Room>> {
-        let room = match room_info::get_active_room_for_token(&session, &token.0.token).await? {
-            None => return Ok(JsonResponse::unauthorized()),
-            Some(room) =>  room,
-        };
+        let user_id = token.0.id;
 
-        let mut room = match room {
+        let mut room = match room_info::get_playback_room(&session, user_id, room_id.0).await? {
             None => return Ok(JsonResponse::bad_request("User has no active room.")),
             Some(room) => room,
         };
 
-        let active_id = match room.active_playlist.clone() {
+        if !access::has_room_role(&session, &room, user_id, RoomRole::CoHost).await? {
+            return Ok(JsonResponse::forbidden())
+        }
+
+        let active_id = match room.active_playlist {
             None => return Ok(JsonResponse::bad_request("No playlist selected.")),
             Some(active_id) => active_id,
         };
@@ -221,12 +468,10 @@ impl UsersApi {
 
         crate::rooms::set_room_currently_playing(
             &session,
-            room.id.clone(),
-            entry_id.0.clone(),
+            &mut room,
+            entry_id.0,
         ).await?;
 
-        room.playing_now = Some(entry_id.0);
-
         Ok(JsonResponse::Ok(Json(room)))
     }
 
@@ -237,12 +482,11 @@ impl UsersApi {
     pub async fn get_user_rooms(
         &self,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: UserBearer,
     ) -> Result<JsonResponse<Vec<ArchivedRoom>>> {
-        match room_info::get_archived_rooms(&session, &token.0.token).await? {
-            None => Ok(JsonResponse::unauthorized()),
-            Some(rooms) =>  Ok(JsonResponse::ok(rooms)),
-        }
+        let rooms = room_info::get_archived_rooms(&session, token.0.id).await?;
+
+        Ok(JsonResponse::ok(rooms))
     }
 
     /// Get User Playlists
@@ -252,12 +496,11 @@ impl UsersApi {
     pub async fn get_user_playlists(
         &self,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: ReadPlaylistsBearer,
     ) -> Result<JsonResponse<Vec<Playlist>>> {
-        match playlist_info::get_playlists_for_token(&session, &token.0.token).await? {
-            None => Ok(JsonResponse::unauthorized()),
-            Some(playlists) =>  Ok(JsonResponse::ok(playlists)),
-        }
+        let playlists = playlist_info::get_playlists_for_user(&session, token.0.id).await?;
+
+        Ok(JsonResponse::ok(playlists))
     }
 
     /// Get User Playlist Entries
@@ -267,30 +510,150 @@ impl UsersApi {
     pub async fn get_user_entries(
         &self,
         session: Data<&Session>,
-        token: TokenBearer,
+        token: ReadPlaylistsBearer,
     ) -> Result<JsonResponse<Vec<PlaylistEntry>>> {
-        match playlist_info::get_playlist_entries_for_token(&session, &token.0.token).await? {
-            None => Ok(JsonResponse::unauthorized()),
-            Some(entries) =>  Ok(JsonResponse::ok(entries)),
+        let entries = playlist_info::get_playlist_entries_for_user(&session, token.0.id).await?;
+
+        Ok(JsonResponse::ok(entries))
+    }
+
+    /// Get User API Keys
+    ///
+    /// Get all of the user's API keys, newest first. The keys themselves
+    /// are only shown when they're created.
+    #[oai(path = "/users/@me/keys", method = "get", tag = "ApiTags::User")]
+    pub async fn get_user_api_keys(
+        &self,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Vec<ApiKey>>> {
+        let user_id = token.0.id;
+
+        let keys = api_keys::list_keys(&session, user_id).await?;
+
+        Ok(JsonResponse::ok(keys))
+    }
+
+    /// Create User API Key
+    ///
+    /// Creates a named API key limited to the given scopes, for use by
+    /// integrations in place of an access token.
+    ///
+    /// The key is only returned here so it must be saved now. API keys
+    /// can't be used to manage API keys.
+    ///
+    /// Keys can expire at most a year from now. The limit on how many keys
+    /// a user can have is checked before the key is created, so concurrent
+    /// requests can briefly take a user over it.
+    #[oai(path = "/users/@me/keys", method = "post", tag = "ApiTags::User")]
+    pub async fn create_user_api_key(
+        &self,
+        payload: Json<ApiKeyPayload>,
+        session: Data<&Session>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<CreatedApiKey>> {
+        let user_id = token.0.id;
+
+        if let Some(expires_at) = payload.0.expires_at {
+            if *expires_at <= *Timestamp::now() {
+                return Ok(JsonResponse::bad_request("The expiry must be in the future."))
+            }
+
+            if *expires_at > *Timestamp::now() + api_keys::MAX_KEY_LIFETIME_MILLIS {
+                return Ok(JsonResponse::bad_request("The expiry must be within a year."))
+            }
+        }
+
+        let existing = api_keys::list_keys(&session, user_id).await?;
+        if existing.len() >= api_keys::MAX_KEYS_PER_USER {
+            return Ok(JsonResponse::bad_request("You have too many API keys, revoke one first."))
+        }
+
+        let key = api_keys::create_key(
+            &session,
+            user_id,
+            payload.0.name,
+            payload.0.scopes.into_iter().collect(),
+            payload.0.expires_at,
+        ).await?;
+
+        Ok(JsonResponse::ok(key))
+    }
+
+    /// Revoke User API Key
+    ///
+    /// Revokes one of the user's API keys, it stops working immediately.
+    #[oai(path = "/users/@me/keys", method = "delete", tag = "ApiTags::User")]
+    pub async fn revoke_user_api_key(
+        &self,
+        id: Query<Uuid>,
+        session: Data<&Session>,
+        cache: Data<&Arc<UserCache>>,
+        token: UserBearer,
+    ) -> Result<JsonResponse<Value>> {
+        let user_id = token.0.id;
+
+        if !api_keys::revoke_key(&session, &cache, user_id, id.0).await? {
+            return Ok(JsonResponse::bad_request("No API key exists with this id."))
         }
+
+        audit::record(&session, AuditRecord {
+            actor_id: Some(user_id),
+            action: AuditAction::ApiKeyRevoked,
+            target_kind: TargetKind::ApiKey,
+            target_id: id.0.to_string(),
+            before: None,
+            after: None,
+        }).await;
+
+        Ok(JsonResponse::ok(Value::Null))
     }
 
     /// Add User Credits
     ///
     /// Add the user credits associated with a given token.
+    ///
+    /// Repeating a request with the same `idempotency_key` only grants the
+    /// credit once, if no key is given every request grants one.
     #[oai(path = "/users/credit", method = "post", tag = "ApiTags::User")]
     pub async fn add_user_credits(
         &self,
         id: Query<i64>,
+        #[oai(validator(max_length = 128, min_length = 1))]
+        idempotency_key: Query<Option<String>>,
         session: Data<&Session>,
-        _token: SuperUserBearer,
+        superuser: SuperUserBearer,
     ) -> Result<JsonResponse<Value>> {
+        if !superuser.0.has(Permission::GrantCredits) {
+            return Ok(JsonResponse::forbidden())
+        }
+
         let user = user_info::get_user_from_id(&session, id.0).await?;
         if user.is_none() {
             return Ok(JsonResponse::bad_request("This user does not exist."))
         }
 
-        user_info::adjust_user_credits(&session, id.0, 1).await?;
+        let key = match idempotency_key.0 {
+            None => format!("{}:{}", CreditReason::Grant, Uuid::new_v4()),
+            Some(key) => format!("{}:{}", CreditReason::Grant, key),
+        };
+
+        let credits = match credits::apply(&session, id.0, 1, CreditReason::Grant, &key).await? {
+            CreditOutcome::Applied(credits) => credits,
+            CreditOutcome::Duplicate | CreditOutcome::Insufficient => {
+                return Ok(JsonResponse::ok(Value::Null))
+            },
+        };
+
+        audit::record(&session, AuditRecord {
+            actor_id: superuser.0.user_id,
+            action: AuditAction::CreditsGranted,
+            target_kind: TargetKind::User,
+            target_id: id.0.to_string(),
+            before: Some(json!({ "credits": credits - 1 })),
+            after: Some(json!({ "credits": credits })),
+        }).await;
+        system::credits_granted(&session, id.0, 1).await;
 
         Ok(JsonResponse::ok(Value::Null))
     }
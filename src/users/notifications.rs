@@ -5,10 +5,22 @@ use scylla::IntoTypedRows;
 use strum::{Display, EnumString};
 use uuid::Uuid;
 
-use crate::db::Session;
-use super::user_info;
+use crate::db::{self, Session};
+use crate::playlists::get_playlist_by_id;
+use crate::rooms::get_room_by_id;
+use crate::users::preferences;
+use crate::rtc::events::{self, NotificationCreated, NotificationsUpdated};
+use crate::utils::{new_time_uuid, Timestamp};
 
-#[derive(Enum, Display, EnumString)]
+
+/// The furthest away a notification can expire, in seconds. This is the
+/// longest TTL Scylla accepts.
+pub const MAX_EXPIRY_SECS: i64 = 630_720_000;
+
+
+/// The icon shown with a notification, this doubles as the category
+/// users can mute.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[strum(serialize_all = "lowercase", ascii_case_insensitive)]
 #[oai(rename_all = "lowercase")]
 pub enum Icons {
@@ -18,9 +30,30 @@ pub enum Icons {
     Coins,
     Issues,
     Discord,
+    Votes,
 }
 
 
+/// What a notification's action opens.
+#[derive(Enum, Display, EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum ActionKind {
+    Room,
+    Playlist,
+    Url,
+}
+
+/// Makes a notification clickable.
+#[derive(Object, Clone)]
+pub struct NotificationAction {
+    pub kind: ActionKind,
+
+    /// The id of the room or playlist, or the URL to open.
+    #[oai(validator(max_length = 512, min_length = 1))]
+    pub target: String,
+}
+
 #[derive(Object)]
 pub struct Notification {
     pub id: Uuid,
@@ -28,62 +61,597 @@ pub struct Notification {
     pub description: Option<String>,
     pub created_on: i64,
     pub icon: Option<Icons>,
+    pub read: bool,
+    pub action: Option<NotificationAction>,
+
+    /// When the notification is removed, it's kept until deleted if not set.
+    pub expires_at: Option<Timestamp>,
+}
+
+/// The outcome of creating a notification.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Delivery {
+    /// The notification with the given id was created and pushed to the
+    /// recipient.
+    Delivered(Uuid),
+
+    /// The recipient has muted the notification's category.
+    Suppressed,
+
+    /// The notification expired before it could be delivered.
+    Expired,
+}
+
+/// Everything needed to create a notification.
+#[derive(Clone)]
+pub struct NotificationContent {
+    pub title: String,
+    pub description: Option<String>,
+    pub icon: Option<Icons>,
+    pub action: Option<NotificationAction>,
+    pub expires_at: Option<Timestamp>,
+}
+
+#[derive(Object)]
+pub struct NotificationPage {
+    /// The notifications on this page, newest first.
+    pub notifications: Vec<Notification>,
+
+    /// The id to pass as `before` to fetch the next page, if there is one.
+    pub next_cursor: Option<Uuid>,
 }
 
 
-pub async fn get_user_notifications_for_token(
+/// Gets a page of the user's notifications, newest first.
+///
+/// Only notifications older than `before` are returned if it's given.
+pub async fn get_user_notifications(
     sess: &Session,
-    token: &str,
-) -> Result<Option<Vec<Notification>>> {
-    let user_id = match user_info::get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
+    user_id: i64,
+    before: Option<Uuid>,
+    limit: u32,
+) -> Result<NotificationPage> {
+    let result = match before {
+        None => sess.query_prepared(
+            r#"
+            SELECT id, title, description, icon, created_on, read, action_kind, action_target, expires_at
+            FROM user_notifications
+            WHERE recipient_id = ?
+            LIMIT ?;
+            "#,
+            (user_id, limit as i32),
+        ).await?,
+        Some(before) => sess.query_prepared(
+            r#"
+            SELECT id, title, description, icon, created_on, read, action_kind, action_target, expires_at
+            FROM user_notifications
+            WHERE recipient_id = ? AND id < ?
+            LIMIT ?;
+            "#,
+            (user_id, before, limit as i32),
+        ).await?,
     };
 
-    get_user_notifications(sess, user_id).await.map(Some)
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    // Rows which fail to parse are skipped but still count towards the
+    // page, otherwise they would end paging early.
+    let row_count = rows.len();
+    let last_id = rows.last()
+        .and_then(|v| v.columns.first())
+        .and_then(|v| v.as_ref())
+        .and_then(|v| v.as_uuid());
+
+    let notifications: Vec<Notification> = rows.into_typed::<NotificationInfo>()
+        .filter_map(|v| {
+            v.ok()
+        })
+        .map(notification_from_info)
+        .collect();
+
+    let next_cursor = if row_count as u32 >= limit {
+        last_id
+    } else {
+        None
+    };
+
+    Ok(NotificationPage { notifications, next_cursor })
+}
+
+
+/// Deletes the given notifications belonging to the user.
+pub async fn delete_notifications(sess: &Session, user_id: i64, ids: Vec<Uuid>) -> Result<()> {
+    for chunk in ids.chunks(db::MAX_IN_VALUES) {
+        sess.query_prepared(
+            "DELETE FROM user_notifications WHERE recipient_id = ? AND id IN ?;",
+            (user_id, chunk.to_vec()),
+        ).await?;
+
+        remove_unread(sess, user_id, chunk.to_vec()).await?;
+    }
+
+    emit_unread_count(sess, user_id).await
+}
+
+
+/// Marks the given notifications belonging to the user as read or unread.
+///
+/// Ids which don't belong to one of the user's notifications are ignored.
+pub async fn set_notifications_read(
+    sess: &Session,
+    user_id: i64,
+    ids: Vec<Uuid>,
+    read: bool,
+) -> Result<()> {
+    let mut changed = false;
+    for chunk in ids.chunks(db::MAX_IN_VALUES) {
+        // An update would create rows for unknown ids, so only touch the
+        // notifications which already exist.
+        let result = sess.query_prepared(
+            "SELECT id, expires_at FROM user_notifications WHERE recipient_id = ? AND id IN ?;",
+            (user_id, chunk.to_vec()),
+        ).await?;
+
+        let rows = result.rows
+            .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+        let existing: Vec<(Uuid, Option<Timestamp>)> = rows.into_typed::<(Uuid, Option<Timestamp>)>()
+            .filter_map(|v| v.ok())
+            .collect();
+
+        if existing.is_empty() {
+            continue;
+        }
+
+        let mut read_ids = vec![];
+        for (id, expires_at) in existing {
+            if !set_read(sess, user_id, id, expires_at, read).await? {
+                continue;
+            }
+
+            if read {
+                read_ids.push(id);
+            } else {
+                add_unread(sess, user_id, id, expires_at).await?;
+            }
+        }
+
+        if !read_ids.is_empty() {
+            remove_unread(sess, user_id, read_ids).await?;
+        }
+
+        changed = true;
+    }
+
+    if !changed {
+        return Ok(())
+    }
+
+    emit_unread_count(sess, user_id).await
 }
 
-pub async fn get_user_notifications(sess: &Session, user_id: i64) -> Result<Vec<Notification>> {
+
+/// Marks all of the user's notifications as read.
+pub async fn mark_all_read(sess: &Session, user_id: i64) -> Result<()> {
+    let ids = get_unread_ids(sess, user_id).await?;
+
+    if ids.is_empty() {
+        return Ok(())
+    }
+
+    for chunk in ids.chunks(db::MAX_IN_VALUES) {
+        let result = sess.query_prepared(
+            "SELECT id, expires_at FROM user_notifications WHERE recipient_id = ? AND id IN ?;",
+            (user_id, chunk.to_vec()),
+        ).await?;
+
+        let rows = result.rows
+            .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+        for (id, expires_at) in rows.into_typed::<(Uuid, Option<Timestamp>)>().filter_map(|v| v.ok()) {
+            set_read(sess, user_id, id, expires_at, true).await?;
+        }
+
+        remove_unread(sess, user_id, chunk.to_vec()).await?;
+    }
+
+    emit_unread_count(sess, user_id).await
+}
+
+
+/// Counts the notifications the user has yet to read.
+///
+/// Unread notifications are tracked in their own table keyed by the
+/// recipient so this only reads the recipient's partition of it.
+pub async fn count_unread_notifications(sess: &Session, user_id: i64) -> Result<i64> {
     let result = sess.query_prepared(
-        "SELECT id, title, description, icon, created_on FROM notifications WHERE recipient_id = ?;",
+        "SELECT COUNT(*) FROM unread_notifications WHERE recipient_id = ?;",
         (user_id,),
     ).await?;
 
     let rows = result.rows
         .ok_or_else(|| anyhow!("expected returned rows"))?;
 
-    type NotificationInfo = (Uuid, String, Option<String>, Option<String>, chrono::Duration);
-    let rows: Vec<Notification> = rows.into_typed::<NotificationInfo>()
-        .filter_map(|v| {
-            v.ok()
-        })
-        .map(|v| Notification {
-            id: v.0,
-            title: v.1,
-            description: v.2,
-            icon: Option::flatten(v.3.map(|v| Icons::from_str(&v).ok())),
-            created_on: v.4.num_milliseconds(),
-        })
-        .collect();
+    let count = match rows.into_typed::<(i64,)>().next() {
+        None => 0,
+        Some(v) => v?.0,
+    };
+
+    Ok(count)
+}
+
+
+/// Checks the action points to something which exists, returning why it
+/// doesn't if it's invalid.
+///
+/// Rooms must currently be open to be linked to.
+pub async fn validate_action(sess: &Session, action: &NotificationAction) -> Result<Option<&'static str>> {
+    let problem = match action.kind {
+        ActionKind::Room => match Uuid::from_str(&action.target) {
+            Err(_) => Some("Action target is not a valid room id."),
+            Ok(id) => get_room_by_id(sess, id).await?
+                .map_or(Some("Action target room does not exist."), |_| None),
+        },
+        ActionKind::Playlist => match Uuid::from_str(&action.target) {
+            Err(_) => Some("Action target is not a valid playlist id."),
+            Ok(id) => get_playlist_by_id(sess, id).await?
+                .map_or(Some("Action target playlist does not exist."), |_| None),
+        },
+        ActionKind::Url => {
+            let is_web_url = action.target.starts_with("https://")
+                | action.target.starts_with("http://");
+
+            if is_web_url {
+                None
+            } else {
+                Some("Action target must be a http or https URL.")
+            }
+        },
+    };
 
-    Ok(rows)
+    Ok(problem)
 }
 
 
-pub async fn delete_user_notification(
+/// Creates a new notification for the given recipient and pushes it to
+/// them over the realtime channel along with their new unread count.
+///
+/// Notifications with an expiry are stored with a matching TTL, nothing
+/// is created if it has already expired or the recipient has muted its
+/// category.
+///
+/// This assumes the recipient currently exists.
+pub async fn insert_notification(
+    sess: &Session,
+    recipient_id: i64,
+    content: NotificationContent,
+) -> Result<Delivery> {
+    insert_notification_with_id(sess, new_time_uuid(), recipient_id, content).await
+}
+
+/// Creates a notification like `insert_notification` with the given id,
+/// which must be a time based uuid.
+///
+/// Inserting the same id again overwrites the earlier notification.
+pub async fn insert_notification_with_id(
     sess: &Session,
-    token: &str,
     id: Uuid,
-) -> Result<Option<()>> {
-    let user_id = match user_info::get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
+    recipient_id: i64,
+    content: NotificationContent,
+) -> Result<Delivery> {
+    if preferences::is_muted(sess, recipient_id, content.icon).await? {
+        return Ok(Delivery::Suppressed)
+    }
+
+    let now = Timestamp::now();
+
+    let ttl = match remaining_ttl(content.expires_at, now)? {
+        None => return Ok(Delivery::Expired),
+        Some(ttl) => ttl,
     };
 
+    let notification = Notification {
+        id,
+        title: content.title,
+        description: content.description,
+        created_on: *now,
+        icon: content.icon,
+        read: false,
+        action: content.action,
+        expires_at: content.expires_at,
+    };
+
+    sess.query_prepared(
+        r#"
+        INSERT INTO user_notifications (
+            id,
+            recipient_id,
+            title,
+            description,
+            created_on,
+            icon,
+            read,
+            action_kind,
+            action_target,
+            expires_at
+        ) VALUES (?, ?, ?, ?, ?, ?, false, ?, ?, ?) USING TTL ?"#,
+        (
+            notification.id,
+            recipient_id,
+            &notification.title,
+            &notification.description,
+            now,
+            notification.icon.map(|v| v.to_string()),
+            notification.action.as_ref().map(|v| v.kind.to_string()),
+            notification.action.as_ref().map(|v| v.target.clone()),
+            notification.expires_at,
+            ttl,
+            )
+    ).await?;
+
+    add_unread(sess, recipient_id, id, notification.expires_at).await?;
+
+    let unread = count_unread_notifications(sess, recipient_id).await?;
+    events::emit_to_user(recipient_id, NotificationCreated { notification, unread }).await;
+
+    Ok(Delivery::Delivered(id))
+}
+
+/// Replaces the title and description of one of the user's existing
+/// notifications, marking it unread and pushing it to them again.
+///
+/// Returns `false` if the notification no longer exists, e.g. because the
+/// user deleted it, or they have since muted its category.
+pub async fn update_notification_text(
+    sess: &Session,
+    recipient_id: i64,
+    id: Uuid,
+    title: String,
+    description: Option<String>,
+) -> Result<bool> {
+    let notification = match get_notification(sess, recipient_id, id).await? {
+        None => return Ok(false),
+        Some(notification) => notification,
+    };
+
+    if preferences::is_muted(sess, recipient_id, notification.icon).await? {
+        return Ok(false)
+    }
+
+    // The update must expire along with the rest of the row.
+    let ttl = match remaining_ttl(notification.expires_at, Timestamp::now())? {
+        None => return Ok(false),
+        Some(ttl) => ttl,
+    };
+
+    let result = sess.query_prepared(
+        r#"
+        UPDATE user_notifications USING TTL ?
+        SET title = ?, description = ?, read = false
+        WHERE recipient_id = ? AND id = ?
+        IF EXISTS;
+        "#,
+        (ttl, &title, &description, recipient_id, id)
+    ).await?;
+
+    if !db::was_applied(&result) {
+        return Ok(false)
+    }
+
+    add_unread(sess, recipient_id, id, notification.expires_at).await?;
+
+    let notification = Notification {
+        title,
+        description,
+        read: false,
+        ..notification
+    };
+
+    let unread = count_unread_notifications(sess, recipient_id).await?;
+    events::emit_to_user(recipient_id, NotificationCreated { notification, unread }).await;
+
+    Ok(true)
+}
+
+/// Gets one of the user's notifications by its id.
+pub async fn get_notification(sess: &Session, recipient_id: i64, id: Uuid) -> Result<Option<Notification>> {
+    let result = sess.query_prepared(
+        r#"
+        SELECT id, title, description, icon, created_on, read, action_kind, action_target, expires_at
+        FROM user_notifications
+        WHERE recipient_id = ? AND id = ?;
+        "#,
+        (recipient_id, id),
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let notification = rows.into_typed::<NotificationInfo>()
+        .next()
+        .transpose()?
+        .map(notification_from_info);
+
+    Ok(notification)
+}
+
+/// Schedules a notification to be created for the recipient once
+/// `deliver_at` has passed, see `notifications::scheduled`.
+///
+/// The recipient's preferences are checked again on delivery.
+pub async fn schedule_notification(
+    sess: &Session,
+    recipient_id: i64,
+    deliver_at: Timestamp,
+    content: NotificationContent,
+) -> Result<()> {
+    sess.query_prepared(
+        r#"
+        INSERT INTO scheduled_notifications (
+            bucket,
+            deliver_at,
+            id,
+            recipient_id,
+            title,
+            description,
+            icon,
+            action_kind,
+            action_target,
+            expires_at
+        ) VALUES (0, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        (
+            deliver_at,
+            Uuid::new_v4(),
+            recipient_id,
+            content.title,
+            content.description,
+            content.icon.map(|v| v.to_string()),
+            content.action.as_ref().map(|v| v.kind.to_string()),
+            content.action.map(|v| v.target),
+            content.expires_at,
+        )
+    ).await?;
+
+    Ok(())
+}
+
+
+type NotificationInfo = (
+    Uuid,
+    String,
+    Option<String>,
+    Option<String>,
+    Timestamp,
+    Option<bool>,
+    Option<String>,
+    Option<String>,
+    Option<Timestamp>,
+);
+
+fn notification_from_info(v: NotificationInfo) -> Notification {
+    Notification {
+        id: v.0,
+        title: v.1,
+        description: v.2,
+        icon: Option::flatten(v.3.map(|v| Icons::from_str(&v).ok())),
+        created_on: v.4.0,
+        read: v.5.unwrap_or(false),
+        action: parse_action(v.6, v.7),
+        expires_at: v.8,
+    }
+}
+
+/// Builds an action from its stored kind and target.
+pub fn parse_action(kind: Option<String>, target: Option<String>) -> Option<NotificationAction> {
+    Some(NotificationAction {
+        kind: ActionKind::from_str(&kind?).ok()?,
+        target: target?,
+    })
+}
+
+
+/// Gets the TTL to write a notification expiring at `expires_at` with,
+/// `None` if it has already expired.
+///
+/// A TTL of 0 means the row never expires.
+fn remaining_ttl(expires_at: Option<Timestamp>, now: Timestamp) -> Result<Option<i32>> {
+    let expires_at = match expires_at {
+        None => return Ok(Some(0)),
+        Some(expires_at) => expires_at,
+    };
+
+    let remaining_secs = expires_at.saturating_sub(*now) / 1000;
+    if remaining_secs <= 0 {
+        return Ok(None)
+    }
+
+    if remaining_secs > MAX_EXPIRY_SECS {
+        return Err(anyhow!("notification expires more than {}s away", MAX_EXPIRY_SECS))
+    }
+
+    Ok(Some(i32::try_from(remaining_secs)?))
+}
+
+/// Marks a notification as read or unread, returning `false` if it has
+/// expired.
+///
+/// The update is written with the notification's remaining TTL so it
+/// doesn't outlive the rest of the row.
+async fn set_read(
+    sess: &Session,
+    recipient_id: i64,
+    id: Uuid,
+    expires_at: Option<Timestamp>,
+    read: bool,
+) -> Result<bool> {
+    let ttl = match remaining_ttl(expires_at, Timestamp::now())? {
+        None => return Ok(false),
+        Some(ttl) => ttl,
+    };
+
+    sess.query_prepared(
+        "UPDATE user_notifications USING TTL ? SET read = ? WHERE recipient_id = ? AND id = ?;",
+        (ttl, read, recipient_id, id),
+    ).await?;
+
+    Ok(true)
+}
+
+
+/// Lets the user's other clients know their unread count changed.
+async fn emit_unread_count(sess: &Session, user_id: i64) -> Result<()> {
+    let unread = count_unread_notifications(sess, user_id).await?;
+    events::emit_to_user(user_id, NotificationsUpdated { unread }).await;
+
+    Ok(())
+}
+
+
+async fn get_unread_ids(sess: &Session, user_id: i64) -> Result<Vec<Uuid>> {
+    let result = sess.query_prepared(
+        "SELECT id FROM unread_notifications WHERE recipient_id = ?;",
+        (user_id,),
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let ids = rows.into_typed::<(Uuid,)>()
+        .filter_map(|v| v.ok())
+        .map(|v| v.0)
+        .collect();
+
+    Ok(ids)
+}
+
+/// Marks the notification as unread, expiring along with it.
+async fn add_unread(
+    sess: &Session,
+    recipient_id: i64,
+    id: Uuid,
+    expires_at: Option<Timestamp>,
+) -> Result<()> {
+    let ttl = match remaining_ttl(expires_at, Timestamp::now())? {
+        None => return Ok(()),
+        Some(ttl) => ttl,
+    };
+
+    sess.query_prepared(
+        "INSERT INTO unread_notifications (recipient_id, id) VALUES (?, ?) USING TTL ?;",
+        (recipient_id, id, ttl),
+    ).await?;
+
+    Ok(())
+}
+
+/// Stops tracking the given notifications as unread, there must be no
+/// more than `db::MAX_IN_VALUES` of them.
+async fn remove_unread(sess: &Session, recipient_id: i64, ids: Vec<Uuid>) -> Result<()> {
     sess.query_prepared(
-        "DELETE FROM notifications WHERE id = ? AND recipient_id = ?;",
-        (id, user_id),
+        "DELETE FROM unread_notifications WHERE recipient_id = ? AND id IN ?;",
+        (recipient_id, ids),
     ).await?;
 
-    Ok(Some(()))
+    Ok(())
 }
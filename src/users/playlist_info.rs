@@ -2,19 +2,13 @@ use anyhow::{anyhow, Result};
 use scylla::IntoTypedRows;
 
 use crate::db::Session;
-use super::user_info;
 use crate::playlists::{PlaylistEntry, Playlist};
 
 
-pub async fn get_playlists_for_token(
+pub async fn get_playlists_for_user(
     sess: &Session,
-    token: &str,
-) -> Result<Option<Vec<Playlist>>> {
-    let user_id = match user_info::get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
-    };
-
+    user_id: i64,
+) -> Result<Vec<Playlist>> {
     let result = sess.query_prepared(
         "SELECT * FROM playlists WHERE owner_id = ?",
         (user_id,)
@@ -27,19 +21,14 @@ pub async fn get_playlists_for_token(
         .filter_map(|v| v.ok())
         .collect();
 
-    Ok(Some(playlists))
+    Ok(playlists)
 }
 
 
-pub async fn get_playlist_entries_for_token(
+pub async fn get_playlist_entries_for_user(
     sess: &Session,
-    token: &str,
-) -> Result<Option<Vec<PlaylistEntry>>> {
-    let user_id = match user_info::get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
-    };
-
+    user_id: i64,
+) -> Result<Vec<PlaylistEntry>> {
     let result = sess.query_prepared(
         "SELECT * FROM playlist_entries WHERE owner_id = ?",
         (user_id,)
@@ -52,5 +41,5 @@ pub async fn get_playlist_entries_for_token(
         .filter_map(|v| v.ok())
         .collect();
 
-    Ok(Some(playlists))
+    Ok(playlists)
 }
\ No newline at end of file
@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use poem_openapi::Object;
+use scylla::IntoTypedRows;
+
+use crate::db::Session;
+use crate::users::notifications::Icons;
+
+
+#[derive(Object)]
+pub struct Preferences {
+    /// The notification categories the user doesn't want to receive.
+    pub muted_categories: Vec<Icons>,
+}
+
+
+/// Gets the notification categories the user has muted.
+pub async fn get_muted_categories(sess: &Session, user_id: i64) -> Result<HashSet<Icons>> {
+    let result = sess.query_prepared(
+        "SELECT muted_categories FROM user_preferences WHERE user_id = ?;",
+        (user_id,)
+    ).await?;
+
+    let rows = result.rows
+        .ok_or_else(|| anyhow!("expected returned rows"))?;
+
+    let muted = match rows.into_typed::<(Option<Vec<String>>,)>().next() {
+        None => HashSet::new(),
+        Some(v) => v?.0
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| Icons::from_str(v).ok())
+            .collect(),
+    };
+
+    Ok(muted)
+}
+
+/// Gets the user's preferences, users who haven't set any have nothing
+/// muted.
+pub async fn get_preferences(sess: &Session, user_id: i64) -> Result<Preferences> {
+    let mut muted_categories: Vec<Icons> = get_muted_categories(sess, user_id).await?
+        .into_iter()
+        .collect();
+
+    muted_categories.sort_by_key(|v| v.to_string());
+
+    Ok(Preferences { muted_categories })
+}
+
+/// Replaces the user's muted categories.
+pub async fn set_muted_categories(sess: &Session, user_id: i64, muted: &HashSet<Icons>) -> Result<()> {
+    let muted: Vec<String> = muted.iter()
+        .map(|v| v.to_string())
+        .collect();
+
+    sess.query_prepared(
+        "UPDATE user_preferences SET muted_categories = ? WHERE user_id = ?;",
+        (muted, user_id)
+    ).await?;
+
+    Ok(())
+}
+
+/// Mutes or unmutes a single category for the user.
+pub async fn set_category_muted(sess: &Session, user_id: i64, category: Icons, muted: bool) -> Result<()> {
+    let query = if muted {
+        "UPDATE user_preferences SET muted_categories = muted_categories + ? WHERE user_id = ?;"
+    } else {
+        "UPDATE user_preferences SET muted_categories = muted_categories - ? WHERE user_id = ?;"
+    };
+
+    sess.query_prepared(query, (vec![category.to_string()], user_id)).await?;
+
+    Ok(())
+}
+
+/// Checks if the user has muted notifications of the given category.
+///
+/// Notifications without a category can't be muted.
+pub async fn is_muted(sess: &Session, user_id: i64, category: Option<Icons>) -> Result<bool> {
+    let category = match category {
+        None => return Ok(false),
+        Some(category) => category,
+    };
+
+    Ok(get_muted_categories(sess, user_id).await?.contains(&category))
+}
@@ -1,33 +1,15 @@
 use anyhow::{anyhow, Result};
 use scylla::IntoTypedRows;
+use uuid::Uuid;
 
 use crate::db::Session;
 use crate::rooms::models::{ArchivedRoom, Room};
-use super::user_info;
-
-
-pub async fn get_active_room_for_token(
-    sess: &Session,
-    token: &str,
-) -> Result<Option<Option<Room>>> {
-    let user_id = match user_info::get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
-    };
-
-    get_active_room_for_user_id(sess, user_id).await.map(|v| Some(v))
-}
 
 
 pub async fn get_archived_rooms(
     sess: &Session,
-    token: &str,
-) -> Result<Option<Vec<ArchivedRoom>>> {
-    let user_id = match user_info::get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
-    };
-
+    user_id: i64,
+) -> Result<Vec<ArchivedRoom>> {
     let result = sess.query_prepared(
         "SELECT * FROM room_archive WHERE owner_id = ?;",
         (user_id,)
@@ -40,13 +22,13 @@ pub async fn get_archived_rooms(
         .filter_map(|v| v.ok())
         .collect();
 
-    Ok(Some(rooms))
+    Ok(rooms)
 }
 
 
 pub async fn get_active_room_for_user_id(sess: &Session, user_id: i64) -> Result<Option<Room>> {
     let result = sess.query_prepared(
-        "SELECT * FROM rooms WHERE owner_id = ?;",
+        "SELECT * FROM active_rooms WHERE owner_id = ?;",
         (user_id,)
     ).await?;
 
@@ -60,4 +42,21 @@ pub async fn get_active_room_for_user_id(sess: &Session, user_id: i64) -> Result
     };
 
     Ok(Some(room))
-}
\ No newline at end of file
+}
+
+
+/// Gets the room a user wants to control.
+///
+/// This is the room with the given id if one is given, otherwise the
+/// user's own active room. This does not check the user is allowed to
+/// control the room.
+pub async fn get_playback_room(
+    sess: &Session,
+    user_id: i64,
+    room_id: Option<Uuid>,
+) -> Result<Option<Room>> {
+    match room_id {
+        None => get_active_room_for_user_id(sess, user_id).await,
+        Some(room_id) => crate::rooms::get_room_by_id(sess, room_id).await,
+    }
+}
@@ -3,10 +3,11 @@ use anyhow::anyhow;
 use scylla::IntoTypedRows;
 use poem_openapi::Object;
 
+use crate::auth::sessions;
 use crate::db::Session;
 use crate::utils::JsSafeBigInt;
 
-#[derive(Object)]
+#[derive(Object, Debug, Clone)]
 pub struct User {
     pub id: JsSafeBigInt,
     #[oai(skip)]
@@ -27,37 +28,14 @@ pub struct Guild {
 
 
 /// Gets a user_id from the given access token if it's valid otherwise return None.
+///
+/// Using a token keeps its session alive, see `sessions::get_token_session`.
 pub async fn get_user_id_from_token(sess: &Session, token: &str) -> anyhow::Result<Option<i64>> {
-    let result = sess.query_prepared(
-        "SELECT user_id FROM access_tokens WHERE access_token = ?;",
-        (token.to_string(),)
-    ).await?;
-
-    let user_id = match result.rows {
-        None => None,
-        Some(rows) => {
-            if let Some(row) = rows.into_typed::<(i64,)>().next() {
-                Some(row?.0)
-            } else {
-                None
-            }
-        }
-    };
-
-    Ok(user_id)
+    let session = sessions::get_token_session(sess, token).await?;
+    Ok(session.map(|v| v.user_id))
 }
 
 
-/// Gets a full user object from the given access token.
-pub async fn get_user_from_token(sess: &Session, token: &str) -> anyhow::Result<Option<User>> {
-    let user_id = match get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
-    };
-
-    get_user_from_id(sess, user_id).await
-}
-
 pub async fn get_user_from_id(sess: &Session, user_id: i64) -> anyhow::Result<Option<User>> {
     let result = sess.query_prepared(
         "SELECT id, access_servers, avatar, updated_on, username FROM users WHERE id = ?;",
@@ -86,58 +64,8 @@ pub async fn get_user_from_id(sess: &Session, user_id: i64) -> anyhow::Result<Op
     Ok(res)
 }
 
-/// Gets the amount of credits the user currently has by a given token.
-pub async fn get_vote_credits_for_token(sess: &Session, token: &str) -> anyhow::Result<Option<i32>> {
-    let user_id = match get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
-    };
-
-    get_user_vote_credits(sess, user_id).await.map(|v| Some(v))
-}
-
-/// Gets the amount of credits the user currently has.
-///
-/// This assumes the user currently exists.
-pub async fn get_user_vote_credits(sess: &Session, user_id: i64) -> anyhow::Result<i32> {
-    let result = sess.query_prepared(
-        "SELECT credits FROM user_vote_credits WHERE id = ?;",
-        (user_id,)
-    ).await?;
-
-    let rows = result.rows
-        .ok_or_else(|| anyhow!("expected returned rows"))?;
-
-
-    let (credits,) = match rows.into_typed::<(i32,)>().next() {
-        None => return Ok(0),
-        Some(v) => v?,
-    };
-
-    Ok(credits)
-}
-
-
-/// Increments a user's vote credits by a given amount.
-///
-/// This assumes the user currently exists.
-pub async fn adjust_user_credits(sess: &Session, user_id: i64, value: i32) -> anyhow::Result<()> {
-    sess.query_prepared(
-        "UPDATE user_vote_credits SET credit = credit + ? WHERE user_id = ?;",
-        (value, user_id)
-    ).await?;
-
-    Ok(())
-}
-
-
-/// Gets all accessible guilds for a given access token.
-pub async fn get_user_guilds_from_token(sess: &Session, token: &str) -> anyhow::Result<Option<Vec<Guild>>> {
-    let user = match get_user_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(u) => u,
-    };
-
+/// Gets all of the user's accessible guilds.
+pub async fn get_user_guilds(sess: &Session, user: &User) -> anyhow::Result<Vec<Guild>> {
     let ids = user.access_servers.keys()
         .copied()
         .collect();
@@ -154,7 +82,7 @@ pub async fn get_user_guilds_from_token(sess: &Session, token: &str) -> anyhow::
         })
         .collect();
 
-    Ok(Some(guilds))
+    Ok(guilds)
 }
 
 
@@ -2,18 +2,28 @@ use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 use std::ops::Deref;
 use std::str::FromStr;
+use std::sync::Arc;
 use poem::Request;
 use poem_openapi::payload::Json;
 use poem_openapi::types::{ParseError, ParseFromJSON, ParseResult, ToJSON, Type};
 use poem_openapi::{Object, ApiResponse, SecurityScheme};
 use poem_openapi::auth::Bearer;
 use poem_openapi::registry::MetaSchemaRef;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use scylla::cql_to_rust::{FromCqlVal, FromCqlValError};
 use scylla::frame::response::result::CqlValue;
+use scylla::frame::value::ValueTooBig;
+use serde::{Serialize, Serializer};
 use serde_json::{json, Value};
 
+use crate::admin::roles::SuperUser;
+use crate::auth::api_keys::ApiScope;
+use crate::auth::identity::{self, AuthenticatedUser, UserCache};
+use crate::db::Session;
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct JsSafeBigInt(pub i64);
 
 impl Display for JsSafeBigInt {
@@ -58,10 +68,16 @@ impl ToJSON for JsSafeBigInt {
     }
 }
 
+impl Serialize for JsSafeBigInt {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
 impl ParseFromJSON for JsSafeBigInt {
     fn parse_from_json(value: Value) -> ParseResult<Self> {
         value.as_i64()
-            .map(|v| Self(v))
+            .map(Self)
             .ok_or_else(|| ParseError::custom("cannot convert value into integer"))
     }
 }
@@ -78,8 +94,83 @@ impl FromStr for JsSafeBigInt {
 impl FromCqlVal<CqlValue> for JsSafeBigInt {
     fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
         cql_val.as_bigint()
-            .map(|v| Self(v))
-            .ok_or_else(|| FromCqlValError::BadCqlType)
+            .map(Self)
+            .ok_or(FromCqlValError::BadCqlType)
+    }
+}
+
+
+/// A UTC timestamp represented as milliseconds since the unix epoch.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub i64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self(chrono::Utc::now().timestamp_millis())
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Deref for Timestamp {
+    type Target = i64;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Type for Timestamp {
+    const IS_REQUIRED: bool = <i64 as Type>::IS_REQUIRED;
+    type RawValueType = <i64 as Type>::RawValueType;
+    type RawElementValueType = <i64 as Type>::RawElementValueType;
+
+    fn name() -> Cow<'static, str> {
+        Cow::from("Timestamp")
+    }
+
+    fn schema_ref() -> MetaSchemaRef {
+        i64::schema_ref()
+    }
+
+    fn as_raw_value(&self) -> Option<&Self::RawValueType> {
+        Some(&self.0)
+    }
+
+    fn raw_element_iter<'a>(&'a self) -> Box<dyn Iterator<Item=&'a Self::RawElementValueType> + 'a> {
+        self.0.raw_element_iter()
+    }
+}
+
+impl ToJSON for Timestamp {
+    fn to_json(&self) -> Value {
+        json!(self.0)
+    }
+}
+
+impl ParseFromJSON for Timestamp {
+    fn parse_from_json(value: Value) -> ParseResult<Self> {
+        value.as_i64()
+            .map(Self)
+            .ok_or_else(|| ParseError::custom("cannot convert value into timestamp"))
+    }
+}
+
+impl FromCqlVal<CqlValue> for Timestamp {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
+        cql_val.as_duration()
+            .map(|v| Self(v.num_milliseconds()))
+            .ok_or(FromCqlValError::BadCqlType)
+    }
+}
+
+impl scylla::frame::value::Value for Timestamp {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        scylla::frame::value::Timestamp(chrono::Duration::milliseconds(self.0)).serialize(buf)
     }
 }
 
@@ -88,24 +179,104 @@ lazy_static!{
     static ref SUPERUSER_KEY: Option<String> = {
       std::env::var("SUPERUSER_KEY").ok()
     };
+
+    static ref TIME_UUID_CONTEXT: uuid::v1::Context = {
+        uuid::v1::Context::new(rand::random())
+    };
+
+    static ref TIME_UUID_NODE_ID: [u8; 6] = rand::random();
 }
 
+/// A raw access token, for endpoints which need the token itself rather
+/// than the user it belongs to, see `UserBearer`.
 #[derive(SecurityScheme)]
 #[oai(type = "bearer")]
 pub struct TokenBearer(pub Bearer);
 
+/// A user with a superuser role, or the static `SUPERUSER_KEY` which is
+/// kept as a break glass credential.
+///
+/// Endpoints still need to check the superuser has the permission they
+/// require.
 #[derive(SecurityScheme)]
-#[oai(type = "bearer", checker = "token_checker")]
-pub struct SuperUserBearer(());
+#[oai(type = "bearer", checker = "superuser_checker")]
+pub struct SuperUserBearer(pub SuperUser);
 
-async fn token_checker(_: &Request, bearer: Bearer) -> Option<()> {
+async fn superuser_checker(req: &Request, bearer: Bearer) -> Option<SuperUser> {
     if let Some(key) = SUPERUSER_KEY.as_ref() {
         if &bearer.token == key {
-            return Some(())
+            warn!("superuser key used for {} {}", req.method(), req.uri());
+            return Some(SuperUser::break_glass())
         }
     }
 
-    None
+    let user = authenticate(req, bearer, None).await?;
+    let sess = req.data::<Session>()?;
+    match SuperUser::from_user(sess, &user).await {
+        Ok(superuser) => superuser,
+        Err(e) => {
+            error!("failed to get roles for user {}: {}", user.id, e);
+            None
+        },
+    }
+}
+
+
+/// The user an access token belongs to, API keys aren't accepted.
+#[derive(SecurityScheme)]
+#[oai(type = "bearer", checker = "user_checker")]
+pub struct UserBearer(pub AuthenticatedUser);
+
+async fn user_checker(req: &Request, bearer: Bearer) -> Option<AuthenticatedUser> {
+    authenticate(req, bearer, None).await
+}
+
+/// An access token or an API key with the `read:playlists` scope.
+#[derive(SecurityScheme)]
+#[oai(type = "bearer", checker = "read_playlists_checker")]
+pub struct ReadPlaylistsBearer(pub AuthenticatedUser);
+
+async fn read_playlists_checker(req: &Request, bearer: Bearer) -> Option<AuthenticatedUser> {
+    authenticate(req, bearer, Some(ApiScope::ReadPlaylists)).await
+}
+
+/// An access token or an API key with the `write:playlists` scope.
+#[derive(SecurityScheme)]
+#[oai(type = "bearer", checker = "write_playlists_checker")]
+pub struct WritePlaylistsBearer(pub AuthenticatedUser);
+
+async fn write_playlists_checker(req: &Request, bearer: Bearer) -> Option<AuthenticatedUser> {
+    authenticate(req, bearer, Some(ApiScope::WritePlaylists)).await
+}
+
+/// An access token or an API key with the `rooms:control` scope.
+#[derive(SecurityScheme)]
+#[oai(type = "bearer", checker = "rooms_control_checker")]
+pub struct RoomsControlBearer(pub AuthenticatedUser);
+
+async fn rooms_control_checker(req: &Request, bearer: Bearer) -> Option<AuthenticatedUser> {
+    authenticate(req, bearer, Some(ApiScope::RoomsControl)).await
+}
+
+/// An access token or an API key with the `notifications:read` scope.
+#[derive(SecurityScheme)]
+#[oai(type = "bearer", checker = "notifications_read_checker")]
+pub struct NotificationsReadBearer(pub AuthenticatedUser);
+
+async fn notifications_read_checker(req: &Request, bearer: Bearer) -> Option<AuthenticatedUser> {
+    authenticate(req, bearer, Some(ApiScope::NotificationsRead)).await
+}
+
+async fn authenticate(req: &Request, bearer: Bearer, scope: Option<ApiScope>) -> Option<AuthenticatedUser> {
+    let sess = req.data::<Session>()?;
+    let cache = req.data::<Arc<UserCache>>()?;
+    match identity::authenticate(sess, cache, &bearer.token, scope).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!("failed to authenticate token: {}", e);
+            None
+        },
+    }
 }
 
 
@@ -159,4 +330,66 @@ impl<T: Send + Sync + ToJSON> JsonResponse<T> {
     pub fn unauthorized() -> Self {
         Self::Unauthorized
     }
-}
\ No newline at end of file
+}
+
+
+#[inline]
+pub fn generate_token(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Generates a time based (v1) uuid for the current time, suitable for
+/// `timeuuid` columns.
+pub fn new_time_uuid() -> uuid::Uuid {
+    let now = chrono::Utc::now();
+    let ts = uuid::v1::Timestamp::from_unix(
+        &*TIME_UUID_CONTEXT,
+        now.timestamp() as u64,
+        now.timestamp_subsec_nanos(),
+    );
+
+    uuid::Uuid::new_v1(ts, &*TIME_UUID_NODE_ID)
+        .expect("node id is always 6 bytes")
+}
+
+/// Generates a time based (v1) uuid for the given time which is always the
+/// same for the same `seed`.
+///
+/// The clock sequence and node id are taken from the seed rather than
+/// this process, so this is only unique for distinct seeds.
+pub fn time_uuid_from(time: Timestamp, seed: uuid::Uuid) -> uuid::Uuid {
+    // Time based uuids count 100ns intervals since 15 October 1582.
+    const UUID_EPOCH_OFFSET: u64 = 0x01B2_1DD2_1381_4000;
+
+    let ticks = time.0.max(0) as u64 * 10_000 + UUID_EPOCH_OFFSET;
+    let seed = seed.as_bytes();
+    let counter = u16::from_be_bytes([seed[0], seed[1]]);
+    let ts = uuid::v1::Timestamp::from_rfc4122(ticks, counter);
+
+    uuid::Uuid::new_v1(ts, &seed[10..16])
+        .expect("node id is always 6 bytes")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_uuid_from_is_deterministic() {
+        let seed = uuid::Uuid::new_v4();
+        let time = Timestamp(1_700_000_000_123);
+
+        let id = time_uuid_from(time, seed);
+        assert_eq!(id, time_uuid_from(time, seed));
+        assert_ne!(id, time_uuid_from(time, uuid::Uuid::new_v4()));
+
+        let (secs, nanos) = id.to_timestamp().unwrap().to_unix();
+        assert_eq!(secs, 1_700_000_000);
+        assert_eq!(nanos, 123_000_000);
+    }
+}